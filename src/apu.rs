@@ -0,0 +1,1179 @@
+// 2A03 APU. Five sound channels, mixed together into a single output:
+// 0x4000-0x4003 - Pulse 1: duty/envelope, sweep, timer low, length/timer high
+// 0x4004-0x4007 - Pulse 2: same as pulse 1
+// 0x4008-0x400B - Triangle: linear counter, unused, timer low, length/timer high
+// 0x400C-0x400F - Noise: envelope, unused, mode/period, length
+// 0x4010-0x4013 - DMC: flags/rate, direct load, sample address, sample length
+// 0x4015        - Status (write: channel enables, read: length counters and IRQ flags)
+// 0x4017        - Frame counter (write only, reads go to controller 2)
+//
+// The frame counter divides the CPU clock into quarter and half frames (about 240Hz and 120Hz),
+// which clock the envelopes, linear counter, length counters and sweeps. In 4-step mode it also
+// raises an IRQ at the end of each sequence.
+//
+// The DMC plays 1 bit delta encoded samples straight out of the CPU address space. Whenever its
+// sample buffer is empty the bus has to fetch the next byte for it, halting the CPU.
+use crate::state::{StateError, StateReader, StateWriter};
+
+// NTSC CPU clock, the master clock divided by 12
+pub const CPU_CLOCK_RATE: f64 = 21_477_272.0 / 12.0;
+
+// Bits of a $4015 read that the APU doesn't drive
+pub const OPEN_BUS_MASK: u8 = 0x20;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [0, 0, 0, 0, 0, 0, 1, 1], // 25%
+    [0, 0, 0, 0, 1, 1, 1, 1], // 50%
+    [1, 1, 1, 1, 1, 1, 0, 0], // 25% negated
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// Timer periods in CPU cycles (NTSC)
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// Frame counter steps, in CPU cycles since the sequence started
+const STEP_1: u32 = 7457;
+const STEP_2: u32 = 14913;
+const STEP_3: u32 = 22371;
+const FOUR_STEP_IRQ: u32 = 29828;
+const FOUR_STEP_4: u32 = 29829;
+const FOUR_STEP_END: u32 = 29830;
+const FIVE_STEP_5: u32 = 37281;
+const FIVE_STEP_END: u32 = 37282;
+
+const STATUS_FRAME_IRQ: u8 = 1 << 6;
+const STATUS_DMC_IRQ: u8 = 1 << 7;
+
+const FRAME_COUNTER_FIVE_STEP: u8 = 1 << 7;
+const FRAME_COUNTER_IRQ_INHIBIT: u8 = 1 << 6;
+
+#[derive(Default)]
+struct LengthCounter {
+    counter: u8,
+    halt: bool,
+    enabled: bool, // set through $4015, the counter is held at 0 while disabled
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize];
+        }
+    }
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+    // Half frame
+    fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+    fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.counter);
+        state.bool(self.halt);
+        state.bool(self.enabled);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.counter = state.u8()?;
+        self.halt = state.bool()?;
+        self.enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+// Volume envelope shared by the pulse and noise channels. Either a constant volume, or a
+// sawtooth decaying from 15 to 0, optionally looping.
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool, // same bit as the length counter halt flag
+    constant: bool,
+    volume: u8, // the constant volume, or the decay period
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+    // Quarter frame
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.start);
+        state.bool(self.looping);
+        state.bool(self.constant);
+        state.u8(self.volume);
+        state.u8(self.divider);
+        state.u8(self.decay);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.start = state.bool()?;
+        self.looping = state.bool()?;
+        self.constant = state.bool()?;
+        self.volume = state.u8()?;
+        self.divider = state.u8()?;
+        self.decay = state.u8()?;
+        Ok(())
+    }
+}
+
+struct Pulse {
+    // Pulse 1 negates its sweep with ones' complement, so it ends up one lower than pulse 2
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    fn write_register(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data & 0x07) as u16) << 8;
+                self.length.load(data >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // The sweep unit continuously computes the target period, even when it's disabled
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            let target = self.period - change;
+            if self.ones_complement {
+                target.saturating_sub(1)
+            } else {
+                target
+            }
+        } else {
+            self.period + change
+        }
+    }
+
+    // Periods below 8 and targets past 0x7FF silence the channel
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // Half frame
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.duty);
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+        state.bool(self.sweep_enabled);
+        state.u8(self.sweep_period);
+        state.bool(self.sweep_negate);
+        state.u8(self.sweep_shift);
+        state.u8(self.sweep_divider);
+        state.bool(self.sweep_reload);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.duty = state.u8_below(DUTY_TABLE.len())?;
+        self.step = state.u8_below(8)?;
+        // The timer is 11 bits, the sweep would overflow on anything bigger
+        self.period = state.u16()?;
+        if self.period > 0x7FF {
+            return Err(StateError::Corrupt);
+        }
+        self.timer = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)?;
+        self.sweep_enabled = state.bool()?;
+        self.sweep_period = state.u8()?;
+        self.sweep_negate = state.bool()?;
+        self.sweep_shift = state.u8_below(8)?;
+        self.sweep_divider = state.u8()?;
+        self.sweep_reload = state.bool()?;
+        Ok(())
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: LengthCounter,
+    control: bool, // also the length counter halt flag
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write_register(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.control = data & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data & 0x07) as u16) << 8;
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Every CPU cycle. The sequencer stops, holding its output, while either counter is 0.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Quarter frame
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.step);
+        state.u16(self.period);
+        state.u16(self.timer);
+        self.length.save_state(state);
+        state.bool(self.control);
+        state.u8(self.linear_reload_value);
+        state.u8(self.linear_counter);
+        state.bool(self.linear_reload);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.step = state.u8_below(TRIANGLE_TABLE.len())?;
+        self.period = state.u16()?;
+        self.timer = state.u16()?;
+        self.length.load_state(state)?;
+        self.control = state.bool()?;
+        self.linear_reload_value = state.u8()?;
+        self.linear_counter = state.u8()?;
+        self.linear_reload = state.bool()?;
+        Ok(())
+    }
+}
+
+struct Noise {
+    short_mode: bool, // feedback from bit 6 instead of bit 1, for a 93 step sequence
+    period: u16,
+    timer: u16,
+    shift: u16, // 15 bit linear feedback shift register
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            short_mode: false,
+            period: NOISE_PERIODS[0],
+            timer: 0,
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    fn write_register(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.period = NOISE_PERIODS[(data & 0x0F) as usize];
+            }
+            _ => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.short_mode);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u16(self.shift);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.short_mode = state.bool()?;
+        self.period = state.u16()?;
+        if !NOISE_PERIODS.contains(&self.period) {
+            return Err(StateError::Corrupt);
+        }
+        self.timer = state.u16()?;
+        self.shift = state.u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
+
+struct DMC {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8, // 7 bit output level
+
+    sample_address: u16,
+    sample_length: u16,
+    address: u16,         // next byte to fetch
+    bytes_remaining: u16, // bytes of the sample that haven't been fetched yet
+    buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl DMC {
+    fn new() -> DMC {
+        DMC {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            period: DMC_RATES[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write_register(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.period = DMC_RATES[(data & 0x0F) as usize];
+            }
+            1 => self.level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = ((data as u16) << 4) + 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Address the memory reader wants to fetch from, when the sample buffer needs refilling
+    fn request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.address)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // The address wraps around to 0x8000, not 0
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.irq_enabled);
+        state.bool(self.irq);
+        state.bool(self.looping);
+        state.u16(self.period);
+        state.u16(self.timer);
+        state.u8(self.level);
+        state.u16(self.sample_address);
+        state.u16(self.sample_length);
+        state.u16(self.address);
+        state.u16(self.bytes_remaining);
+        state.bool(self.buffer.is_some());
+        state.u8(self.buffer.unwrap_or(0));
+        state.u8(self.shift);
+        state.u8(self.bits_remaining);
+        state.bool(self.silence);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = state.bool()?;
+        self.irq = state.bool()?;
+        self.looping = state.bool()?;
+        self.period = state.u16()?;
+        if !DMC_RATES.contains(&self.period) {
+            return Err(StateError::Corrupt);
+        }
+        self.timer = state.u16()?;
+        self.level = state.u8()?;
+        self.sample_address = state.u16()?;
+        self.sample_length = state.u16()?;
+        self.address = state.u16()?;
+        self.bytes_remaining = state.u16()?;
+        let buffered = state.bool()?;
+        let buffer = state.u8()?;
+        self.buffer = if buffered { Some(buffer) } else { None };
+        self.shift = state.u8()?;
+        self.bits_remaining = state.u8()?;
+        if !(1..=8).contains(&self.bits_remaining) {
+            return Err(StateError::Corrupt);
+        }
+        self.silence = state.bool()?;
+        Ok(())
+    }
+
+    // Every CPU cycle. Each timer period plays one bit of the shift register, moving the level up
+    // or down by 2 without leaving 0-127.
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.shift = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+pub struct APU {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: DMC,
+
+    cycles: u64,    // CPU cycles since power on, the pulse timers run on every other one
+    frame_mode: u8, // last value written to $4017
+    frame_cycle: u32, // CPU cycles into the current frame counter sequence
+    frame_reset: Option<u8>, // a $4017 write restarts the sequence a few cycles later
+    frame_irq: bool,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        let mut apu = APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: DMC::new(),
+            cycles: 0,
+            frame_mode: 0,
+            frame_cycle: 0,
+            frame_reset: None,
+            frame_irq: false,
+        };
+        // At power on the frame counter acts as though 0 was just written to it
+        apu.write_frame_counter(0);
+        apu
+    }
+
+    // Pressing reset silences every channel and restarts the frame counter in the same mode
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.write_frame_counter(self.frame_mode);
+        self.frame_irq = false;
+        self.dmc.irq = false;
+        self.triangle.step = 0;
+        self.dmc.level &= 1;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"APU ");
+        self.pulse1.save_state(state);
+        self.pulse2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        state.u64(self.cycles);
+        state.u8(self.frame_mode);
+        state.u32(self.frame_cycle);
+        state.bool(self.frame_reset.is_some());
+        state.u8(self.frame_reset.unwrap_or(0));
+        state.bool(self.frame_irq);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"APU ")?;
+        self.pulse1.load_state(state)?;
+        self.pulse2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.cycles = state.u64()?;
+        self.frame_mode = state.u8()?;
+        self.frame_cycle = state.u32()?;
+        let resetting = state.bool()?;
+        let delay = state.u8_below(5)?;
+        self.frame_reset = if resetting { Some(delay) } else { None };
+        // The sequence wraps around at its end. A new mode only takes effect when the pending
+        // restart happens, so until then a switch from 5-step to 4-step can run past the end.
+        let end = if self.frame_reset.is_some() {
+            FIVE_STEP_END + 4
+        } else if self.frame_mode & FRAME_COUNTER_FIVE_STEP != 0 {
+            FIVE_STEP_END
+        } else {
+            FOUR_STEP_END
+        };
+        if self.frame_cycle >= end {
+            return Err(StateError::Corrupt);
+        }
+        self.frame_irq = state.bool()?;
+        Ok(())
+    }
+
+    // Reading the status acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        let lengths = [
+            &self.pulse1.length,
+            &self.pulse2.length,
+            &self.triangle.length,
+            &self.noise.length,
+        ];
+        for (bit, length) in lengths.iter().enumerate() {
+            if length.active() {
+                status |= 1 << bit;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 1 << 4;
+        }
+        if self.frame_irq {
+            status |= STATUS_FRAME_IRQ;
+        }
+        if self.dmc.irq {
+            status |= STATUS_DMC_IRQ;
+        }
+        status
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        let reg = addr & 0x03;
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write_register(reg, data),
+            0x4004..=0x4007 => self.pulse2.write_register(reg, data),
+            0x4008..=0x400B => self.triangle.write_register(reg, data),
+            0x400C..=0x400F => self.noise.write_register(reg, data),
+            0x4010..=0x4013 => self.dmc.write_register(reg, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+                self.dmc.irq = false;
+            }
+            0x4017 => self.write_frame_counter(data),
+            _ => {}
+        }
+    }
+
+    fn write_frame_counter(&mut self, data: u8) {
+        self.frame_mode = data;
+        if data & FRAME_COUNTER_IRQ_INHIBIT != 0 {
+            self.frame_irq = false;
+        }
+        // The sequence restarts 3 CPU cycles after a write on an APU cycle, 4 after one between
+        self.frame_reset = Some(if self.cycles % 2 == 1 { 4 } else { 3 });
+    }
+
+    // Advances the APU by one CPU cycle
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+        self.clock_frame_counter();
+
+        if self.cycles % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset {
+            if delay <= 1 {
+                self.frame_reset = None;
+                self.frame_cycle = 0;
+                if self.frame_mode & FRAME_COUNTER_FIVE_STEP != 0 {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                return;
+            }
+            self.frame_reset = Some(delay - 1);
+        }
+
+        self.frame_cycle += 1;
+        if self.frame_mode & FRAME_COUNTER_FIVE_STEP == 0 {
+            match self.frame_cycle {
+                STEP_1 | STEP_3 => self.quarter_frame(),
+                STEP_2 => {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                FOUR_STEP_IRQ => self.set_frame_irq(),
+                FOUR_STEP_4 => {
+                    self.quarter_frame();
+                    self.half_frame();
+                    self.set_frame_irq();
+                }
+                FOUR_STEP_END => {
+                    self.set_frame_irq();
+                    self.frame_cycle = 0;
+                }
+                _ => {}
+            }
+        } else {
+            match self.frame_cycle {
+                STEP_1 | STEP_3 => self.quarter_frame(),
+                STEP_2 | FIVE_STEP_5 => {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+                FIVE_STEP_END => self.frame_cycle = 0,
+                _ => {}
+            }
+        }
+    }
+
+    fn set_frame_irq(&mut self) {
+        if self.frame_mode & FRAME_COUNTER_IRQ_INHIBIT == 0 {
+            self.frame_irq = true;
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // State of the APU's IRQ output, from the frame counter or the end of a DMC sample
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Address of the sample byte the DMC is waiting for, if any. The bus should fetch it and hand
+    // it over with dmc_fill.
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.request()
+    }
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+    // The DMC's 7 bit output level, set directly through 0x4011 or moved by playing a sample
+    pub fn dmc_level(&self) -> u8 {
+        self.dmc.level
+    }
+
+    // Current output level of the mixer, from 0.0 to about 1.0. The two pulse channels share one
+    // nonlinear DAC, and triangle, noise and DMC share another.
+    pub fn output(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+}
+
+impl Default for APU {
+    fn default() -> APU {
+        APU::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(apu: &APU) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        apu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    fn load(state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(state);
+        APU::new().load_state(&mut reader)
+    }
+
+    // The frame counter's clocks, by CPU cycle after the $4017 write that started the sequence
+    #[derive(Default, Debug, PartialEq)]
+    struct FrameClocks {
+        quarter: Vec<u32>,
+        half: Vec<u32>,
+        irq: Vec<u32>,
+    }
+
+    fn frame_clocks(mode: u8, cycles: u32) -> FrameClocks {
+        let mut apu = APU::new();
+        // The triangle's linear counter moves on quarter frames, pulse 1's length on half frames
+        apu.write_register(0x4015, 0x05);
+        apu.write_register(0x4008, 0x7F);
+        apu.write_register(0x400B, 0x08);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x4017, mode);
+        let mut clocks = FrameClocks::default();
+        for cycle in 1..=cycles {
+            let linear_counter = apu.triangle.linear_counter;
+            let length = apu.pulse1.length.counter;
+            apu.tick();
+            if apu.triangle.linear_counter != linear_counter {
+                clocks.quarter.push(cycle);
+            }
+            if apu.pulse1.length.counter != length {
+                clocks.half.push(cycle);
+            }
+            // Acknowledged straight away, to see each cycle that sets it
+            if apu.read_status() & STATUS_FRAME_IRQ != 0 {
+                clocks.irq.push(cycle);
+            }
+        }
+        clocks
+    }
+
+    #[test]
+    fn four_step_sequence() {
+        // The sequence starts 3 cycles after a write on an even cycle
+        let start = 3;
+        let clocks = frame_clocks(0, start + FOUR_STEP_END + STEP_1);
+        let expected = FrameClocks {
+            quarter: [STEP_1, STEP_2, STEP_3, FOUR_STEP_4, FOUR_STEP_END + STEP_1]
+                .iter()
+                .map(|step| start + step)
+                .collect(),
+            half: vec![start + STEP_2, start + FOUR_STEP_4],
+            // The flag is set on 3 cycles in a row, around the last step
+            irq: vec![
+                start + FOUR_STEP_IRQ,
+                start + FOUR_STEP_4,
+                start + FOUR_STEP_END,
+            ],
+        };
+        assert_eq!(clocks, expected);
+
+        // The inhibit flag keeps the IRQ from being raised
+        let clocks = frame_clocks(FRAME_COUNTER_IRQ_INHIBIT, start + FOUR_STEP_END);
+        assert_eq!(clocks.irq, vec![]);
+        assert_eq!(clocks.half, expected.half);
+    }
+
+    #[test]
+    fn five_step_sequence() {
+        // Writing 5-step mode clocks everything immediately when the sequence restarts
+        let start = 3;
+        let clocks = frame_clocks(FRAME_COUNTER_FIVE_STEP, start + FIVE_STEP_END + STEP_1);
+        let expected = FrameClocks {
+            quarter: [
+                0,
+                STEP_1,
+                STEP_2,
+                STEP_3,
+                FIVE_STEP_5,
+                FIVE_STEP_END + STEP_1,
+            ]
+            .iter()
+            .map(|step| start + step)
+            .collect(),
+            half: vec![start, start + STEP_2, start + FIVE_STEP_5],
+            irq: vec![],
+        };
+        assert_eq!(clocks, expected);
+    }
+
+    #[test]
+    fn inhibit_clears_the_frame_irq() {
+        // Power on starts the sequence like a write of 0
+        let mut apu = APU::new();
+        for _ in 0..3 + FOUR_STEP_IRQ {
+            apu.tick();
+        }
+        assert!(apu.irq());
+        apu.write_register(0x4017, FRAME_COUNTER_IRQ_INHIBIT);
+        assert!(!apu.irq());
+
+        // But writing 5-step mode on its own leaves it pending
+        let mut apu = APU::new();
+        for _ in 0..3 + FOUR_STEP_IRQ {
+            apu.tick();
+        }
+        apu.write_register(0x4017, FRAME_COUNTER_FIVE_STEP);
+        assert!(apu.irq());
+    }
+
+    #[test]
+    fn length_counters() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0x01);
+        // The top 5 bits of $4003 index the length table
+        for index in 0..32 {
+            apu.write_register(0x4003, index << 3);
+            assert_eq!(apu.pulse1.length.counter, LENGTH_TABLE[index as usize]);
+        }
+        assert_eq!(LENGTH_TABLE[0x00], 10);
+        assert_eq!(LENGTH_TABLE[0x01], 254);
+        assert_eq!(LENGTH_TABLE[0x1F], 30);
+
+        // Counts down on half frames, until the channel is silenced
+        apu.write_register(0x4003, 0x03 << 3);
+        apu.half_frame();
+        assert_eq!(apu.peek_status() & 0x01, 0x01);
+        apu.half_frame();
+        assert_eq!(apu.peek_status() & 0x01, 0x00);
+        apu.half_frame();
+        assert_eq!(apu.pulse1.length.counter, 0);
+
+        // The halt flag holds it
+        apu.write_register(0x4000, 0x20);
+        apu.write_register(0x4003, 0x03 << 3);
+        apu.half_frame();
+        apu.half_frame();
+        assert_eq!(apu.pulse1.length.counter, 2);
+        apu.write_register(0x4000, 0x00);
+        apu.half_frame();
+        assert_eq!(apu.pulse1.length.counter, 1);
+
+        // Loads are ignored while the channel is disabled
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.pulse1.length.counter, 0);
+        apu.write_register(0x4003, 0x01 << 3);
+        assert_eq!(apu.pulse1.length.counter, 0);
+    }
+
+    #[test]
+    fn status_register() {
+        let mut apu = APU::new();
+        // Each length counter is reported once enabled and loaded
+        apu.write_register(0x4015, 0x0F);
+        for &reg in [0x4003, 0x4007, 0x400B, 0x400F].iter() {
+            apu.write_register(reg, 0x08);
+        }
+        assert_eq!(apu.peek_status(), 0x0F);
+        // And disabling one clears its counter
+        apu.write_register(0x4015, 0x0E);
+        assert_eq!(apu.peek_status(), 0x0E);
+        assert_eq!(apu.pulse1.length.counter, 0);
+
+        // Bit 4 is set while the DMC has sample bytes left
+        apu.write_register(0x4013, 0x01);
+        apu.write_register(0x4015, 0x1E);
+        assert_eq!(apu.peek_status(), 0x1E);
+        assert_eq!(apu.dmc.bytes_remaining, 0x11);
+        apu.write_register(0x4015, 0x0E);
+        assert_eq!(apu.peek_status(), 0x0E);
+
+        // Reading acknowledges the frame IRQ but not the DMC's, a write acknowledges the DMC's
+        for _ in 0..3 + FOUR_STEP_IRQ {
+            apu.tick();
+        }
+        apu.dmc.irq = true;
+        assert_eq!(apu.peek_status(), STATUS_DMC_IRQ | STATUS_FRAME_IRQ | 0x0E);
+        assert_eq!(apu.read_status(), STATUS_DMC_IRQ | STATUS_FRAME_IRQ | 0x0E);
+        assert_eq!(apu.read_status(), STATUS_DMC_IRQ | 0x0E);
+        apu.write_register(0x4015, 0x0E);
+        assert_eq!(apu.read_status(), 0x0E);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn sweep_muting() {
+        let mut pulse = Pulse::new(true);
+        pulse.write_register(0, 0x3F);
+        pulse.length.set_enabled(true);
+        pulse.write_register(3, 0x08);
+        // A constant volume on the high part of the 75% duty cycle
+        pulse.write_register(0, 0xFF);
+        pulse.step = 1;
+
+        // Periods below 8
+        pulse.write_register(2, 0x07);
+        assert!(pulse.muted());
+        assert_eq!(pulse.output(), 0);
+        pulse.write_register(2, 0x08);
+        assert!(!pulse.muted());
+        assert_eq!(pulse.output(), 15);
+
+        // And targets past 0x7FF, even with the sweep disabled
+        pulse.write_register(2, 0x00);
+        pulse.write_register(3, 0x04);
+        pulse.write_register(1, 0x00);
+        assert_eq!(pulse.sweep_target(), 0x800);
+        assert!(pulse.muted());
+        assert_eq!(pulse.output(), 0);
+        pulse.write_register(1, 0x01);
+        assert_eq!(pulse.sweep_target(), 0x600);
+        assert!(!pulse.muted());
+        pulse.write_register(1, 0x08);
+        assert!(!pulse.muted());
+
+        // A muted channel's sweep doesn't change the period
+        pulse.write_register(1, 0x80);
+        pulse.clock_sweep();
+        assert_eq!(pulse.period, 0x400);
+        pulse.write_register(1, 0x81);
+        pulse.clock_sweep();
+        assert_eq!(pulse.period, 0x600);
+    }
+
+    #[test]
+    fn rejects_out_of_range_state() {
+        let mut apu = APU::new();
+        apu.write_register(0x4002, 0xFF);
+        apu.write_register(0x4003, 0x07);
+        for _ in 0..1000 {
+            apu.tick();
+        }
+        let state = saved(&apu);
+        assert!(load(&state).is_ok());
+
+        // Pulse 1's period comes after the section tag, duty and step
+        let mut bad_period = state.clone();
+        bad_period[6..8].copy_from_slice(&0x0800u16.to_le_bytes());
+        assert!(matches!(load(&bad_period), Err(StateError::Corrupt)));
+
+        // The frame counter's cycle, restart flag and delay come before the final IRQ flag
+        let frame_cycle = state.len() - 7;
+        let mut bad_cycle = state.clone();
+        bad_cycle[frame_cycle..frame_cycle + 4].copy_from_slice(&FOUR_STEP_END.to_le_bytes());
+        assert!(matches!(load(&bad_cycle), Err(StateError::Corrupt)));
+        let mut bad_delay = state;
+        bad_delay[frame_cycle + 5] = 5;
+        assert!(matches!(load(&bad_delay), Err(StateError::Corrupt)));
+    }
+}
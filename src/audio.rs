@@ -0,0 +1,285 @@
+// Turns the APU's output, one level per CPU cycle, into samples at a normal audio rate.
+//
+// Resampling works like blip_buf: every change in level is added to a buffer at the output
+// rate as a band-limited step, spread over a few samples by a windowed sinc kernel picked for
+// where the change falls between two samples. Summing the buffer gives the resampled signal
+// without the aliasing that plain decimation of the square waves would cause.
+//
+// The samples then go through the same filters as the NES's audio output (high-pass at 90Hz and
+// 440Hz, low-pass at 14kHz), and wait in a queue until they're read.
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::io;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+// Taps in the step kernel, the output is delayed by half of this
+const KERNEL_WIDTH: usize = 16;
+// Positions between two output samples the kernel is computed for
+const KERNEL_PHASES: usize = 64;
+// Kernel cutoff, as a fraction of the output sample rate
+const KERNEL_CUTOFF: f64 = 0.45;
+
+// The oldest samples are dropped once a second's worth are waiting
+const MAX_QUEUED_SECONDS: usize = 1;
+
+// One pole filter, as used on the NES's audio output
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32,
+}
+
+impl Filter {
+    fn high_pass(cutoff: f64, sample_rate: u32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        Filter {
+            high_pass: true,
+            alpha: (rc / (rc + dt)) as f32,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn low_pass(cutoff: f64, sample_rate: u32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f64;
+        Filter {
+            high_pass: false,
+            alpha: (dt / (rc + dt)) as f32,
+            prev_input: 0.0,
+            prev_output: 0.0,
+        }
+    }
+
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_output + input - self.prev_input)
+        } else {
+            self.prev_output + self.alpha * (input - self.prev_output)
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+pub struct AudioOutput {
+    sample_rate: u32,
+    samples_per_clock: f64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>, // one set of taps per phase, each summing to 1
+    // Level changes waiting to be summed, starting at the next output sample
+    deltas: VecDeque<f32>,
+    time: f64,  // position of the current CPU cycle, in output samples after deltas[0]
+    level: f32, // last level from the APU
+    sum: f32,
+    filters: [Filter; 3],
+    queue: VecDeque<f32>,
+}
+
+impl AudioOutput {
+    // clock_rate is the rate levels are passed to clock at
+    pub fn new(clock_rate: f64, sample_rate: u32) -> AudioOutput {
+        AudioOutput {
+            sample_rate,
+            samples_per_clock: sample_rate as f64 / clock_rate,
+            kernel: step_kernel(),
+            deltas: VecDeque::from(vec![0.0; KERNEL_WIDTH]),
+            time: 0.0,
+            level: 0.0,
+            sum: 0.0,
+            filters: [
+                Filter::high_pass(90.0, sample_rate),
+                Filter::high_pass(440.0, sample_rate),
+                Filter::low_pass(14_000.0, sample_rate),
+            ],
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Takes the output level for one clock
+    pub fn clock(&mut self, level: f32) {
+        if level != self.level {
+            let delta = level - self.level;
+            self.level = level;
+            let phase = (self.time * KERNEL_PHASES as f64) as usize;
+            for (slot, tap) in self.deltas.iter_mut().zip(&self.kernel[phase]) {
+                *slot += delta * tap;
+            }
+        }
+        self.time += self.samples_per_clock;
+        // Changes from now on can't affect the first sample any more
+        if self.time >= 1.0 {
+            self.time -= 1.0;
+            self.sum += self.deltas.pop_front().unwrap_or(0.0);
+            self.deltas.push_back(0.0);
+            let sample = self
+                .filters
+                .iter_mut()
+                .fold(self.sum, |sample, filter| filter.apply(sample));
+            if self.queue.len() >= self.sample_rate as usize * MAX_QUEUED_SECONDS {
+                self.queue.pop_front();
+            }
+            self.queue.push_back(sample);
+        }
+    }
+
+    // Number of samples waiting to be read
+    pub fn available(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Moves the oldest samples into out, returning how many there were. Samples are nominally
+    // between -1.0 and 1.0.
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.queue.len());
+        for (slot, sample) in out.iter_mut().zip(self.queue.drain(..count)) {
+            *slot = sample;
+        }
+        count
+    }
+    pub fn read_i16(&mut self, out: &mut [i16]) -> usize {
+        let count = out.len().min(self.queue.len());
+        for (slot, sample) in out.iter_mut().zip(self.queue.drain(..count)) {
+            *slot = to_i16(sample);
+        }
+        count
+    }
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+// Impulse responses of a band-limited step starting between two samples, for each phase
+fn step_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    let half = (KERNEL_WIDTH / 2) as f64;
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0; KERNEL_WIDTH];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = i as f64 - offset - half;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (2.0 * PI * KERNEL_CUTOFF * x).sin() / (2.0 * PI * KERNEL_CUTOFF * x)
+                };
+                // Blackman window
+                let w = x / half;
+                let window = 0.42 + 0.5 * (PI * w).cos() + 0.08 * (2.0 * PI * w).cos();
+                *tap = (sinc * window.max(0.0)) as f32;
+            }
+            let total: f32 = taps.iter().sum();
+            for tap in &mut taps {
+                *tap /= total;
+            }
+            taps
+        })
+        .collect()
+}
+
+// Writes 16 bit mono samples as a WAV file
+pub fn write_wav<W: io::Write>(out: &mut W, sample_rate: u32, samples: &[i16]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // channels
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?; // bytes per second
+    out.write_all(&2u16.to_le_bytes())?; // bytes per sample
+    out.write_all(&16u16.to_le_bytes())?; // bits per sample
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    let data = samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect::<Vec<_>>();
+    out.write_all(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apu::CPU_CLOCK_RATE;
+
+    fn drain(audio: &mut AudioOutput) -> Vec<f32> {
+        let mut samples = vec![0.0; audio.available()];
+        audio.read(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn sample_count_follows_the_rates() {
+        let mut audio = AudioOutput::new(176_400.0, 44_100);
+        for _ in 0..100_000 {
+            audio.clock(0.0);
+        }
+        assert_eq!(audio.available(), 25_000);
+
+        // Half a second of CPU cycles
+        let mut audio = AudioOutput::new(CPU_CLOCK_RATE, 48_000);
+        for _ in 0..(CPU_CLOCK_RATE / 2.0) as usize {
+            audio.clock(0.0);
+        }
+        assert!((23_999..=24_000).contains(&audio.available()));
+
+        // Only a second's worth are kept
+        let mut audio = AudioOutput::new(176_400.0, 44_100);
+        for _ in 0..176_400 * 2 {
+            audio.clock(0.0);
+        }
+        assert_eq!(audio.available(), 44_100);
+        assert_eq!(drain(&mut audio).len(), 44_100);
+        assert_eq!(audio.available(), 0);
+    }
+
+    #[test]
+    fn constant_level_decays_to_zero() {
+        let mut audio = AudioOutput::new(CPU_CLOCK_RATE, DEFAULT_SAMPLE_RATE);
+        for _ in 0..CPU_CLOCK_RATE as usize / 2 {
+            audio.clock(0.5);
+        }
+        let samples = drain(&mut audio);
+        // The step gets through, then the high-pass filters take it back down
+        let peak = samples
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(*sample));
+        assert!(peak > 0.3, "{}", peak);
+        for sample in &samples[samples.len() - 100..] {
+            assert!(sample.abs() < 1e-4, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn wav_header() {
+        let mut out = Vec::new();
+        write_wav(&mut out, 8000, &[1, -2]).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            b'R', b'I', b'F', b'F', 40, 0, 0, 0, b'W', b'A', b'V', b'E',
+            b'f', b'm', b't', b' ', 16, 0, 0, 0,
+            1, 0, // PCM
+            1, 0, // channels
+            0x40, 0x1F, 0, 0, // 8000Hz
+            0x80, 0x3E, 0, 0, // 16000 bytes per second
+            2, 0, // bytes per sample
+            16, 0, // bits per sample
+            b'd', b'a', b't', b'a', 4, 0, 0, 0,
+            0x01, 0x00, 0xFE, 0xFF,
+        ];
+        assert_eq!(out, expected);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::apu::{self, APU};
+use crate::audio::{self, AudioOutput};
+use crate::controller::{self, Controller, Port};
+use crate::mapper::Mapper;
+use crate::mem::Memory;
+use crate::ppu::PPU;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const RAM_END: u16 = 0x1FFF;
+pub const PPU_REGISTERS_START: u16 = 0x2000;
+pub const PPU_REGISTERS_END: u16 = 0x3FFF;
+pub const IO_REGISTERS_START: u16 = 0x4000;
+pub const IO_REGISTERS_END: u16 = 0x401F;
+pub const CARTRIDGE_START: u16 = 0x4020;
+pub const APU_REGISTERS_END: u16 = 0x4013;
+pub const OAM_DMA: u16 = 0x4014;
+pub const APU_STATUS: u16 = 0x4015;
+pub const CONTROLLER_1: u16 = 0x4016;
+pub const CONTROLLER_2: u16 = 0x4017;
+pub const APU_FRAME_COUNTER: u16 = 0x4017;
+
+// Everything the CPU is connected to. The CPU makes exactly one read or write per cycle, and
+// calls tick at the end of each one.
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    // Reads without side effects, for debuggers, disassemblers and trace logs
+    fn peek(&mut self, addr: u16) -> u8;
+
+    // Advances the devices on the bus by one CPU cycle
+    fn tick(&mut self) {}
+    // State of the NMI line, true while asserted
+    fn nmi(&self) -> bool {
+        false
+    }
+    // State of the shared IRQ line, true while any device is pulling it low
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BusAccess {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// 64KB of plain RAM with nothing else attached, for running 6502 code outside of the NES and
+// for testing the CPU. Can keep a log of every access.
+pub struct RamBus {
+    pub ram: Box<[u8; 0x10000]>,
+    pub log: Vec<BusAccess>,
+    logging: bool,
+}
+
+impl RamBus {
+    pub fn new() -> RamBus {
+        RamBus {
+            ram: Box::new([0; 0x10000]),
+            log: Vec::new(),
+            logging: false,
+        }
+    }
+
+    pub fn set_logging(&mut self, logging: bool) {
+        self.logging = logging;
+    }
+}
+
+impl Default for RamBus {
+    fn default() -> RamBus {
+        RamBus::new()
+    }
+}
+
+impl Bus for RamBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.ram[addr as usize];
+        if self.logging {
+            self.log.push(BusAccess::Read(addr, data));
+        }
+        data
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+        if self.logging {
+            self.log.push(BusAccess::Write(addr, data));
+        }
+    }
+    fn peek(&mut self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+}
+
+// The NES CPU's view of the 64kb address space. Dispatches each address to the device that
+// backs it, following the memory map documented in mem.rs.
+pub struct NesBus {
+    mem: Memory,
+    ppu: PPU,
+    apu: APU,
+    audio: AudioOutput, // resamples the APU's output
+    mapper: Box<dyn Mapper>,
+    controllers: [Controller; 2],
+    // Last value driven onto the data bus. Reads from addresses nothing responds to return it.
+    open_bus: u8,
+    cycles: u64, // CPU cycles since power on
+}
+
+impl Bus for NesBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = match addr {
+            0x0000..=RAM_END => self.mem.ram_read(addr),
+            PPU_REGISTERS_START..=PPU_REGISTERS_END => {
+                self.ppu.read_register(addr & 0x7, &mut *self.mapper)
+            }
+            // The status is read from inside the CPU, so it doesn't change the open bus value
+            APU_STATUS => return self.apu.read_status() | (self.open_bus & apu::OPEN_BUS_MASK),
+            CONTROLLER_1 | CONTROLLER_2 => {
+                let port = (addr - CONTROLLER_1) as usize;
+                self.controllers[port].read() | (self.open_bus & controller::OPEN_BUS_MASK)
+            }
+            // The other APU registers are write only
+            IO_REGISTERS_START..=IO_REGISTERS_END => self.open_bus,
+            CARTRIDGE_START..=0xFFFF => self.mapper.cpu_read(addr).unwrap_or(self.open_bus),
+        };
+        self.open_bus = data;
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+        match addr {
+            0x0000..=RAM_END => self.mem.ram_write(addr, data),
+            PPU_REGISTERS_START..=PPU_REGISTERS_END => {
+                self.ppu.write_register(addr & 0x7, data, &mut *self.mapper)
+            }
+            OAM_DMA => self.oam_dma(data),
+            CONTROLLER_1 => {
+                for controller in &mut self.controllers {
+                    controller.write_strobe(data);
+                }
+            }
+            IO_REGISTERS_START..=APU_REGISTERS_END | APU_STATUS | APU_FRAME_COUNTER => {
+                self.apu.write_register(addr, data)
+            }
+            IO_REGISTERS_START..=IO_REGISTERS_END => {}
+            CARTRIDGE_START..=0xFFFF => self.mapper.cpu_write(addr, data),
+        }
+    }
+
+    // Registers return the open bus value instead of being read, since reading them can change
+    // the state of the device
+    fn peek(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=RAM_END => self.mem.ram_read(addr),
+            APU_STATUS => self.apu.peek_status() | (self.open_bus & apu::OPEN_BUS_MASK),
+            CONTROLLER_1 | CONTROLLER_2 => {
+                let port = (addr - CONTROLLER_1) as usize;
+                self.controllers[port].peek() | (self.open_bus & controller::OPEN_BUS_MASK)
+            }
+            PPU_REGISTERS_START..=IO_REGISTERS_END => self.open_bus,
+            CARTRIDGE_START..=0xFFFF => self.mapper.cpu_peek(addr).unwrap_or(self.open_bus),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.clock();
+        if let Some(addr) = self.apu.dmc_request() {
+            self.dmc_dma(addr);
+        }
+    }
+
+    // State of the PPU's NMI output
+    fn nmi(&self) -> bool {
+        self.ppu.nmi()
+    }
+
+    fn irq(&self) -> bool {
+        self.mapper.irq() || self.apu.irq()
+    }
+}
+
+impl NesBus {
+    pub fn new(mapper: Box<dyn Mapper>) -> NesBus {
+        NesBus {
+            mem: Memory::new(),
+            ppu: PPU::new(),
+            apu: APU::new(),
+            audio: AudioOutput::new(apu::CPU_CLOCK_RATE, audio::DEFAULT_SAMPLE_RATE),
+            mapper,
+            controllers: [Controller::new(), Controller::new()],
+            open_bus: 0,
+            cycles: 0,
+        }
+    }
+
+    // Saves everything on the bus except the audio output, which isn't part of the machine
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"BUS ");
+        state.u8(self.open_bus);
+        state.u64(self.cycles);
+        for controller in &self.controllers {
+            controller.save_state(state);
+        }
+        self.mem.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.mapper.save_state(state);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"BUS ")?;
+        self.open_bus = state.u8()?;
+        self.cycles = state.u64()?;
+        for controller in &mut self.controllers {
+            controller.load_state(state)?;
+        }
+        self.mem.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.mapper.load_state(state)
+    }
+
+    // Presses the reset button on everything but the CPU
+    pub fn reset(&mut self) {
+        self.apu.reset();
+    }
+
+    // Advances every device by one CPU cycle
+    fn clock(&mut self) {
+        self.cycles += 1;
+        for _ in 0..3 {
+            self.ppu.tick(&mut *self.mapper);
+        }
+        self.mapper.cpu_clock();
+        self.apu.tick();
+        self.audio.clock(self.apu.output());
+    }
+
+    // Fetches the next sample byte for the DMC. The CPU is halted for the 4 cycles this takes.
+    fn dmc_dma(&mut self, addr: u16) {
+        for _ in 0..3 {
+            self.clock();
+        }
+        let data = self.read(addr);
+        self.clock();
+        self.apu.dmc_fill(data);
+    }
+
+    // Copies a 256 byte page (0xXX00-0xXXFF) to OAM. The CPU is halted for the 513 cycles this
+    // takes, plus one more if the DMA starts on an odd cycle.
+    fn oam_dma(&mut self, page: u8) {
+        self.tick();
+        if self.cycles % 2 == 1 {
+            self.tick();
+        }
+        let base = (page as u16) << 8;
+        for i in 0..256 {
+            let data = self.read(base + i);
+            self.tick();
+            self.ppu.write_oam_data(data, &mut *self.mapper);
+            self.tick();
+        }
+    }
+
+    pub fn controller(&self, port: Port) -> &Controller {
+        &self.controllers[port as usize]
+    }
+    pub fn controller_mut(&mut self, port: Port) -> &mut Controller {
+        &mut self.controllers[port as usize]
+    }
+
+    // CPU cycles since power on, including the ones the CPU spent halted for DMA
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn audio(&self) -> &AudioOutput {
+        &self.audio
+    }
+    pub fn audio_mut(&mut self) -> &mut AudioOutput {
+        &mut self.audio
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+}
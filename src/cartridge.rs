@@ -0,0 +1,459 @@
+// Cartridge loading for the iNES and NES 2.0 file formats.
+// Header layout (16 bytes):
+// 0-3   - Constant "NES" followed by MS-DOS end-of-file (0x1A)
+// 4     - PRG ROM size LSB (16KB units)
+// 5     - CHR ROM size LSB (8KB units)
+// 6     - Flags 6: mirroring, battery, trainer, four-screen, mapper bits 0-3
+// 7     - Flags 7: console type, NES 2.0 identifier, mapper bits 4-7
+// 8     - NES 2.0: mapper bits 8-11 and submapper. iNES: PRG RAM size (8KB units)
+// 9     - NES 2.0: PRG/CHR ROM size MSB. iNES: TV system
+// 10    - NES 2.0: PRG RAM/NVRAM shift counts
+// 11    - NES 2.0: CHR RAM/NVRAM shift counts
+// 12    - NES 2.0: CPU/PPU timing
+// 13    - NES 2.0: Vs. System type or extended console type
+// 14-15 - NES 2.0: misc ROM count and default expansion device
+// The header is followed by an optional 512 byte trainer, PRG ROM and CHR ROM.
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+pub const PRG_ROM_BANK_SIZE: usize = 0x4000;
+pub const CHR_ROM_BANK_SIZE: usize = 0x2000;
+// Smallest banks any supported mapper switches, exponent-multiplier sizes must be a multiple
+const PRG_ROM_MIN_BANK: usize = 0x2000;
+const CHR_ROM_MIN_BANK: usize = 0x400;
+const MAGIC: [u8; 4] = [b'N', b'E', b'S', 0x1A];
+
+#[derive(Clone)]
+pub struct Cartridge {
+    pub header: Header,
+    pub trainer: Option<Vec<u8>>, // 512 bytes loaded at 0x7000-0x71FF, if present
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>, // empty when the board uses CHR RAM instead
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub format: Format,
+    pub prg_rom_size: usize, // in bytes
+    pub chr_rom_size: usize, // in bytes
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool, // cartridge contains battery-backed PRG RAM or other persistent memory
+    pub trainer: bool,
+    pub prg_ram_size: usize,   // volatile PRG RAM in bytes
+    pub prg_nvram_size: usize, // battery-backed PRG RAM in bytes
+    pub chr_ram_size: usize,   // volatile CHR RAM in bytes
+    pub chr_nvram_size: usize, // battery-backed CHR RAM in bytes
+    pub console_type: ConsoleType,
+    pub timing: Timing,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    INes,
+    Nes2,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mirroring {
+    Horizontal,        // vertical arrangement of nametables: 0x2000 = 0x2400, 0x2800 = 0x2C00
+    Vertical,          // horizontal arrangement of nametables: 0x2000 = 0x2800, 0x2400 = 0x2C00
+    FourScreen, // cartridge provides an extra 2KB of VRAM so all four nametables are distinct
+    SingleScreenLower, // all nametables map to the first 1KB of VRAM, selected by mapper
+    SingleScreenUpper, // all nametables map to the second 1KB of VRAM, selected by mapper
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ConsoleType {
+    Nes, // Regular NES/Famicom/Dendy
+    VsSystem,
+    Playchoice10,
+    Extended(u8), // NES 2.0 extended console type from byte 13
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    InvalidMagic,
+    // File is shorter than its header says it should be
+    Truncated { expected: usize, actual: usize },
+    // NES 2.0 exponent-multiplier ROM size that doesn't fit in memory
+    RomTooLarge,
+    // NES 2.0 exponent-multiplier ROM size that isn't a whole number of the smallest bank mappers
+    // switch: 8KB for PRG ROM, 1KB for CHR ROM
+    UnalignedRomSize(usize),
+    UnsupportedMapper(u16),
+    MissingPrgRom,
+}
+
+impl Cartridge {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge, CartridgeError> {
+        let data = fs::read(path)?;
+        Cartridge::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(data)?;
+        let expected = HEADER_SIZE
+            + if header.trainer { TRAINER_SIZE } else { 0 }
+            + header.prg_rom_size
+            + header.chr_rom_size;
+        if data.len() < expected {
+            return Err(CartridgeError::Truncated {
+                expected,
+                actual: data.len(),
+            });
+        }
+
+        let mut offset = HEADER_SIZE;
+        let trainer = if header.trainer {
+            offset += TRAINER_SIZE;
+            Some(data[HEADER_SIZE..offset].to_vec())
+        } else {
+            None
+        };
+        let prg_rom = data[offset..offset + header.prg_rom_size].to_vec();
+        offset += header.prg_rom_size;
+        let chr_rom = data[offset..offset + header.chr_rom_size].to_vec();
+
+        Ok(Cartridge {
+            header,
+            trainer,
+            prg_rom,
+            chr_rom,
+        })
+    }
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Result<Header, CartridgeError> {
+        if data.len() < HEADER_SIZE {
+            return Err(CartridgeError::Truncated {
+                expected: HEADER_SIZE,
+                actual: data.len(),
+            });
+        }
+        if data[0..4] != MAGIC {
+            return Err(CartridgeError::InvalidMagic);
+        }
+
+        let flags_6 = data[6];
+        let flags_7 = data[7];
+        let format = if flags_7 & 0x0C == 0x08 {
+            Format::Nes2
+        } else {
+            Format::INes
+        };
+
+        let mirroring = if flags_6 & (1 << 3) != 0 {
+            Mirroring::FourScreen
+        } else if flags_6 & 1 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = flags_6 & (1 << 1) != 0;
+        let trainer = flags_6 & (1 << 2) != 0;
+
+        match format {
+            Format::Nes2 => {
+                let mapper = (flags_6 >> 4) as u16
+                    | (flags_7 & 0xF0) as u16
+                    | ((data[8] & 0x0F) as u16) << 8;
+                let prg_rom_size =
+                    nes2_rom_size(data[4], data[9] & 0x0F, PRG_ROM_BANK_SIZE, PRG_ROM_MIN_BANK)?;
+                let chr_rom_size =
+                    nes2_rom_size(data[5], data[9] >> 4, CHR_ROM_BANK_SIZE, CHR_ROM_MIN_BANK)?;
+                let console_type = match flags_7 & 0x03 {
+                    0 => ConsoleType::Nes,
+                    1 => ConsoleType::VsSystem,
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Extended(data[13] & 0x0F),
+                };
+                let timing = match data[12] & 0x03 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                Ok(Header {
+                    format,
+                    prg_rom_size,
+                    chr_rom_size,
+                    mapper,
+                    submapper: data[8] >> 4,
+                    mirroring,
+                    battery,
+                    trainer,
+                    prg_ram_size: nes2_ram_size(data[10] & 0x0F),
+                    prg_nvram_size: nes2_ram_size(data[10] >> 4),
+                    chr_ram_size: nes2_ram_size(data[11] & 0x0F),
+                    chr_nvram_size: nes2_ram_size(data[11] >> 4),
+                    console_type,
+                    timing,
+                })
+            }
+            Format::INes => {
+                // Old dumping tools wrote junk like "DiskDude!" into bytes 7-15. If the tail of the
+                // header isn't zeroed, the upper mapper nibble can't be trusted.
+                let mapper_hi = if data[12..16].iter().all(|&b| b == 0) {
+                    flags_7 & 0xF0
+                } else {
+                    0
+                };
+                let mapper = ((flags_6 >> 4) | mapper_hi) as u16;
+                let prg_rom_size = data[4] as usize * PRG_ROM_BANK_SIZE;
+                let chr_rom_size = data[5] as usize * CHR_ROM_BANK_SIZE;
+                // A value of 0 means 8KB for compatibility
+                let prg_ram_size = data[8].max(1) as usize * 0x2000;
+                let console_type = match flags_7 & 0x03 {
+                    1 => ConsoleType::VsSystem,
+                    2 => ConsoleType::Playchoice10,
+                    _ => ConsoleType::Nes,
+                };
+                let timing = if data[9] & 1 != 0 {
+                    Timing::Pal
+                } else {
+                    Timing::Ntsc
+                };
+                Ok(Header {
+                    format,
+                    prg_rom_size,
+                    chr_rom_size,
+                    mapper,
+                    submapper: 0,
+                    mirroring,
+                    battery,
+                    trainer,
+                    prg_ram_size: if battery { 0 } else { prg_ram_size },
+                    prg_nvram_size: if battery { prg_ram_size } else { 0 },
+                    // iNES has no way to specify CHR RAM, boards without CHR ROM get 8KB
+                    chr_ram_size: if chr_rom_size == 0 { 0x2000 } else { 0 },
+                    chr_nvram_size: 0,
+                    console_type,
+                    timing,
+                })
+            }
+        }
+    }
+}
+
+// NES 2.0 ROM sizes are either a plain 12 bit count of banks, or when the MSB nibble is 0xF,
+// an exponent-multiplier pair in the LSB byte: size = 2^E * (MM*2+1), where LSB = EEEEEEMM.
+// The exponent form can describe any size, so it has to come out a multiple of min_bank.
+fn nes2_rom_size(
+    lsb: u8,
+    msb: u8,
+    bank_size: usize,
+    min_bank: usize,
+) -> Result<usize, CartridgeError> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        let size = 1usize
+            .checked_shl(exponent)
+            .filter(|_| exponent < usize::BITS - 3)
+            .map(|size| size * multiplier)
+            .ok_or(CartridgeError::RomTooLarge)?;
+        if size % min_bank != 0 {
+            return Err(CartridgeError::UnalignedRomSize(size));
+        }
+        Ok(size)
+    } else {
+        Ok((((msb as usize) << 8) | lsb as usize) * bank_size)
+    }
+}
+
+// NES 2.0 RAM sizes are stored as shift counts: size = 64 << shift, with 0 meaning none
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "failed to read cartridge: {}", e),
+            CartridgeError::InvalidMagic => write!(f, "not an iNES or NES 2.0 file"),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "cartridge file is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            CartridgeError::RomTooLarge => write!(f, "ROM size in header is too large"),
+            CartridgeError::UnalignedRomSize(size) => write!(
+                f,
+                "ROM size in header ({} bytes) is not a whole number of banks",
+                size
+            ),
+            CartridgeError::UnsupportedMapper(n) => write!(f, "mapper {} is not supported", n),
+            CartridgeError::MissingPrgRom => write!(f, "cartridge has no PRG ROM"),
+        }
+    }
+}
+
+impl Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> CartridgeError {
+        CartridgeError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: [u8; 12]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&bytes);
+        data
+    }
+
+    #[test]
+    fn parses_ines_header() {
+        // Mapper 0x41, vertical mirroring with battery, 2 PRG banks, 1 CHR bank, PAL
+        let data = header([2, 1, 0x13, 0x40, 2, 1, 0, 0, 0, 0, 0, 0]);
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.format, Format::INes);
+        assert_eq!(header.mapper, 0x41);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.battery && !header.trainer);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0, 0x4000));
+        assert_eq!(header.chr_ram_size, 0);
+        assert_eq!(header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn ignores_mapper_high_nibble_with_junk_in_header() {
+        // Flags 7 is 'D' = 0x44, which would make this mapper 0x41
+        let mut data = header([1, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data[7..].copy_from_slice(b"DiskDude!");
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.mapper, 1);
+        // No CHR ROM means 8KB of CHR RAM
+        assert_eq!(header.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn parses_nes2_header() {
+        // Mapper 0x234 submapper 5, four-screen, 0x102 PRG banks, 3 CHR banks, 8KB PRG RAM,
+        // 32KB PRG NVRAM, 8KB CHR RAM, Dendy timing
+        let data = header([
+            0x02, 0x03, 0x48, 0x38, 0x52, 0x01, 0x97, 0x07, 0x03, 0, 0, 0,
+        ]);
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.format, Format::Nes2);
+        assert_eq!(header.mapper, 0x234);
+        assert_eq!(header.submapper, 5);
+        assert_eq!(header.mirroring, Mirroring::FourScreen);
+        assert_eq!(header.prg_rom_size, 0x102 * PRG_ROM_BANK_SIZE);
+        assert_eq!(header.chr_rom_size, 3 * CHR_ROM_BANK_SIZE);
+        assert_eq!(
+            (header.prg_ram_size, header.prg_nvram_size),
+            (0x2000, 0x8000)
+        );
+        assert_eq!((header.chr_ram_size, header.chr_nvram_size), (0x2000, 0));
+        assert_eq!(header.timing, Timing::Dendy);
+    }
+
+    #[test]
+    fn parses_nes2_exponent_rom_sizes() {
+        // PRG 2^14 * 3 = 48KB, CHR 2^10 * 1 = 1KB
+        let data = header([14 << 2 | 1, 10 << 2, 0, 0x08, 0, 0xFF, 0, 0, 0, 0, 0, 0]);
+        let header = Header::parse(&data).unwrap();
+        assert_eq!(header.prg_rom_size, 0xC000);
+        assert_eq!(header.chr_rom_size, 0x400);
+
+        // 2^0 * 1 = 1 byte can't be banked
+        let data = self::header([0, 10 << 2, 0, 0x08, 0, 0xFF, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Header::parse(&data),
+            Err(CartridgeError::UnalignedRomSize(1))
+        ));
+        // 2^12 * 1 = 4KB is fine for CHR ROM, but not a whole PRG bank
+        let data = self::header([12 << 2, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Header::parse(&data),
+            Err(CartridgeError::UnalignedRomSize(0x1000))
+        ));
+        // 2^9 * 1 = 512 bytes is less than a CHR bank
+        let data = self::header([1, 9 << 2, 0, 0x08, 0, 0xF0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Header::parse(&data),
+            Err(CartridgeError::UnalignedRomSize(0x200))
+        ));
+        let data = self::header([63 << 2, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Header::parse(&data),
+            Err(CartridgeError::RomTooLarge)
+        ));
+    }
+
+    #[test]
+    fn skips_trainer() {
+        let mut data = header([1, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0xAA; TRAINER_SIZE]);
+        data.extend_from_slice(&[0x11; PRG_ROM_BANK_SIZE]);
+        data.extend_from_slice(&[0x22; CHR_ROM_BANK_SIZE]);
+        let cartridge = Cartridge::from_bytes(&data).unwrap();
+        assert_eq!(cartridge.trainer, Some(vec![0xAA; TRAINER_SIZE]));
+        assert_eq!(cartridge.prg_rom, vec![0x11; PRG_ROM_BANK_SIZE]);
+        assert_eq!(cartridge.chr_rom, vec![0x22; CHR_ROM_BANK_SIZE]);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        assert!(matches!(
+            Cartridge::from_bytes(b"NES\x1A"),
+            Err(CartridgeError::Truncated {
+                expected: HEADER_SIZE,
+                actual: 4
+            })
+        ));
+
+        let mut data = header([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data[3] = 0;
+        assert!(matches!(
+            Cartridge::from_bytes(&data),
+            Err(CartridgeError::InvalidMagic)
+        ));
+
+        // Header says 16KB PRG ROM and a trainer, but only the trainer is there
+        let mut data = header([1, 0, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0; TRAINER_SIZE]);
+        assert!(matches!(
+            Cartridge::from_bytes(&data),
+            Err(CartridgeError::Truncated {
+                expected: 0x4210,
+                actual: 0x210
+            })
+        ));
+    }
+}
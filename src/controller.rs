@@ -0,0 +1,180 @@
+// Standard controller. The eight buttons are loaded in parallel into a shift register while the
+// strobe (bit 0 of writes to 0x4016) is high, and shifted out one bit per read once it's low:
+// 0x4016 - Port 1 (write: strobe for both ports)
+// 0x4017 - Port 2 (write: APU frame counter)
+//
+// Buttons are read in the order A, B, Select, Start, Up, Down, Left, Right. After all eight
+// have been read, official controllers return 1. Only bit 0 of the read value comes from the
+// controller, bits 5-7 are open bus.
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const BUTTON_A: u8 = 1 << 0;
+pub const BUTTON_B: u8 = 1 << 1;
+pub const BUTTON_SELECT: u8 = 1 << 2;
+pub const BUTTON_START: u8 = 1 << 3;
+pub const BUTTON_UP: u8 = 1 << 4;
+pub const BUTTON_DOWN: u8 = 1 << 5;
+pub const BUTTON_LEFT: u8 = 1 << 6;
+pub const BUTTON_RIGHT: u8 = 1 << 7;
+
+// Bits of a read that the controller port doesn't drive
+pub const OPEN_BUS_MASK: u8 = 0xE0;
+
+// The two controller ports. Port 1 is read through 0x4016, port 2 through 0x4017.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Port {
+    One = 0,
+    Two = 1,
+}
+
+#[derive(Default)]
+pub struct Controller {
+    buttons: u8, // currently pressed buttons, using the BUTTON_ bits
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift = buttons;
+        }
+    }
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    pub fn write_strobe(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    // Returns the next button in bit 0
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 1;
+        }
+        let bit = self.shift & 1;
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
+    }
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 1
+        } else {
+            self.shift & 1
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.buttons);
+        state.u8(self.shift);
+        state.bool(self.strobe);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = state.u8()?;
+        self.shift = state.u8()?;
+        self.strobe = state.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, NesBus, CONTROLLER_1, CONTROLLER_2};
+    use crate::mapper::{self, test_cartridge};
+
+    fn read_all(controller: &mut Controller) -> Vec<u8> {
+        (0..8).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn buttons_are_shifted_out_in_order() {
+        let buttons = [
+            BUTTON_A,
+            BUTTON_B,
+            BUTTON_SELECT,
+            BUTTON_START,
+            BUTTON_UP,
+            BUTTON_DOWN,
+            BUTTON_LEFT,
+            BUTTON_RIGHT,
+        ];
+        for (i, &button) in buttons.iter().enumerate() {
+            let mut controller = Controller::new();
+            controller.set_buttons(button);
+            controller.write_strobe(1);
+            controller.write_strobe(0);
+            let mut expected = vec![0; 8];
+            expected[i] = 1;
+            assert_eq!(
+                read_all(&mut controller),
+                expected,
+                "button {:#04x}",
+                button
+            );
+        }
+    }
+
+    #[test]
+    fn strobe_high_keeps_returning_a() {
+        let mut controller = Controller::new();
+        controller.set_buttons(BUTTON_A | BUTTON_START);
+        controller.write_strobe(1);
+        assert_eq!(read_all(&mut controller), vec![1; 8]);
+        // And follows the button while it's held
+        controller.set_buttons(BUTTON_START);
+        assert_eq!(controller.read(), 0);
+
+        // Buttons pressed since the strobe went low aren't seen until the next one
+        controller.write_strobe(0);
+        controller.set_buttons(BUTTON_A);
+        assert_eq!(read_all(&mut controller), vec![0, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reads_after_the_eighth_return_1() {
+        let mut controller = Controller::new();
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        assert_eq!(read_all(&mut controller), vec![0; 8]);
+        assert_eq!(read_all(&mut controller), vec![1; 8]);
+        assert_eq!(controller.peek(), 1);
+
+        // Until the next strobe
+        controller.set_buttons(BUTTON_B);
+        controller.write_strobe(1);
+        controller.write_strobe(0);
+        assert_eq!(read_all(&mut controller), vec![0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn ports_are_read_through_the_bus() {
+        let cartridge = test_cartridge(0, 0, 0x4000, 0);
+        let mut bus = NesBus::new(mapper::new(cartridge).unwrap());
+        bus.controller_mut(Port::One).set_buttons(BUTTON_A);
+        bus.controller_mut(Port::Two).set_buttons(BUTTON_B);
+        assert_eq!(bus.controller(Port::One).buttons(), BUTTON_A);
+        assert_eq!(bus.controller(Port::Two).buttons(), BUTTON_B);
+
+        // One strobe write reaches both ports, which are then read separately
+        bus.write(CONTROLLER_1, 1);
+        bus.write(CONTROLLER_1, 0);
+        let port_1 = (0..8)
+            .map(|_| bus.read(CONTROLLER_1) & 1)
+            .collect::<Vec<_>>();
+        let port_2 = (0..8)
+            .map(|_| bus.read(CONTROLLER_2) & 1)
+            .collect::<Vec<_>>();
+        assert_eq!(port_1, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(port_2, vec![0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -1,20 +1,62 @@
+use crate::bus::Bus;
 use crate::instruction::*;
 use crate::mem;
-use crate::mem::Memory;
-use std::cell::RefCell;
+use crate::state::{StateError, StateReader, StateWriter};
 use std::fmt;
-use std::rc::Rc;
 
-// 6502 CPU @ 1.79 MHz
-pub struct CPU {
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+// XAA and LXA OR the accumulator with a value that depends on the chip and its temperature
+// before the AND. 0xEE is what most 2A03s are measured to use.
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
+// Which 6502 the core behaves like
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Variant {
+    // Original NMOS 6502: ADC and SBC do binary-coded decimal arithmetic while D is set
+    Nmos6502,
+    // The NES's 2A03, which has the decimal logic disconnected. D can still be set and pushed.
+    Ricoh2A03,
+}
+
+// Register values, for debugging and for setting up tests
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Registers {
+    pub pc: u16,
+    pub accum: u8,
+    pub x: u8,
+    pub y: u8,
+    pub status: u8,
+    pub sp: u8,
+    pub cycle: u64,
+}
+
+// 6502 CPU @ 1.79 MHz. Everything outside the CPU is reached through the bus.
+pub struct CPU<B: Bus> {
+    variant: Variant,
     pc: u16,
     sp: u8, // Stack pointer holds lowest 8 bits of next free location on the stack. The stack resides between 0x100 and 0x1FF.
-    accum: i8, // Accumulator used for arithmetic operations
-    x: i8,
-    y: i8,
+    accum: u8, // Accumulator used for arithmetic operations
+    x: u8,
+    y: u8,
     status: StatusRegister,
-    mem: Rc<RefCell<Memory>>,
-    cycle: u64, // current cycle of the processor
+    bus: B,
+    cycle: u64,        // current cycle of the processor
+    nmi_line: bool,    // NMI input, edge triggered
+    nmi_pending: bool, // set on the falling edge of /NMI (rising edge of nmi_line) until serviced
+    irq_line: bool,    // IRQ input, level triggered
+    poll: Poll,        // interrupts detected at the end of the last cycle
+    prev_poll: Poll,   // and a cycle earlier, which is what the end of an instruction acts on
+    halted: bool,      // set by the JAM opcodes, only a reset recovers
+}
+
+// Interrupts the CPU would take if the current instruction ended now
+#[derive(Copy, Clone, Default)]
+struct Poll {
+    nmi: bool,
+    irq: bool,
 }
 
 // 8-bit register that contains flags about the state of the CPU
@@ -23,700 +65,838 @@ struct StatusRegister {
     c: u8, // Carry bit: set if last operation resulted in overflow from bit 7 or underflow from bit 0
     z: u8, // Zero bit: set if result of last operation was zero
     i: u8, // Interrupt disable
-    d: u8, // Decimal - no effect on NES, but on original 6502 causes some instructions to use binary-encoded decimal represenation
+    d: u8, // Decimal - no effect on NES, but on original 6502 makes ADC and SBC use binary-coded decimal representation
     b: u8, // B flag: doesn't actually exist in the status register, but exists when the flags are pushed onto the stack by certain instructions
     bit_5: u8, // Doesn't actually exist in the status register, but exists when the flags are pushed onto the stack by certain instructions
     v: u8, // Overflow: set if result of arithmetic operation resulted in invalid 2's complement result (i.e. sign bit is incorrect)
     n: u8, // Negative: contains bit 7 of value result
 }
 
-impl CPU {
-    pub fn new(mem: Rc<RefCell<Memory>>) -> CPU {
+impl<B: Bus> CPU<B> {
+    pub fn new(bus: B, variant: Variant) -> CPU<B> {
         CPU {
+            variant,
             pc: 0,
-            sp: 0xfd,
+            sp: 0,
             accum: 0,
             x: 0,
             y: 0,
             status: StatusRegister::new(),
-            mem,
+            bus,
             cycle: 0,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
+            poll: Poll::default(),
+            prev_poll: Poll::default(),
+            halted: false,
         }
     }
+    // Runs the 7 cycle reset sequence: it's the interrupt sequence with the stack writes turned
+    // into reads, so SP goes down by 3 without touching memory. At power on SP is 0, leaving 0xFD.
     pub fn reset(&mut self) {
-        self.pc = 0;
-        self.sp = 0xfd;
-        self.status = StatusRegister::new();
-        self.cycle = 0
+        self.dummy_read();
+        self.dummy_read();
+        for _ in 0..3 {
+            self.dummy_stack_read();
+            self.sp = self.sp.wrapping_sub(1);
+        }
+        self.status.set_i();
+        self.nmi_pending = false;
+        let lsb = self.read(RESET_VECTOR) as u16;
+        let msb = self.read(RESET_VECTOR + 1) as u16;
+        self.pc = lsb | (msb << 8);
+        self.prev_poll = Poll::default();
+        self.halted = false;
+    }
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            accum: self.accum,
+            x: self.x,
+            y: self.y,
+            status: self.status.get_flags(),
+            sp: self.sp,
+            cycle: self.cycle,
+        }
+    }
+    // Jumps to an address without executing anything, for running test programs that have
+    // their own entry point
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+    // Loads every register but the cycle counter
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.pc = registers.pc;
+        self.accum = registers.accum;
+        self.x = registers.x;
+        self.y = registers.y;
+        self.status.set_flags(registers.status);
+        self.sp = registers.sp;
+    }
+    pub fn bus(&self) -> &B {
+        &self.bus
+    }
+    pub fn bus_mut(&mut self) -> &mut B {
+        &mut self.bus
+    }
+
+    // Saves everything but the bus, which has its own state
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"CPU ");
+        state.u16(self.pc);
+        state.u8(self.sp);
+        state.u8(self.accum);
+        state.u8(self.x);
+        state.u8(self.y);
+        state.u8(self.status.get_flags());
+        state.u64(self.cycle);
+        state.bool(self.nmi_line);
+        state.bool(self.nmi_pending);
+        state.bool(self.irq_line);
+        for poll in &[self.poll, self.prev_poll] {
+            state.bool(poll.nmi);
+            state.bool(poll.irq);
+        }
+        state.bool(self.halted);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"CPU ")?;
+        self.pc = state.u16()?;
+        self.sp = state.u8()?;
+        self.accum = state.u8()?;
+        self.x = state.u8()?;
+        self.y = state.u8()?;
+        self.status.set_flags(state.u8()?);
+        self.cycle = state.u64()?;
+        self.nmi_line = state.bool()?;
+        self.nmi_pending = state.bool()?;
+        self.irq_line = state.bool()?;
+        for poll in &mut [&mut self.poll, &mut self.prev_poll] {
+            poll.nmi = state.bool()?;
+            poll.irq = state.bool()?;
+        }
+        self.halted = state.bool()?;
+        Ok(())
+    }
+    // True once a JAM opcode has locked up the CPU
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+    // Drives the NMI input. An NMI is triggered when the line goes from inactive to active.
+    pub fn set_nmi(&mut self, active: bool) {
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = active;
+    }
+    // Drives the IRQ input. IRQs are triggered for as long as the line is active and I is clear.
+    pub fn set_irq(&mut self, active: bool) {
+        self.irq_line = active;
     }
+    // Ends the current CPU cycle: steps the rest of the system and samples the interrupt lines
     fn tick_clock(&mut self) {
-        // Later: This will also tick the PPU * 3
         self.cycle += 1;
+        self.bus.tick();
+        let (nmi, irq) = (self.bus.nmi(), self.bus.irq());
+        self.set_nmi(nmi);
+        self.set_irq(irq);
+        self.prev_poll = self.poll;
+        self.poll = Poll {
+            nmi: self.nmi_pending,
+            irq: self.irq_line && self.status.get_i() == 0,
+        };
+    }
+    // Every bus access takes one CPU cycle
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.bus.read(addr);
+        self.tick_clock();
+        data
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.bus.write(addr, data);
+        self.tick_clock();
+    }
+    // Reads the byte at PC and moves past it
+    fn fetch_byte(&mut self) -> u8 {
+        let data = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        data
+    }
+    fn fetch_u16(&mut self) -> u16 {
+        let lsb = self.fetch_byte() as u16;
+        let msb = self.fetch_byte() as u16;
+        lsb | (msb << 8)
     }
-    fn fetch_instruction(&mut self) -> Instruction {
-        let opcode = self.mem.borrow().ram_read(self.pc);
-        self.pc += 1;
-        Instruction::new(opcode)
+    fn fetch_instruction(&mut self) -> &'static Instruction {
+        Instruction::decode(self.fetch_byte())
     }
     fn push_byte(&mut self, data: u8) {
-        self.mem
-            .borrow_mut()
-            .ram_write(mem::STACK_TOP + self.sp as u16, data);
-        self.sp -= 1;
+        self.write(mem::STACK_TOP + self.sp as u16, data);
+        self.sp = self.sp.wrapping_sub(1);
     }
     fn pop_byte(&mut self) -> u8 {
-        self.sp += 1;
-        self.mem.borrow().ram_read(mem::STACK_TOP + self.sp as u16)
+        self.sp = self.sp.wrapping_add(1);
+        self.read(mem::STACK_TOP + self.sp as u16)
+    }
+    // Single byte instructions still read the byte after the opcode, and throw it away
+    fn dummy_read(&mut self) {
+        self.read(self.pc);
+    }
+    // Pulls start with a dummy read of the current top of the stack before SP is incremented
+    fn dummy_stack_read(&mut self) {
+        self.read(mem::STACK_TOP + self.sp as u16);
     }
 
     fn execute_instruction(&mut self, inst: &Instruction) {
+        let mode = inst.addr_mode;
         match inst.op {
-            // TODO validate carry flag and overflow flag behavior
             OpCode::ADC => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if signed_overflow_add(self.accum, operand + self.status.get_c() as i8) {
-                    self.status.set_v();
-                } else {
-                    self.status.clear_v();
-                }
-                if self
-                    .accum
-                    .checked_add(operand + self.status.get_c() as i8)
-                    .is_none()
-                {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                self.accum = self.accum.wrapping_add(operand + self.status.get_c() as i8);
+                let operand = self.load(mode);
+                self.add_with_carry(operand);
             }
             OpCode::AND => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                self.accum &= operand;
-                if self.accum == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.accum < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.accum &= self.load(mode);
+                self.status.update_zn(self.accum);
             }
             OpCode::ASL => {
-                let (operand, addr) = self.get_operand(inst.addr_mode);
-                if (operand & (1 << 7)) >> 7 == 1 {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                let result = operand << 1;
-                if (result & (1 << 7)) >> 7 == 1 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
-                if let Some(addr) = addr {
-                    self.mem.borrow_mut().ram_write(addr, result as u8);
-                } else {
-                    self.accum = result;
-                }
-            }
-            OpCode::BCC => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_c() == 0 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
-            }
-            OpCode::BCS => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_c() == 1 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
-            }
-            OpCode::BEQ => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_z() == 1 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
+                self.modify(mode, CPU::shift_left);
             }
+            OpCode::BCC => self.branch(self.status.get_c() == 0),
+            OpCode::BCS => self.branch(self.status.get_c() == 1),
+            OpCode::BEQ => self.branch(self.status.get_z() == 1),
             OpCode::BIT => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                let result = self.accum & operand;
-                if result == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if (result & (1 << 6)) >> 6 == 1 {
-                    self.status.set_v();
-                } else {
-                    self.status.clear_v();
-                }
-                if (result & (1 << 7)) >> 7 == 1 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
-            }
-            OpCode::BMI => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_n() == 1 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
-            }
-            OpCode::BNE => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_z() == 0 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
-            }
-            OpCode::BPL => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_n() == 0 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
-            }
+                // Z comes from A & M, while V and N are copied straight from bits 6 and 7 of M
+                let operand = self.load(mode);
+                self.status.set_z_to(self.accum & operand == 0);
+                self.status.set_v_to(operand & 0x40 != 0);
+                self.status.set_n_to(operand & 0x80 != 0);
+            }
+            OpCode::BMI => self.branch(self.status.get_n() == 1),
+            OpCode::BNE => self.branch(self.status.get_z() == 0),
+            OpCode::BPL => self.branch(self.status.get_n() == 0),
             OpCode::BRK => {
-                // Step 1: Push PC and Status Flags onto the stack
-                let pc_lsb = (self.pc & 0xFF) as u8;
-                let pc_msb = ((self.pc & 0xFF00) >> 8) as u8;
-
-                self.push_byte(pc_lsb);
-                self.push_byte(pc_msb);
-                self.push_byte(self.status.get_flags());
-
-                // Step 2: Load IRQ vector (held at 0xFFFE and OXFFFF) into PC
-                let irq_vec_lsb = self.mem.borrow().ram_read(0xFFFE) as u16;
-                let irq_vec_msb = self.mem.borrow().ram_read(0xFFFF) as u16;
-                self.pc = irq_vec_lsb | (irq_vec_msb << 8);
-
-                // Step 3: Set B flag
-                self.status.set_b();
-            }
-            OpCode::BVC => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_v() == 0 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
-            }
-            OpCode::BVS => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.status.get_v() == 1 {
-                    let new_pc = (self.pc as i16 + operand as i16) as u16;
-                    if page_crossed(self.pc, new_pc) {
-                        self.tick_clock();
-                    }
-                    self.pc = (self.pc as i16 + operand as i16) as u16;
-                    self.tick_clock();
-                }
+                // BRK skips the byte after the opcode, and runs the interrupt sequence with the B flag
+                // set in the pushed status
+                self.fetch_byte();
+                self.interrupt_sequence(IRQ_VECTOR, true);
             }
+            OpCode::BVC => self.branch(self.status.get_v() == 0),
+            OpCode::BVS => self.branch(self.status.get_v() == 1),
             OpCode::CLC => {
+                self.dummy_read();
                 self.status.clear_c();
             }
             OpCode::CLD => {
+                self.dummy_read();
                 self.status.clear_d();
             }
             OpCode::CLI => {
+                self.dummy_read();
                 self.status.clear_i();
             }
             OpCode::CLV => {
+                self.dummy_read();
                 self.status.clear_v();
             }
             OpCode::CMP => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.accum >= operand {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                if self.accum == operand {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.accum - operand < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                let operand = self.load(mode);
+                self.compare(self.accum, operand);
             }
             OpCode::CPX => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.x >= operand {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                if self.x == operand {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.x - operand < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                let operand = self.load(mode);
+                self.compare(self.x, operand);
             }
             OpCode::CPY => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if self.y >= operand {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                if self.y == operand {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.y - operand < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                let operand = self.load(mode);
+                self.compare(self.y, operand);
             }
             OpCode::DEC => {
-                let (operand, addr) = self.get_operand(inst.addr_mode);
-                let res = operand - 1;
-                if res == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if res < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
-                self.mem.borrow_mut().ram_write(addr.unwrap(), res as u8);
+                self.modify(mode, CPU::decrement);
             }
             OpCode::DEX => {
-                self.x -= 1;
-                if self.x == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.x < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.dummy_read();
+                self.x = self.x.wrapping_sub(1);
+                self.status.update_zn(self.x);
             }
             OpCode::DEY => {
-                self.y -= 1;
-                if self.y == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.y < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.dummy_read();
+                self.y = self.y.wrapping_sub(1);
+                self.status.update_zn(self.y);
             }
             OpCode::EOR => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                self.accum ^= operand;
-                if self.accum == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.accum < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.accum ^= self.load(mode);
+                self.status.update_zn(self.accum);
             }
             OpCode::INC => {
-                let (operand, addr) = self.get_operand(inst.addr_mode);
-                let res = operand + 1;
-                if res == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if res < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
-                self.mem.borrow_mut().ram_write(addr.unwrap(), res as u8);
+                self.modify(mode, CPU::increment);
             }
             OpCode::INX => {
-                self.x += 1;
-                if self.x == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.x < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.dummy_read();
+                self.x = self.x.wrapping_add(1);
+                self.status.update_zn(self.x);
             }
             OpCode::INY => {
-                self.y += 1;
-                if self.y == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.y < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.dummy_read();
+                self.y = self.y.wrapping_add(1);
+                self.status.update_zn(self.y);
             }
             OpCode::JMP => {
-                let (_, addr) = self.get_operand(inst.addr_mode);
-                self.pc = addr.unwrap();
+                self.pc = self.operand_address(mode, Access::Other);
             }
             OpCode::JSR => {
-                let (_, addr) = self.get_operand(inst.addr_mode);
-                // Push ret addr onto stack
-                let pc_lsb = (self.pc & 0xFF) as u8;
-                let pc_msb = ((self.pc & 0xFF00) >> 8) as u8;
-                self.push_byte(pc_lsb);
-                self.push_byte(pc_msb);
-                self.pc = addr.unwrap();
+                // The return address pushed is the last byte of the JSR, RTS adds 1 to it. The
+                // high byte of the target is only fetched after the push.
+                let lsb = self.fetch_byte() as u16;
+                self.dummy_stack_read();
+                self.push_byte((self.pc >> 8) as u8);
+                self.push_byte((self.pc & 0xFF) as u8);
+                let msb = self.read(self.pc) as u16;
+                self.pc = lsb | (msb << 8);
             }
             OpCode::LDA => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                self.accum = operand as i8;
-                if self.accum == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.accum < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.accum = self.load(mode);
+                self.status.update_zn(self.accum);
             }
             OpCode::LDX => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                self.x = operand as i8;
-                if self.x == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.x < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.x = self.load(mode);
+                self.status.update_zn(self.x);
             }
             OpCode::LDY => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                self.y = operand as i8;
-                if self.y == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.y < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.y = self.load(mode);
+                self.status.update_zn(self.y);
             }
             OpCode::LSR => {
-                let (operand, addr) = self.get_operand(inst.addr_mode);
-                let sign_bit = operand & (1 << 7);
-                if sign_bit == 1 {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                // Have to do this bc behavior of shift operator in Rust depends on signed-ness of operand (arithmetic shift on i-types, logical shift on u-types)
-                let result = (operand as u8) >> 1;
-                self.status.clear_n();
-                if let Some(addr) = addr {
-                    self.mem.borrow_mut().ram_write(addr, result);
+                self.modify(mode, CPU::shift_right);
+            }
+            // The unofficial NOPs with an operand perform the read, including the extra cycle
+            // when indexing crosses a page
+            OpCode::NOP => {
+                if mode == AddrMode::Implicit {
+                    self.dummy_read();
                 } else {
-                    self.accum = result as i8;
+                    self.load(mode);
                 }
             }
-            OpCode::NOP => {}
             OpCode::ORA => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                self.accum |= operand;
-                if self.accum == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.accum < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.accum |= self.load(mode);
+                self.status.update_zn(self.accum);
             }
             OpCode::PHA => {
-                self.push_byte(self.accum as u8);
+                self.dummy_read();
+                self.push_byte(self.accum);
             }
             OpCode::PHP => {
-                self.push_byte(self.status.get_flags());
+                // PHP pushes with the B flag set
+                self.dummy_read();
+                self.push_byte(self.status.get_flags() | (1 << 4) | (1 << 5));
             }
             OpCode::PLA => {
-                self.accum = self.pop_byte() as i8;
-                if self.accum == 0 {
-                    self.status.set_z();
-                } else {
-                    self.status.clear_z();
-                }
-                if self.accum < 0 {
-                    self.status.set_n();
-                } else {
-                    self.status.clear_n();
-                }
+                self.dummy_read();
+                self.dummy_stack_read();
+                self.accum = self.pop_byte();
+                self.status.update_zn(self.accum);
             }
             OpCode::PLP => {
+                self.dummy_read();
+                self.dummy_stack_read();
                 let flags = self.pop_byte();
                 self.status.set_flags(flags);
             }
             OpCode::ROL => {
-                // Semantics: Bit 0 is filled with current carry flag value. Old bit 7 goes into the carry flag.
-                let (operand, addr) = self.get_operand(inst.addr_mode);
-                let old_sign_bit = operand & (1 << 7);
-                let curr_carry_flag = self.status.get_c();
-                if old_sign_bit == 0 {
-                    self.status.clear_c();
-                } else {
-                    self.status.set_c();
-                }
-                let mut result = operand.rotate_left(1);
-                // clear least significant bit
-                result &= !1;
-                result |= curr_carry_flag as i8;
-                if let Some(addr) = addr {
-                    self.mem.borrow_mut().ram_write(addr, result as u8);
-                } else {
-                    self.accum = result;
-                }
+                self.modify(mode, CPU::rotate_left);
             }
             OpCode::ROR => {
-                // Semantics: Bit 7 is filled with the current carry flag value. Old bit 0 goes into the carry flag.
-                let (operand, addr) = self.get_operand(inst.addr_mode);
-                let old_bit_zero = operand & 1;
-                let curr_carry_flag = self.status.get_c();
-                if old_bit_zero == 0 {
-                    self.status.clear_c();
-                } else {
-                    self.status.set_c();
-                }
-                let mut result = operand.rotate_right(1);
-                // clear msb
-                result &= !(1 << 7);
-                result |= (curr_carry_flag << 7) as i8;
-                if let Some(addr) = addr {
-                    self.mem.borrow_mut().ram_write(addr, result as u8);
-                } else {
-                    self.accum = result;
-                }
+                self.modify(mode, CPU::rotate_right);
             }
             OpCode::RTI => {
+                self.dummy_read();
+                self.dummy_stack_read();
                 let new_flags = self.pop_byte();
                 self.status.set_flags(new_flags);
-                let pc_msb = self.pop_byte();
                 let pc_lsb = self.pop_byte();
-                let new_pc = pc_lsb as u16 | (pc_msb as u16) << 8;
-                self.pc = new_pc;
+                let pc_msb = self.pop_byte();
+                self.pc = pc_lsb as u16 | (pc_msb as u16) << 8;
             }
             OpCode::RTS => {
-                let pc_msb = self.pop_byte();
+                self.dummy_read();
+                self.dummy_stack_read();
                 let pc_lsb = self.pop_byte();
-                let new_pc = pc_lsb as u16 | (pc_msb as u16) << 8;
-                self.pc = new_pc;
+                let pc_msb = self.pop_byte();
+                self.pc = pc_lsb as u16 | (pc_msb as u16) << 8;
+                // Moves from the last byte of the JSR to the next instruction
+                self.fetch_byte();
             }
-            // TODO validate carry flag and overflow flag behavior
             OpCode::SBC => {
-                let (operand, _) = self.get_operand(inst.addr_mode);
-                if signed_overflow_sub(self.accum, operand - (1 - self.status.get_c()) as i8) {
-                    self.status.set_v();
-                } else {
-                    self.status.clear_v();
-                }
-                if self
-                    .accum
-                    .checked_add(operand - (1 - self.status.get_c()) as i8)
-                    .is_none()
-                {
-                    self.status.set_c();
-                } else {
-                    self.status.clear_c();
-                }
-                self.accum = self.accum.wrapping_sub(operand + self.status.get_c() as i8);
+                let operand = self.load(mode);
+                self.subtract_with_carry(operand);
             }
             OpCode::SEC => {
+                self.dummy_read();
                 self.status.set_c();
             }
             OpCode::SED => {
+                self.dummy_read();
                 self.status.set_d();
             }
             OpCode::SEI => {
+                self.dummy_read();
                 self.status.set_i();
             }
-            OpCode::STA => {
-                let (_, addr) = self.get_operand(inst.addr_mode);
-                self.mem
-                    .borrow_mut()
-                    .ram_write(addr.unwrap(), self.accum as u8);
-            }
-            OpCode::STX => {
-                let (_, addr) = self.get_operand(inst.addr_mode);
-                self.mem.borrow_mut().ram_write(addr.unwrap(), self.x as u8);
-            }
-            OpCode::STY => {
-                let (_, addr) = self.get_operand(inst.addr_mode);
-                self.mem.borrow_mut().ram_write(addr.unwrap(), self.y as u8);
-            }
+            OpCode::STA => self.store(mode, self.accum),
+            OpCode::STX => self.store(mode, self.x),
+            OpCode::STY => self.store(mode, self.y),
             OpCode::TAX => {
+                self.dummy_read();
                 self.x = self.accum;
+                self.status.update_zn(self.x);
             }
             OpCode::TAY => {
+                self.dummy_read();
                 self.y = self.accum;
+                self.status.update_zn(self.y);
             }
             OpCode::TSX => {
-                self.x = self.sp as i8;
+                self.dummy_read();
+                self.x = self.sp;
+                self.status.update_zn(self.x);
             }
             OpCode::TXA => {
+                self.dummy_read();
                 self.accum = self.x;
+                self.status.update_zn(self.accum);
             }
             OpCode::TXS => {
-                self.sp = self.x as u8;
+                // The only transfer that doesn't affect the flags
+                self.dummy_read();
+                self.sp = self.x;
             }
             OpCode::TYA => {
+                self.dummy_read();
                 self.accum = self.y;
+                self.status.update_zn(self.accum);
+            }
+
+            OpCode::ALR => {
+                self.accum &= self.load(mode);
+                self.accum = CPU::shift_right(self, self.accum);
+                self.status.update_zn(self.accum);
+            }
+            OpCode::ANC => {
+                self.accum &= self.load(mode);
+                self.status.update_zn(self.accum);
+                self.status.set_c_to(self.accum & 0x80 != 0);
+            }
+            OpCode::ARR => {
+                let operand = self.load(mode);
+                self.and_rotate_right(operand);
+            }
+            OpCode::AXS => {
+                let operand = self.load(mode);
+                let value = self.accum & self.x;
+                self.status.set_c_to(value >= operand);
+                self.x = value.wrapping_sub(operand);
+                self.status.update_zn(self.x);
+            }
+            OpCode::DCP => {
+                let result = self.modify(mode, CPU::decrement);
+                self.compare(self.accum, result);
+            }
+            OpCode::ISC => {
+                let result = self.modify(mode, CPU::increment);
+                self.subtract_with_carry(result);
+            }
+            OpCode::JAM => {
+                self.dummy_read();
+                self.halted = true;
+            }
+            OpCode::LAS => {
+                let value = self.load(mode) & self.sp;
+                self.accum = value;
+                self.x = value;
+                self.sp = value;
+                self.status.update_zn(value);
+            }
+            OpCode::LAX => {
+                self.accum = self.load(mode);
+                self.x = self.accum;
+                self.status.update_zn(self.accum);
+            }
+            OpCode::LXA => {
+                self.accum = (self.accum | UNSTABLE_MAGIC) & self.load(mode);
+                self.x = self.accum;
+                self.status.update_zn(self.accum);
+            }
+            OpCode::RLA => {
+                self.accum &= self.modify(mode, CPU::rotate_left);
+                self.status.update_zn(self.accum);
+            }
+            OpCode::RRA => {
+                let result = self.modify(mode, CPU::rotate_right);
+                self.add_with_carry(result);
+            }
+            OpCode::SAX => self.store(mode, self.accum & self.x),
+            OpCode::SHA => self.store_high_and(mode, self.accum & self.x),
+            OpCode::SHX => self.store_high_and(mode, self.x),
+            OpCode::SHY => self.store_high_and(mode, self.y),
+            OpCode::SLO => {
+                self.accum |= self.modify(mode, CPU::shift_left);
+                self.status.update_zn(self.accum);
+            }
+            OpCode::SRE => {
+                self.accum ^= self.modify(mode, CPU::shift_right);
+                self.status.update_zn(self.accum);
+            }
+            OpCode::TAS => {
+                self.sp = self.accum & self.x;
+                self.store_high_and(mode, self.sp);
+            }
+            OpCode::XAA => {
+                self.accum = (self.accum | UNSTABLE_MAGIC) & self.x & self.load(mode);
+                self.status.update_zn(self.accum);
             }
         }
-        for _ in 0..inst.cycles {
-            self.tick_clock();
+    }
+
+    // Shifts and rotates shared by the official read-modify-write instructions and the
+    // unofficial ones built on them. The old bit shifted out goes into C.
+    fn shift_left(&mut self, operand: u8) -> u8 {
+        self.status.set_c_to(operand & 0x80 != 0);
+        operand << 1
+    }
+    fn shift_right(&mut self, operand: u8) -> u8 {
+        self.status.set_c_to(operand & 1 != 0);
+        operand >> 1
+    }
+    // Bit 0 is filled with the current carry flag value
+    fn rotate_left(&mut self, operand: u8) -> u8 {
+        let result = (operand << 1) | self.status.get_c();
+        self.status.set_c_to(operand & 0x80 != 0);
+        result
+    }
+    // Bit 7 is filled with the current carry flag value
+    fn rotate_right(&mut self, operand: u8) -> u8 {
+        let result = (operand >> 1) | (self.status.get_c() << 7);
+        self.status.set_c_to(operand & 1 != 0);
+        result
+    }
+    fn increment(&mut self, operand: u8) -> u8 {
+        operand.wrapping_add(1)
+    }
+    fn decrement(&mut self, operand: u8) -> u8 {
+        operand.wrapping_sub(1)
+    }
+
+    // ARR: A = (A & M) ROR 1, but C comes from bit 6 of the result and V from bit 6 XOR bit 5
+    // In decimal mode on the NMOS 6502 each nibble of the result gets a BCD style correction,
+    // and C comes from the high nibble correction instead.
+    fn and_rotate_right(&mut self, operand: u8) {
+        let value = self.accum & operand;
+        let result = (value >> 1) | (self.status.get_c() << 7);
+        if !self.decimal_mode() {
+            self.status.set_c_to(result & 0x40 != 0);
+            self.status
+                .set_v_to(((result >> 6) ^ (result >> 5)) & 1 != 0);
+            self.accum = result;
+            self.status.update_zn(result);
+            return;
         }
+        self.status.update_zn(result);
+        self.status.set_v_to((value ^ result) & 0x40 != 0);
+        let mut result = result;
+        if (value & 0x0F) + (value & 0x01) > 0x05 {
+            result = (result & 0xF0) | (result.wrapping_add(0x06) & 0x0F);
+        }
+        let high_carry = (value & 0xF0) as u16 + (value & 0x10) as u16 > 0x50;
+        if high_carry {
+            result = result.wrapping_add(0x60);
+        }
+        self.status.set_c_to(high_carry);
+        self.accum = result;
+    }
+
+    // SHA, SHX, SHY and TAS store the value ANDed with the high byte of the base address plus 1.
+    // If indexing crosses a page, the stored value also ends up as the high byte of the address.
+    fn store_high_and(&mut self, addr_mode: AddrMode, data: u8) {
+        let (base, index) = match addr_mode {
+            AddrMode::AbsoluteX => (self.fetch_u16(), self.x),
+            AddrMode::AbsoluteY => (self.fetch_u16(), self.y),
+            _ => {
+                let in_addr = self.fetch_byte();
+                (self.read_zero_page_u16(in_addr), self.y)
+            }
+        };
+        let addr = self.indexed(base, index, Access::Write);
+        let data = data & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if page_crossed(base, addr) {
+            ((data as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.write(addr, data);
+    }
+
+    // A + M + C. C is set on unsigned overflow (a carry out of bit 7), V on signed overflow,
+    // which happens when both inputs have the same sign and the result's sign differs.
+    fn add_with_carry(&mut self, operand: u8) {
+        if self.decimal_mode() {
+            self.add_with_carry_decimal(operand);
+        } else {
+            self.add_with_carry_binary(operand);
+        }
+    }
+    fn add_with_carry_binary(&mut self, operand: u8) {
+        let sum = self.accum as u16 + operand as u16 + self.status.get_c() as u16;
+        let result = sum as u8;
+        self.status.set_c_to(sum > 0xFF);
+        self.status
+            .set_v_to((self.accum ^ result) & (operand ^ result) & 0x80 != 0);
+        self.accum = result;
+        self.status.update_zn(result);
+    }
+
+    // A - M - (1 - C) is A + !M + C in two's complement, so SBC is ADC of the inverted operand.
+    // C ends up set when no borrow was needed.
+    fn subtract_with_carry(&mut self, operand: u8) {
+        if self.decimal_mode() {
+            self.subtract_with_carry_decimal(operand);
+        } else {
+            self.add_with_carry_binary(!operand);
+        }
+    }
+
+    fn decimal_mode(&self) -> bool {
+        self.variant == Variant::Nmos6502 && self.status.get_d() == 1
+    }
+
+    // NMOS decimal ADC adds each nibble and corrects it by 6 when it passes 9. Z still comes from
+    // the binary sum, and N and V from the result before the high nibble is corrected, so they
+    // don't mean much for BCD results. Invalid BCD inputs give the same results as the real chip.
+    fn add_with_carry_decimal(&mut self, operand: u8) {
+        let (a, m, c) = (self.accum, operand, self.status.get_c());
+        self.status.set_z_to(a.wrapping_add(m).wrapping_add(c) == 0);
+        let mut lo = (a & 0x0F) + (m & 0x0F) + c;
+        if lo >= 0x0A {
+            lo = ((lo + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (a & 0xF0) as u16 + (m & 0xF0) as u16 + lo as u16;
+        let signed_sum = (a & 0xF0) as i8 as i16 + (m & 0xF0) as i8 as i16 + lo as i16;
+        self.status.set_n_to(sum & 0x80 != 0);
+        self.status.set_v_to(!(-128..=127).contains(&signed_sum));
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.status.set_c_to(sum >= 0x100);
+        self.accum = sum as u8;
+    }
+
+    // NMOS decimal SBC sets every flag exactly like binary SBC, only the result is corrected
+    fn subtract_with_carry_decimal(&mut self, operand: u8) {
+        let (a, m, c) = (
+            self.accum as i16,
+            operand as i16,
+            self.status.get_c() as i16,
+        );
+        self.add_with_carry_binary(!operand);
+        let mut lo = (a & 0x0F) - (m & 0x0F) + c - 1;
+        if lo < 0 {
+            lo = ((lo - 0x06) & 0x0F) - 0x10;
+        }
+        let mut result = (a & 0xF0) - (m & 0xF0) + lo;
+        if result < 0 {
+            result -= 0x60;
+        }
+        self.accum = result as u8;
+    }
+
+    // CMP, CPX and CPY: flags are set as if the operand was subtracted from the register
+    fn compare(&mut self, register: u8, operand: u8) {
+        self.status.set_c_to(register >= operand);
+        self.status.update_zn(register.wrapping_sub(operand));
+    }
+
+    // Taken branches take an extra cycle, and another one if the target is on a different page.
+    // In that case the CPU first reads from the target address before the high byte is fixed.
+    fn branch(&mut self, condition: bool) {
+        let offset = self.fetch_byte();
+        if !condition {
+            return;
+        }
+        let target = self.pc.wrapping_add(offset as i8 as u16);
+        if page_crossed(self.pc, target) {
+            self.dummy_read();
+            self.read((self.pc & 0xFF00) | (target & 0x00FF));
+        } else {
+            // Interrupts aren't polled in the extra cycle of a taken branch that stays on the
+            // same page, so one that arrived during it waits until after the next instruction
+            if !self.prev_poll.nmi {
+                self.poll.nmi = false;
+            }
+            if !self.prev_poll.irq {
+                self.poll.irq = false;
+            }
+            self.dummy_read();
+        }
+        self.pc = target;
     }
-    // Returns (operand, operand_addr)
-    fn get_operand(&mut self, addr_mode: AddrMode) -> (i8, Option<u16>) {
+
+    // Reads the operand of an instruction that only uses it as an input
+    fn load(&mut self, addr_mode: AddrMode) -> u8 {
+        let addr = self.operand_address(addr_mode, Access::Read);
+        self.read(addr)
+    }
+    fn store(&mut self, addr_mode: AddrMode, data: u8) {
+        let addr = self.operand_address(addr_mode, Access::Write);
+        self.write(addr, data);
+    }
+    // Read-modify-write: the 6502 writes the unmodified value back while it computes the result,
+    // then writes the result. Z and N are set from the result.
+    fn modify(&mut self, addr_mode: AddrMode, op: fn(&mut CPU<B>, u8) -> u8) -> u8 {
+        let result = if addr_mode == AddrMode::Accumulator {
+            self.dummy_read();
+            let result = op(self, self.accum);
+            self.accum = result;
+            result
+        } else {
+            let addr = self.operand_address(addr_mode, Access::ReadModifyWrite);
+            let operand = self.read(addr);
+            self.write(addr, operand);
+            let result = op(self, operand);
+            self.write(addr, result);
+            result
+        };
+        self.status.update_zn(result);
+        result
+    }
+
+    // Runs the addressing cycles of the mode and returns the effective address
+    fn operand_address(&mut self, addr_mode: AddrMode, access: Access) -> u16 {
         match addr_mode {
-            AddrMode::Absolute => {
-                let addr = self.mem.borrow().ram_read(self.pc) as u16
-                    | (self.mem.borrow().ram_read(self.pc + 1) << 8) as u16;
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
+            AddrMode::Immediate | AddrMode::Relative => {
+                let addr = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                addr
+            }
+            AddrMode::ZeroPage => mem::ZERO_PAGE_START + self.fetch_byte() as u16,
+            // Zero page indexing wraps around within the zero page. The CPU reads from the
+            // unindexed address while it adds the index.
+            AddrMode::ZeroPageX | AddrMode::ZeroPageY => {
+                let base = self.fetch_byte();
+                self.read(mem::ZERO_PAGE_START + base as u16);
+                let index = if addr_mode == AddrMode::ZeroPageX {
+                    self.x
+                } else {
+                    self.y
+                };
+                mem::ZERO_PAGE_START + base.wrapping_add(index) as u16
             }
+            AddrMode::Absolute => self.fetch_u16(),
             AddrMode::AbsoluteX => {
-                let addr = (self.mem.borrow().ram_read(self.pc) as u16
-                    | (self.mem.borrow().ram_read(self.pc + 1) << 8) as u16)
-                    + self.x as u16;
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
+                let base = self.fetch_u16();
+                self.indexed(base, self.x, access)
             }
             AddrMode::AbsoluteY => {
-                let addr = (self.mem.borrow().ram_read(self.pc) as u16
-                    | (self.mem.borrow().ram_read(self.pc + 1) << 8) as u16)
-                    + self.y as u16;
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
-            }
-            AddrMode::Immediate => (self.mem.borrow().ram_read(self.pc) as i8, None),
-            AddrMode::ZeroPage => {
-                let addr = mem::ZERO_PAGE_START + self.mem.borrow().ram_read(self.pc) as u16;
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
-            }
-            AddrMode::ZeroPageX => {
-                let addr = mem::ZERO_PAGE_START
-                    + ((self.mem.borrow().ram_read(self.pc) + self.x as u8) % 255) as u16;
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
-            }
-            AddrMode::ZeroPageY => {
-                let addr = mem::ZERO_PAGE_START
-                    + ((self.mem.borrow().ram_read(self.pc) + self.y as u8) as u16 % 256);
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
-            }
-            AddrMode::Relative => (self.mem.borrow().ram_read(self.pc) as i8, None),
+                let base = self.fetch_u16();
+                self.indexed(base, self.y, access)
+            }
             AddrMode::Indirect => {
-                let in_addr = self.mem.borrow().ram_read(self.pc) as u16
-                    | (self.mem.borrow().ram_read(self.pc + 1) << 8) as u16;
                 // Original 6502 doesn't fetch Indirect addresses correctly when the indirect address vector falls on a page boundary.
-                // The logic below encodes this behavior.
-                let addr = if (in_addr + 1) % 256 == 0 {
-                    self.mem.borrow().ram_read(in_addr) as u16
-                        | (self.mem.borrow().ram_read(in_addr + 1) << 8) as u16
-                } else {
-                    self.mem.borrow().ram_read(in_addr) as u16
-                        | (self.mem.borrow().ram_read(in_addr & 0xFF00) << 8) as u16
-                };
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
+                // The high byte is read from the start of the same page instead of the next one.
+                let in_addr = self.fetch_u16();
+                let lsb = self.read(in_addr) as u16;
+                let msb = self.read((in_addr & 0xFF00) | (in_addr.wrapping_add(1) & 0x00FF)) as u16;
+                lsb | (msb << 8)
             }
             AddrMode::IndexedIndirect => {
-                let in_addr = mem::ZERO_PAGE_START
-                    + ((self.mem.borrow().ram_read(self.pc) + self.x as u8) as u16 % 256);
-                let addr = self.mem.borrow().ram_read(in_addr) as u16
-                    | (self.mem.borrow().ram_read(in_addr + 1) << 8) as u16;
-                (self.mem.borrow().ram_read(addr) as i8, Some(addr))
+                let in_addr = self.fetch_byte();
+                self.read(mem::ZERO_PAGE_START + in_addr as u16);
+                self.read_zero_page_u16(in_addr.wrapping_add(self.x))
             }
             AddrMode::IndirectIndexed => {
-                let in_addr = mem::ZERO_PAGE_START + self.mem.borrow().ram_read(self.pc) as u16;
-                let addr = self.mem.borrow().ram_read(in_addr) as u16
-                    | (self.mem.borrow().ram_read(in_addr + 1) << 8) as u16;
-                (
-                    self.mem.borrow().ram_read(addr + self.y as u16) as i8,
-                    Some(addr + self.y as u16),
-                )
+                let in_addr = self.fetch_byte();
+                let base = self.read_zero_page_u16(in_addr);
+                self.indexed(base, self.y, access)
             }
-            AddrMode::Accumulator => (self.accum as i8, None),
-            AddrMode::Implicit => (0, None), // should never be used
+            // These don't address memory, instructions using them never ask for an address
+            AddrMode::Accumulator | AddrMode::Implicit => unreachable!(),
         }
     }
 
+    // The index is added to the low byte first, and the CPU reads from that address while it
+    // carries into the high byte. Reads that don't cross a page use that read and skip the fix
+    // up cycle, but writes always take it since they can't undo a write to the wrong address.
+    fn indexed(&mut self, base: u16, index: u8, access: Access) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        let crossed = page_crossed(base, addr);
+        if crossed || access != Access::Read {
+            self.read((base & 0xFF00) | (addr & 0x00FF));
+        }
+        addr
+    }
+
+    // 16 bit pointer read from the zero page, where the high byte wraps around to 0x00
+    fn read_zero_page_u16(&mut self, addr: u8) -> u16 {
+        let lsb = self.read(mem::ZERO_PAGE_START + addr as u16) as u16;
+        let msb = self.read(mem::ZERO_PAGE_START + addr.wrapping_add(1) as u16) as u16;
+        lsb | (msb << 8)
+    }
+
+    // Pushes PC and the status flags, disables further interrupts and jumps through the vector.
+    // This is the last 5 cycles of BRK, IRQ and NMI. If an NMI is detected before the vector is
+    // fetched, it hijacks the sequence and the NMI vector is used instead, while the pushed B flag
+    // still tells the handler whether this started as a BRK.
+    fn interrupt_sequence(&mut self, vector: u16, brk: bool) {
+        self.push_byte((self.pc >> 8) as u8);
+        self.push_byte((self.pc & 0xFF) as u8);
+        let b = if brk { 1 << 4 } else { 0 };
+        self.push_byte((self.status.get_flags() & !(1 << 4)) | b | (1 << 5));
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else {
+            vector
+        };
+        self.status.set_i();
+        let lsb = self.read(vector) as u16;
+        let msb = self.read(vector + 1) as u16;
+        self.pc = lsb | (msb << 8);
+        // The first instruction of the handler always runs before another interrupt is taken
+        self.prev_poll = Poll::default();
+    }
+
+    // Hardware interrupt entry: two dummy reads of the next opcode, then the interrupt sequence.
+    // Takes 7 cycles.
+    fn interrupt(&mut self, vector: u16) {
+        self.dummy_read();
+        self.dummy_read();
+        self.interrupt_sequence(vector, false);
+    }
+
+    // Runs one instruction, and then the interrupt sequence if an interrupt was detected before
+    // the instruction's last cycle. Flags changed in the last cycle (CLI, SEI and PLP) only
+    // affect interrupts after the next instruction, while RTI restores I early enough to apply
+    // immediately.
     pub fn advance_cpu(&mut self) {
+        // A jammed CPU keeps the bus busy without ever fetching another instruction
+        if self.halted {
+            self.read(0xFFFF);
+            return;
+        }
         let inst = self.fetch_instruction();
-        self.execute_instruction(&inst);
-        self.pc += (inst.size - 1) as u16;
+        self.execute_instruction(inst);
+
+        if self.halted {
+            // Interrupts can't wake up a jammed CPU either
+        } else if self.prev_poll.nmi {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR);
+        } else if self.prev_poll.irq {
+            self.interrupt(IRQ_VECTOR);
+        }
     }
 }
 
-impl fmt::Debug for CPU {
+impl<B: Bus> fmt::Debug for CPU<B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("CPU")
             .field("pc", &self.pc)
@@ -753,12 +933,6 @@ impl StatusRegister {
         self.c
     }
 
-    pub fn set_z(&mut self) {
-        self.z = 1;
-    }
-    pub fn clear_z(&mut self) {
-        self.z = 0;
-    }
     pub fn get_z(&self) -> u8 {
         self.z
     }
@@ -783,29 +957,6 @@ impl StatusRegister {
         self.d
     }
 
-    pub fn set_b(&mut self) {
-        self.b = 1;
-    }
-    pub fn clear_b(&mut self) {
-        self.b = 0;
-    }
-    pub fn get_b(&self) -> u8 {
-        self.b
-    }
-
-    pub fn set_bit_5(&mut self) {
-        self.bit_5 = 1;
-    }
-    pub fn clear_bit_5(&mut self) {
-        self.bit_5 = 0;
-    }
-    pub fn get_bit_5(&self) -> u8 {
-        self.bit_5
-    }
-
-    pub fn set_v(&mut self) {
-        self.v = 1;
-    }
     pub fn clear_v(&mut self) {
         self.v = 0;
     }
@@ -813,16 +964,28 @@ impl StatusRegister {
         self.v
     }
 
-    pub fn set_n(&mut self) {
-        self.n = 1;
-    }
-    pub fn clear_n(&mut self) {
-        self.n = 0;
-    }
     pub fn get_n(&self) -> u8 {
         self.n
     }
 
+    pub fn set_c_to(&mut self, carry: bool) {
+        self.c = carry as u8;
+    }
+    pub fn set_z_to(&mut self, zero: bool) {
+        self.z = zero as u8;
+    }
+    pub fn set_v_to(&mut self, overflow: bool) {
+        self.v = overflow as u8;
+    }
+    pub fn set_n_to(&mut self, negative: bool) {
+        self.n = negative as u8;
+    }
+    // Z and N are set from the result of almost every instruction that produces a value
+    pub fn update_zn(&mut self, result: u8) {
+        self.set_z_to(result == 0);
+        self.set_n_to(result & 0x80 != 0);
+    }
+
     pub fn get_flags(&self) -> u8 {
         self.c
             | (self.z << 1)
@@ -835,28 +998,680 @@ impl StatusRegister {
     }
     pub fn set_flags(&mut self, flags: u8) {
         self.c = flags & 1;
-        self.z = flags & (1 << 1);
-        self.i = flags & (1 << 2);
-        self.d = flags & (1 << 3);
-        self.b = flags & (1 << 4);
-        self.bit_5 = flags & (1 << 5);
-        self.v = flags & (1 << 6);
-        self.n = flags & (1 << 7);
+        self.z = (flags >> 1) & 1;
+        self.i = (flags >> 2) & 1;
+        self.d = (flags >> 3) & 1;
+        self.b = (flags >> 4) & 1;
+        self.bit_5 = (flags >> 5) & 1;
+        self.v = (flags >> 6) & 1;
+        self.n = (flags >> 7) & 1;
     }
 }
 
-// Checks if adding these numbers will result in an incorrect sign bit
-fn signed_overflow_add(x: i8, y: i8) -> bool {
-    (x > 0 && y > 0 && x.wrapping_add(y) <= 0) || (x < 0 && y < 0 && x.wrapping_add(y) >= 0)
-}
-// Checks if subtracting these numbers will result in an incorrect sign bit
-fn signed_overflow_sub(x: i8, y: i8) -> bool {
-    (x > 0 && y > 0 && x > y && x.wrapping_sub(y) <= 0)
-        || (x > 0 && y > 0 && x < y && x.wrapping_sub(y) >= 0)
-        || (x > 0 && y < 0 && x.wrapping_sub(y) < 0)
-        || (x < 0 && y > 0 && x.wrapping_sub(y) > 0)
-}
 // checks if a page boundary is crossed
 fn page_crossed(old_addr: u16, new_addr: u16) -> bool {
-    old_addr >> 7 == new_addr >> 7
+    old_addr & 0xFF00 != new_addr & 0xFF00
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BusAccess::{self, Read, Write};
+    use crate::bus::RamBus;
+    use std::ops::Range;
+
+    const C: u8 = 1 << 0;
+    const Z: u8 = 1 << 1;
+    const I: u8 = 1 << 2;
+    const D: u8 = 1 << 3;
+    const V: u8 = 1 << 6;
+    const N: u8 = 1 << 7;
+
+    const START: u16 = 0x0200;
+
+    // A 2A03 on plain RAM with the program at START, straight after power on
+    fn setup(program: &[u8]) -> CPU<RamBus> {
+        let mut bus = RamBus::new();
+        bus.ram[START as usize..START as usize + program.len()].copy_from_slice(program);
+        let mut cpu = CPU::new(bus, Variant::Ricoh2A03);
+        cpu.pc = START;
+        cpu.sp = 0xFD;
+        cpu
+    }
+
+    // RAM with NMI and IRQ lines that are asserted for a range of cycles
+    struct InterruptBus {
+        ram: RamBus,
+        cycles: u64,
+        nmi: Range<u64>,
+        irq: Range<u64>,
+    }
+
+    impl Bus for InterruptBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            self.ram.read(addr)
+        }
+        fn write(&mut self, addr: u16, data: u8) {
+            self.ram.write(addr, data);
+        }
+        fn peek(&mut self, addr: u16) -> u8 {
+            self.ram.peek(addr)
+        }
+        fn tick(&mut self) {
+            self.cycles += 1;
+        }
+        fn nmi(&self) -> bool {
+            self.nmi.contains(&self.cycles)
+        }
+        fn irq(&self) -> bool {
+            self.irq.contains(&self.cycles)
+        }
+    }
+
+    const NMI_HANDLER: u16 = 0x0300; // a page of NOPs
+    const IRQ_HANDLER: u16 = 0x0400; // RTI
+
+    // Like setup, with handlers for both interrupts and neither line asserted yet
+    fn setup_interrupts(program: &[u8]) -> CPU<InterruptBus> {
+        let mut ram = setup(program).bus;
+        ram.ram[NMI_HANDLER as usize..NMI_HANDLER as usize + 0x100].copy_from_slice(&[0xEA; 0x100]);
+        ram.ram[IRQ_HANDLER as usize] = 0x40;
+        for &(vector, handler) in [(NMI_VECTOR, NMI_HANDLER), (IRQ_VECTOR, IRQ_HANDLER)].iter() {
+            ram.ram[vector as usize..vector as usize + 2].copy_from_slice(&handler.to_le_bytes());
+        }
+        let bus = InterruptBus {
+            ram,
+            cycles: 0,
+            nmi: 0..0,
+            irq: 0..0,
+        };
+        let mut cpu = CPU::new(bus, Variant::Ricoh2A03);
+        cpu.pc = START;
+        cpu.sp = 0xFD;
+        cpu
+    }
+
+    // The return address and status pushed by the last interrupt
+    fn pushed(cpu: &CPU<InterruptBus>) -> (u16, u8) {
+        let ram = &cpu.bus.ram.ram;
+        let top = mem::STACK_TOP as usize + cpu.sp as usize;
+        (
+            u16::from_le_bytes([ram[top + 2], ram[top + 3]]),
+            ram[top + 1],
+        )
+    }
+
+    // Runs one instruction and returns the cycles it took
+    fn step<B: Bus>(cpu: &mut CPU<B>) -> u64 {
+        let start = cpu.cycle;
+        cpu.advance_cpu();
+        cpu.cycle - start
+    }
+
+    // Runs one instruction and returns every bus access it made
+    fn accesses(cpu: &mut CPU<RamBus>) -> Vec<BusAccess> {
+        cpu.bus.set_logging(true);
+        cpu.advance_cpu();
+        cpu.bus.set_logging(false);
+        std::mem::take(&mut cpu.bus.log)
+    }
+
+    // The flags without B and bit 5, which only exist on the stack
+    fn flags<B: Bus>(cpu: &CPU<B>) -> u8 {
+        cpu.status.get_flags() & !0x30
+    }
+
+    #[test]
+    fn indexed_dummy_reads() {
+        // LDA absolute,X reads the address before the carry only when it crosses a page
+        let mut cpu = setup(&[0xBD, 0xF0, 0x12, 0xBD, 0x00, 0x12]);
+        cpu.x = 0x20;
+        cpu.bus.ram[0x1210] = 0x11;
+        cpu.bus.ram[0x1310] = 0x22;
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0200, 0xBD), Read(0x0201, 0xF0), Read(0x0202, 0x12),
+            Read(0x1210, 0x11), Read(0x1310, 0x22),
+        ]);
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0203, 0xBD), Read(0x0204, 0x00), Read(0x0205, 0x12), Read(0x1220, 0x00),
+        ]);
+
+        // LDA (indirect),Y the same, after reading the pointer
+        let mut cpu = setup(&[0xB1, 0x10]);
+        cpu.y = 0x20;
+        cpu.bus.ram[0x10..0x12].copy_from_slice(&[0xF0, 0x12]);
+        cpu.bus.ram[0x1310] = 0x22;
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0200, 0xB1), Read(0x0201, 0x10), Read(0x0010, 0xF0), Read(0x0011, 0x12),
+            Read(0x1210, 0x00), Read(0x1310, 0x22),
+        ]);
+
+        // Stores always make the dummy read, even without crossing a page
+        let mut cpu = setup(&[0x9D, 0x00, 0x12]);
+        cpu.x = 0x20;
+        cpu.accum = 0x33;
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0200, 0x9D), Read(0x0201, 0x00), Read(0x0202, 0x12),
+            Read(0x1220, 0x00), Write(0x1220, 0x33),
+        ]);
+    }
+
+    #[test]
+    fn read_modify_write_writes_twice() {
+        // INC absolute writes the old value back before the new one
+        let mut cpu = setup(&[0xEE, 0x00, 0x12, 0x1E, 0xF0, 0x12]);
+        cpu.bus.ram[0x1200] = 0x41;
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0200, 0xEE), Read(0x0201, 0x00), Read(0x0202, 0x12),
+            Read(0x1200, 0x41), Write(0x1200, 0x41), Write(0x1200, 0x42),
+        ]);
+
+        // ASL absolute,X crossing a page, after the dummy read
+        cpu.x = 0x20;
+        cpu.bus.ram[0x1310] = 0x81;
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0203, 0x1E), Read(0x0204, 0xF0), Read(0x0205, 0x12), Read(0x1210, 0x00),
+            Read(0x1310, 0x81), Write(0x1310, 0x81), Write(0x1310, 0x02),
+        ]);
+    }
+
+    #[test]
+    fn implied_and_stack_dummy_reads() {
+        // INX reads the next byte and throws it away
+        let mut cpu = setup(&[0xE8, 0x48, 0x68, 0x60]);
+        cpu.accum = 0x42;
+        assert_eq!(accesses(&mut cpu), [Read(0x0200, 0xE8), Read(0x0201, 0x48)]);
+        // PHA
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0201, 0x48), Read(0x0202, 0x68), Write(0x01FD, 0x42),
+        ]);
+        // PLA also reads the top of the stack before incrementing SP
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0202, 0x68), Read(0x0203, 0x60), Read(0x01FC, 0x00), Read(0x01FD, 0x42),
+        ]);
+        // RTS, then a read of the last byte of the JSR while moving past it
+        cpu.bus.ram[0x01FE..0x0200].copy_from_slice(&[0x02, 0x03]);
+        #[rustfmt::skip]
+        assert_eq!(accesses(&mut cpu), [
+            Read(0x0203, 0x60), Read(0x0204, 0x00), Read(0x01FD, 0x42), Read(0x01FE, 0x02),
+            Read(0x01FF, 0x03), Read(0x0302, 0x00),
+        ]);
+        assert_eq!(cpu.pc, 0x0303);
+    }
+
+    #[test]
+    fn pushed_status_bits() {
+        // PHP and BRK push B and bit 5 set
+        let mut cpu = setup_interrupts(&[0x08, 0x00, 0xEA]);
+        cpu.status.set_c();
+        cpu.status.set_z_to(true);
+        step(&mut cpu);
+        assert_eq!(cpu.bus.ram.ram[0x01FD], 0x30 | I | Z | C);
+        step(&mut cpu);
+        assert_eq!(pushed(&cpu), (START + 3, 0x30 | I | Z | C));
+
+        // IRQ and NMI push B clear, even after PLP pulled it set
+        let mut cpu = setup_interrupts(&[0x28, 0xEA, 0xEA]);
+        cpu.bus.ram.ram[0x01FE] = !I;
+        cpu.bus.irq = 0..u64::MAX;
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu).1, !I & !0x10);
+
+        let mut cpu = setup_interrupts(&[0x28, 0xEA]);
+        cpu.bus.ram.ram[0x01FE] = 0x00;
+        cpu.bus.nmi = 0..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+        assert_eq!(pushed(&cpu).1, 0x20);
+
+        // And PHP still pushes them set after PLP pulled them clear
+        let mut cpu = setup(&[0x28, 0x08]);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.bus.ram[0x01FE], 0x30);
+    }
+
+    #[test]
+    fn registers_wrap() {
+        // INX, DEY, then PHA with SP at 0
+        let mut cpu = setup(&[0xE8, 0x88, 0x48]);
+        cpu.x = 0xFF;
+        step(&mut cpu);
+        assert_eq!((cpu.x, flags(&cpu)), (0x00, I | Z));
+        step(&mut cpu);
+        assert_eq!((cpu.y, flags(&cpu)), (0xFF, I | N));
+        cpu.sp = 0x00;
+        cpu.accum = 0x42;
+        step(&mut cpu);
+        assert_eq!((cpu.sp, cpu.bus.ram[0x0100]), (0xFF, 0x42));
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered() {
+        let mut cpu = setup_interrupts(&[0xEA; 8]);
+        cpu.bus.nmi = 0..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+        assert_eq!(pushed(&cpu).0, START + 1);
+
+        // Holding the line active doesn't trigger another one
+        for _ in 0..4 {
+            step(&mut cpu);
+        }
+        assert_eq!(cpu.pc, NMI_HANDLER + 4);
+
+        // A new edge does, even if the line is only active for a cycle
+        cpu.bus.nmi = cpu.cycle + 2..cpu.cycle + 3;
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+        assert_eq!(pushed(&cpu).0, NMI_HANDLER + 6);
+    }
+
+    #[test]
+    fn irq_is_level_triggered() {
+        // Asserted while I is set and released before CLI: never taken
+        let mut cpu = setup_interrupts(&[0xEA, 0x58, 0xEA, 0xEA]);
+        cpu.bus.irq = 0..3;
+        for _ in 0..4 {
+            step(&mut cpu);
+        }
+        assert_eq!(cpu.pc, START + 4);
+
+        // Held active, it's taken again as soon as RTI clears I
+        let mut cpu = setup_interrupts(&[0xEA; 4]);
+        cpu.status.clear_i();
+        cpu.bus.irq = 0..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu).0, START + 1);
+        cpu.bus.irq = 0..0;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, START + 1);
+    }
+
+    #[test]
+    fn flag_changes_delay_irq_by_an_instruction() {
+        // CLI: the instruction after it runs before the IRQ is taken
+        let mut cpu = setup_interrupts(&[0x58, 0xEA, 0xEA]);
+        cpu.bus.irq = 0..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, START + 1);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu).0, START + 2);
+
+        // SEI: an IRQ can still be taken straight after it, with I set in the pushed status
+        let mut cpu = setup_interrupts(&[0x78, 0xEA]);
+        cpu.status.clear_i();
+        cpu.bus.irq = 0..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu), (START + 1, 0x20 | I));
+
+        // PLP clearing I behaves like CLI
+        let mut cpu = setup_interrupts(&[0x28, 0xEA, 0xEA]);
+        cpu.bus.ram.ram[0x01FE] = 0x00;
+        cpu.bus.irq = 0..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, START + 1);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu).0, START + 2);
+    }
+
+    #[test]
+    fn taken_branches_without_page_crossing_delay_interrupts() {
+        // BEQ +0: the IRQ arrives in the second cycle and isn't seen until after the next
+        // instruction
+        let mut cpu = setup_interrupts(&[0xF0, 0x00, 0xEA, 0xEA]);
+        cpu.status.clear_i();
+        cpu.status.set_z_to(true);
+        cpu.bus.irq = 2..u64::MAX;
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.pc, START + 2);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu).0, START + 3);
+
+        // BEQ -128 crosses a page, and the same IRQ is taken straight after it
+        let mut cpu = setup_interrupts(&[0xF0, 0x80]);
+        cpu.bus.ram.ram[START as usize + 2 - 0x80] = 0xEA;
+        cpu.status.clear_i();
+        cpu.status.set_z_to(true);
+        cpu.bus.irq = 2..u64::MAX;
+        assert_eq!(step(&mut cpu), 4 + 7);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        assert_eq!(pushed(&cpu).0, START + 2 - 0x80);
+
+        // NMIs are delayed the same way
+        let mut cpu = setup_interrupts(&[0xF0, 0x00, 0xEA, 0xEA]);
+        cpu.status.set_z_to(true);
+        cpu.bus.nmi = 2..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, START + 2);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk_and_irq() {
+        // An NMI during BRK's pushes takes over its vector, B is still set in the pushed status
+        let mut cpu = setup_interrupts(&[0x00, 0xEA]);
+        cpu.bus.nmi = 3..u64::MAX;
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+        assert_eq!(pushed(&cpu), (START + 2, 0x30 | I));
+        // And it isn't taken a second time
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER + 1);
+
+        // One arriving after the vector fetch starts runs after the first handler instruction
+        let mut cpu = setup_interrupts(&[0x00, 0xEA]);
+        cpu.bus.nmi = 6..u64::MAX;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, IRQ_HANDLER);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+
+        // The same during an IRQ, with B clear
+        let mut cpu = setup_interrupts(&[0xEA, 0xEA]);
+        cpu.status.clear_i();
+        cpu.bus.irq = 0..u64::MAX;
+        cpu.bus.nmi = 5..u64::MAX;
+        assert_eq!(step(&mut cpu), 2 + 7);
+        assert_eq!(cpu.pc, NMI_HANDLER);
+        assert_eq!(pushed(&cpu), (START + 1, 0x20));
+        step(&mut cpu);
+        assert_eq!(cpu.pc, NMI_HANDLER + 1);
+    }
+
+    // Runs one immediate instruction with D set, returning A and the flags
+    fn decimal(variant: Variant, opcode: u8, accum: u8, operand: u8, carry: u8) -> (u8, u8) {
+        let mut cpu = setup(&[opcode, operand]);
+        cpu.variant = variant;
+        cpu.accum = accum;
+        cpu.status.set_d();
+        cpu.status.set_c_to(carry != 0);
+        step(&mut cpu);
+        (cpu.accum, flags(&cpu) & !(I | D))
+    }
+
+    #[test]
+    fn decimal_adc() {
+        // A, M and C, then the result and flags. Z comes from the binary sum, N and V from the
+        // sum before the high nibble is corrected.
+        #[rustfmt::skip]
+        let cases = [
+            (0x15, 0x27, 0, 0x42, 0),
+            (0x58, 0x46, C, 0x05, N | V | C),
+            (0x99, 0x01, 0, 0x00, N | C),     // 0, but Z clear since the binary sum was 0x9A
+            (0x80, 0x80, 0, 0x60, Z | V | C), // and Z set since the binary sum was 0x00
+            (0x0F, 0x0F, 0, 0x14, 0),         // invalid BCD
+            (0xFF, 0xFF, 0, 0x54, N | C),
+        ];
+        for &(accum, operand, carry, result, flags) in cases.iter() {
+            assert_eq!(
+                decimal(Variant::Nmos6502, 0x69, accum, operand, carry),
+                (result, flags),
+                "{:02X} + {:02X} + {}",
+                accum,
+                operand,
+                carry
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_sbc() {
+        // Every flag is set as for binary SBC, only the result is corrected
+        #[rustfmt::skip]
+        let cases = [
+            (0x42, 0x15, C, 0x27, C),
+            (0x00, 0x01, C, 0x99, N),
+            (0x50, 0x49, 0, 0x00, C),
+            (0x0A, 0x05, C, 0x05, C), // invalid BCD
+        ];
+        for &(accum, operand, carry, result, flags) in cases.iter() {
+            assert_eq!(
+                decimal(Variant::Nmos6502, 0xE9, accum, operand, carry),
+                (result, flags),
+                "{:02X} - {:02X} - {}",
+                accum,
+                operand,
+                1 - carry
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_arr() {
+        // Each nibble of the rotated result is corrected separately. N and Z come from the
+        // result before the correction and C from the high nibble's.
+        assert_eq!(
+            decimal(Variant::Nmos6502, 0x6B, 0xFF, 0x55, 0),
+            (0x80, V | C)
+        );
+        assert_eq!(decimal(Variant::Nmos6502, 0x6B, 0xFF, 0x00, C), (0x80, N));
+        assert_eq!(decimal(Variant::Ricoh2A03, 0x6B, 0xFF, 0x55, 0), (0x2A, V));
+    }
+
+    #[test]
+    fn ricoh_ignores_decimal_flag() {
+        assert_eq!(decimal(Variant::Ricoh2A03, 0x69, 0x15, 0x27, 0), (0x3C, 0));
+        assert_eq!(decimal(Variant::Ricoh2A03, 0x69, 0x99, 0x01, 0), (0x9A, N));
+        assert_eq!(decimal(Variant::Ricoh2A03, 0xE9, 0x42, 0x15, C), (0x2D, C));
+
+        // D can still be set, and is pushed with the other flags
+        let mut cpu = setup(&[0xF8, 0x08]);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.bus.ram[0x01FD], 0x30 | I | D);
+    }
+
+    #[test]
+    fn lax_loads_a_and_x() {
+        let mut cpu = setup(&[0xA7, 0x10, 0xBF, 0xFF, 0x02]);
+        cpu.bus.ram[0x10] = 0x85;
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!((cpu.accum, cpu.x), (0x85, 0x85));
+        assert_eq!(flags(&cpu), I | N);
+
+        // Indexed reads take the page crossing penalty like LDA
+        cpu.y = 1;
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!((cpu.accum, cpu.x), (0, 0));
+        assert_eq!(flags(&cpu), I | Z);
+    }
+
+    #[test]
+    fn sax_stores_a_and_x_without_touching_flags() {
+        let mut cpu = setup(&[0x87, 0x10, 0x97, 0x10]);
+        cpu.accum = 0xF3;
+        cpu.x = 0x3C;
+        cpu.y = 0x05;
+        cpu.bus.ram[0x15] = 0xFF;
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.bus.ram[0x10], 0x30);
+        cpu.x = 0x0C;
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!(cpu.bus.ram[0x15], 0x00);
+        assert_eq!(flags(&cpu), I);
+    }
+
+    #[test]
+    fn read_modify_write_combinations() {
+        // Opcode, then memory, A and C before, then memory, A and flags after
+        #[rustfmt::skip]
+        let cases = [
+            (0x07, 0x81, 0x40, 0, 0x02, 0x42, C),     // SLO: ASL then ORA
+            (0x27, 0x81, 0x0F, C, 0x03, 0x03, C),     // RLA: ROL then AND
+            (0x47, 0x03, 0xFF, 0, 0x01, 0xFE, C | N), // SRE: LSR then EOR
+            (0x67, 0x02, 0x10, C, 0x81, 0x91, N),     // RRA: ROR then ADC
+            (0xC7, 0x41, 0x40, 0, 0x40, 0x40, Z | C), // DCP: DEC then CMP
+            (0xE7, 0x0F, 0x20, C, 0x10, 0x10, C),     // ISC: INC then SBC
+        ];
+        for &(opcode, memory, accum, carry, memory_out, accum_out, flags_out) in cases.iter() {
+            let mut cpu = setup(&[opcode, 0x10]);
+            cpu.bus.ram[0x10] = memory;
+            cpu.accum = accum;
+            cpu.status.set_c_to(carry != 0);
+            assert_eq!(step(&mut cpu), 5, "opcode {:02X}", opcode);
+            assert_eq!(
+                (cpu.bus.ram[0x10], cpu.accum, flags(&cpu)),
+                (memory_out, accum_out, I | flags_out),
+                "opcode {:02X}",
+                opcode
+            );
+        }
+
+        // Indexed forms always take the fix up cycle, like the official ones
+        let mut cpu = setup(&[0xDF, 0x00, 0x03]);
+        cpu.x = 0x10;
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!(cpu.bus.ram[0x0310], 0xFF);
+    }
+
+    #[test]
+    fn immediate_combinations() {
+        // Opcode and operand, then A, X and C before, then A, X and flags after
+        #[rustfmt::skip]
+        let cases = [
+            (0x0B, 0x80, 0xFF, 0x00, 0, 0x80, 0x00, N | C), // ANC copies N into C
+            (0x2B, 0x7F, 0xFF, 0x00, C, 0x7F, 0x00, 0),
+            (0x4B, 0x03, 0xFF, 0x00, 0, 0x01, 0x00, C),     // ALR: AND then LSR
+            (0x6B, 0xFF, 0xC0, 0x00, C, 0xE0, 0x00, N | C), // ARR: C from bit 6
+            (0x6B, 0xFF, 0x40, 0x00, 0, 0x20, 0x00, V),     // and V from bit 6 ^ bit 5
+            (0xCB, 0x02, 0x0F, 0xF3, 0, 0x0F, 0x01, C),     // AXS: X = (A & X) - M
+            (0xCB, 0x05, 0x0F, 0xF3, C, 0x0F, 0xFE, N),
+        ];
+        for &(opcode, operand, accum, x, carry, accum_out, x_out, flags_out) in cases.iter() {
+            let mut cpu = setup(&[opcode, operand]);
+            cpu.accum = accum;
+            cpu.x = x;
+            cpu.status.set_c_to(carry != 0);
+            assert_eq!(step(&mut cpu), 2, "opcode {:02X}", opcode);
+            assert_eq!(
+                (cpu.accum, cpu.x, flags(&cpu)),
+                (accum_out, x_out, I | flags_out),
+                "opcode {:02X} {:02X}",
+                opcode,
+                operand
+            );
+        }
+    }
+
+    #[test]
+    fn high_byte_stores() {
+        // SHX: X & (high byte of the base + 1)
+        let mut cpu = setup(&[0x9E, 0x00, 0x03]);
+        cpu.x = 0xFF;
+        cpu.y = 0x10;
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!(cpu.bus.ram[0x0310], 0x04);
+
+        // SHY crossing a page: the stored value replaces the high byte of the address
+        let mut cpu = setup(&[0x9C, 0xF0, 0x06]);
+        cpu.x = 0x20;
+        cpu.y = 0x05;
+        step(&mut cpu);
+        assert_eq!(cpu.bus.ram[0x0510], 0x05);
+        assert_eq!(cpu.bus.ram[0x0710], 0x00);
+
+        // SHA absolute,Y and (indirect),Y: A & X & (high byte + 1)
+        for program in [[0x9F, 0x00, 0x12], [0x93, 0x10, 0xEA]].iter() {
+            let mut cpu = setup(program);
+            cpu.accum = 0xF3;
+            cpu.x = 0x5F;
+            cpu.y = 0x05;
+            cpu.bus.ram[0x10..0x12].copy_from_slice(&[0x00, 0x12]);
+            assert_eq!(step(&mut cpu), if program[0] == 0x93 { 6 } else { 5 });
+            assert_eq!(cpu.bus.ram[0x1205], 0x13);
+        }
+
+        // TAS: SP = A & X, then stored like SHA
+        let mut cpu = setup(&[0x9B, 0x00, 0x12]);
+        cpu.accum = 0xF3;
+        cpu.x = 0x5F;
+        cpu.y = 0x05;
+        step(&mut cpu);
+        assert_eq!(cpu.sp, 0x53);
+        assert_eq!(cpu.bus.ram[0x1205], 0x13);
+
+        // LAS: A, X and SP = M & SP
+        let mut cpu = setup(&[0xBB, 0x00, 0x12]);
+        cpu.y = 0x05;
+        cpu.sp = 0x3C;
+        cpu.bus.ram[0x1205] = 0xF0;
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!((cpu.accum, cpu.x, cpu.sp), (0x30, 0x30, 0x30));
+        assert_eq!(flags(&cpu), I);
+    }
+
+    #[test]
+    fn unofficial_nops() {
+        #[rustfmt::skip]
+        let mut cpu = setup(&[
+            0x1A,             // implied
+            0x80, 0xFF,       // immediate
+            0x04, 0x10,       // zero page
+            0x14, 0x10,       // zero page,X
+            0x0C, 0x00, 0x03, // absolute
+            0x1C, 0xFF, 0x02, // absolute,X crossing a page
+        ]);
+        cpu.x = 1;
+        let registers = cpu.registers();
+        for &(size, cycles) in [(1, 2), (2, 2), (2, 3), (2, 4), (3, 4), (3, 5)].iter() {
+            let pc = cpu.pc;
+            assert_eq!(step(&mut cpu), cycles);
+            assert_eq!(cpu.pc, pc + size);
+        }
+        let after = cpu.registers();
+        assert_eq!(
+            Registers {
+                pc: 0,
+                cycle: 0,
+                ..after
+            },
+            Registers {
+                pc: 0,
+                cycle: 0,
+                ..registers
+            }
+        );
+    }
+
+    #[test]
+    fn jam_halts_until_reset() {
+        let mut cpu = setup(&[0x02, 0xEA]);
+        cpu.bus.ram[RESET_VECTOR as usize..RESET_VECTOR as usize + 2]
+            .copy_from_slice(&[0x00, 0x02]);
+        step(&mut cpu);
+        assert!(cpu.halted());
+        let pc = cpu.pc;
+        assert_eq!(step(&mut cpu), 1);
+        cpu.set_nmi(true);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert!(cpu.halted());
+        assert_eq!(cpu.pc, pc);
+
+        cpu.reset();
+        assert!(!cpu.halted());
+        assert_eq!(cpu.pc, START);
+        step(&mut cpu);
+        assert!(cpu.halted());
+    }
 }
@@ -0,0 +1,94 @@
+// Converts the PPU's framebuffer of palette indices to RGB, and writes it as a PPM or PNG file.
+//
+// PNGs are written without compression: the image data goes into zlib "stored" blocks, so only
+// the checksums need computing.
+use crate::checksum;
+use crate::ppu::{HEIGHT, WIDTH};
+use std::io;
+
+// RGB for each of the 64 colors the 2C02 can output
+#[rustfmt::skip]
+pub static PALETTE: [[u8; 3]; 64] = [
+    [0x62, 0x62, 0x62], [0x00, 0x1F, 0xB2], [0x24, 0x04, 0xC8], [0x52, 0x00, 0xB2],
+    [0x73, 0x00, 0x76], [0x80, 0x00, 0x24], [0x73, 0x0B, 0x00], [0x52, 0x28, 0x00],
+    [0x24, 0x44, 0x00], [0x00, 0x57, 0x00], [0x00, 0x5C, 0x00], [0x00, 0x53, 0x24],
+    [0x00, 0x3C, 0x76], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xAB, 0xAB, 0xAB], [0x0D, 0x57, 0xFF], [0x4B, 0x30, 0xFF], [0x8A, 0x13, 0xFF],
+    [0xBC, 0x08, 0xD6], [0xD2, 0x12, 0x69], [0xC7, 0x2E, 0x00], [0x9D, 0x54, 0x00],
+    [0x60, 0x7B, 0x00], [0x20, 0x98, 0x00], [0x00, 0xA3, 0x00], [0x00, 0x99, 0x42],
+    [0x00, 0x7D, 0xB4], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF], [0x53, 0xAE, 0xFF], [0x90, 0x85, 0xFF], [0xD3, 0x65, 0xFF],
+    [0xFF, 0x57, 0xFF], [0xFF, 0x5D, 0xCF], [0xFF, 0x77, 0x57], [0xFA, 0x9E, 0x00],
+    [0xBD, 0xC7, 0x00], [0x7A, 0xE7, 0x00], [0x43, 0xF6, 0x11], [0x26, 0xEF, 0x7E],
+    [0x2C, 0xD5, 0xF6], [0x4E, 0x4E, 0x4E], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFF, 0xFF, 0xFF], [0xB6, 0xE1, 0xFF], [0xCE, 0xD1, 0xFF], [0xE9, 0xC3, 0xFF],
+    [0xFF, 0xBC, 0xFF], [0xFF, 0xBD, 0xF4], [0xFF, 0xC6, 0xC3], [0xFF, 0xD5, 0x9A],
+    [0xE9, 0xE6, 0x81], [0xCE, 0xF4, 0x81], [0xB6, 0xFB, 0x9A], [0xA9, 0xFA, 0xC3],
+    [0xA9, 0xF0, 0xF4], [0xB8, 0xB8, 0xB8], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+];
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const PNG_COLOR_RGB: u8 = 2;
+// Largest block deflate can store uncompressed
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// WIDTH x HEIGHT pixels, 3 bytes each
+pub fn to_rgb(framebuffer: &[u8]) -> Vec<u8> {
+    framebuffer
+        .iter()
+        .flat_map(|&index| PALETTE[(index & 0x3F) as usize])
+        .collect()
+}
+
+// Binary PPM (P6)
+pub fn write_ppm<W: io::Write>(out: &mut W, framebuffer: &[u8]) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", WIDTH, HEIGHT)?;
+    out.write_all(&to_rgb(framebuffer))
+}
+
+pub fn write_png<W: io::Write>(out: &mut W, framebuffer: &[u8]) -> io::Result<()> {
+    out.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(HEIGHT as u32).to_be_bytes());
+    // 8 bits per channel, RGB, deflate, no filtering, no interlacing
+    header.extend_from_slice(&[8, PNG_COLOR_RGB, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // Each row starts with its filter type, which is always 0 (none)
+    let rgb = to_rgb(framebuffer);
+    let mut rows = Vec::with_capacity(HEIGHT * (WIDTH * 3 + 1));
+    for row in rgb.chunks(WIDTH * 3) {
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&rows))?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: io::Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = checksum::crc32_update(checksum::crc32(kind), data);
+    out.write_all(&crc.to_be_bytes())
+}
+
+// Wraps data in a zlib stream without compressing it
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate, 32KB window, no preset dictionary, and the check bits for that
+    let mut stream = vec![0x78, 0x01];
+    let blocks = data.chunks(MAX_STORED_BLOCK).collect::<Vec<_>>();
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i == blocks.len() - 1;
+        stream.push(last as u8); // BFINAL, and BTYPE 0 for a stored block
+        let len = block.len() as u16;
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&checksum::adler32(data).to_be_bytes());
+    stream
+}
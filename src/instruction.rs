@@ -1,11 +1,25 @@
+// Decoding information for one opcode. Every opcode has an entry in INSTRUCTIONS, which the CPU,
+// the disassembler and the trace logger all use.
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct Instruction {
     pub op: OpCode,
     pub addr_mode: AddrMode,
-    pub cycles: u8, // cycles this instruction will take to execute
+    pub cycles: u8,         // base number of cycles this instruction takes to execute
     pub size: u8, // size in bytes, so CPU knows how much to incrememnt PC and whether it needs to fetch more data from memory
+    pub page_penalty: bool, // takes an extra cycle when indexing crosses a page (or for branches, when taken, plus one more when crossing)
+    pub official: bool, // documented by MOS, as opposed to the side effects of the decoding logic
+    pub access: Access, // what the instruction does with the memory its operand addresses
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+    Other, // no operand in memory: implied and accumulator modes, branches, jumps and stack instructions
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum AddrMode {
     Implicit,        // No further action necessary
     Accumulator,     // Operate directony on the accumulator
@@ -22,7 +36,7 @@ pub enum AddrMode {
     IndirectIndexed, // Instruction contains zero page address of least significant byte of a 16 bit address. This is added to the Y register to get the target address
 }
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum OpCode {
     ADC, // Add With Carry
     AND, // Logical AND
@@ -80,925 +94,451 @@ pub enum OpCode {
     TXA, // Transfer X to Accumulator
     TXS, // Transfer X to Stack Pointer
     TYA, // Transfer Y to Accumulator
+
+    // Unofficial opcodes. Most combine two official instructions that share decoding logic.
+    ALR, // AND then LSR A
+    ANC, // AND, then copy N into C
+    ARR, // AND then ROR A, with C and V set from bits 6 and 5 of the result
+    AXS, // X = (A & X) - M, setting C like CMP
+    DCP, // DEC then CMP
+    ISC, // INC then SBC
+    JAM, // Halts the CPU until reset
+    LAS, // A, X and SP = M & SP
+    LAX, // LDA and LDX
+    LXA, // A and X = (A | magic) & M, unstable
+    RLA, // ROL then AND
+    RRA, // ROR then ADC
+    SAX, // Store A & X
+    SHA, // Store A & X & (high byte of address + 1), unstable
+    SHX, // Store X & (high byte of address + 1), unstable
+    SHY, // Store Y & (high byte of address + 1), unstable
+    SLO, // ASL then ORA
+    SRE, // LSR then EOR
+    TAS, // SP = A & X, then SHA with SP, unstable
+    XAA, // A = (A | magic) & X & M, unstable
+}
+
+impl AddrMode {
+    // Size in bytes of an instruction using this mode, including the opcode
+    pub const fn size(self) -> u8 {
+        match self {
+            AddrMode::Implicit | AddrMode::Accumulator => 1,
+            AddrMode::Absolute | AddrMode::AbsoluteX | AddrMode::AbsoluteY | AddrMode::Indirect => {
+                3
+            }
+            _ => 2,
+        }
+    }
 }
 
 impl Instruction {
-    pub fn new(opcode: u8) -> Instruction {
-        // FAT ASS MATCH INCOMING
-        match opcode {
-            0x00 => Instruction {
-                op: OpCode::BRK,
-                addr_mode: AddrMode::Implicit,
-                cycles: 7,
-                size: 1,
-            },
-            0x01 => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0x05 => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x06 => Instruction {
-                op: OpCode::ASL,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 5,
-                size: 2,
-            },
-            0x08 => Instruction {
-                op: OpCode::PHP,
-                addr_mode: AddrMode::Implicit,
-                cycles: 3,
-                size: 1,
-            },
-            0x09 => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0x0a => Instruction {
-                op: OpCode::ASL,
-                addr_mode: AddrMode::Accumulator,
-                cycles: 2,
-                size: 1,
-            },
-            0x0d => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x0e => Instruction {
-                op: OpCode::ASL,
-                addr_mode: AddrMode::Absolute,
-                cycles: 6,
-                size: 3,
-            },
-            0x10 => Instruction {
-                op: OpCode::BPL,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0x11 => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0x15 => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0x16 => Instruction {
-                op: OpCode::ASL,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 6,
-                size: 2,
-            },
-            0x18 => Instruction {
-                op: OpCode::CLC,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x19 => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 2,
-            },
-            0x1d => Instruction {
-                op: OpCode::ORA,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0x1e => Instruction {
-                op: OpCode::ASL,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 7,
-                size: 3,
-            },
-            0x20 => Instruction {
-                op: OpCode::JSR,
-                addr_mode: AddrMode::Relative,
-                cycles: 6,
-                size: 3,
-            },
-            0x21 => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0x24 => Instruction {
-                op: OpCode::BIT,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x25 => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x26 => Instruction {
-                op: OpCode::ROL,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 5,
-                size: 2,
-            },
-            0x28 => Instruction {
-                op: OpCode::PLP,
-                addr_mode: AddrMode::Implicit,
-                cycles: 4,
-                size: 1,
-            },
-            0x29 => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0x2a => Instruction {
-                op: OpCode::ROL,
-                addr_mode: AddrMode::Accumulator,
-                cycles: 2,
-                size: 1,
-            },
-            0x2c => Instruction {
-                op: OpCode::BIT,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x2d => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x2e => Instruction {
-                op: OpCode::ROL,
-                addr_mode: AddrMode::Absolute,
-                cycles: 6,
-                size: 3,
-            },
-            0x30 => Instruction {
-                op: OpCode::BMI,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0x31 => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0x35 => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0x36 => Instruction {
-                op: OpCode::ROL,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 6,
-                size: 2,
-            },
-            0x38 => Instruction {
-                op: OpCode::SEC,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x39 => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0x3d => Instruction {
-                op: OpCode::AND,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0x3e => Instruction {
-                op: OpCode::ROL,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 7,
-                size: 3,
-            },
-            0x40 => Instruction {
-                op: OpCode::RTI,
-                addr_mode: AddrMode::Implicit,
-                cycles: 6,
-                size: 1,
-            },
-            0x41 => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0x45 => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x46 => Instruction {
-                op: OpCode::LSR,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 5,
-                size: 2,
-            },
-            0x48 => Instruction {
-                op: OpCode::PHA,
-                addr_mode: AddrMode::Implicit,
-                cycles: 3,
-                size: 1,
-            },
-            0x49 => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0x4a => Instruction {
-                op: OpCode::LSR,
-                addr_mode: AddrMode::Accumulator,
-                cycles: 2,
-                size: 1,
-            },
-            0x4c => Instruction {
-                op: OpCode::JMP,
-                addr_mode: AddrMode::Absolute,
-                cycles: 3,
-                size: 3,
-            },
-            0x4d => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x4e => Instruction {
-                op: OpCode::LSR,
-                addr_mode: AddrMode::Absolute,
-                cycles: 6,
-                size: 3,
-            },
-            0x50 => Instruction {
-                op: OpCode::BVC,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0x51 => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0x55 => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0x56 => Instruction {
-                op: OpCode::LSR,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 6,
-                size: 2,
-            },
-            0x58 => Instruction {
-                op: OpCode::CLI,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x59 => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0x5d => Instruction {
-                op: OpCode::EOR,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0x5e => Instruction {
-                op: OpCode::LSR,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 7,
-                size: 3,
-            },
-            0x60 => Instruction {
-                op: OpCode::RTS,
-                addr_mode: AddrMode::Implicit,
-                cycles: 6,
-                size: 1,
-            },
-            0x61 => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0x65 => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x66 => Instruction {
-                op: OpCode::ROR,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 5,
-                size: 2,
-            },
-            0x68 => Instruction {
-                op: OpCode::PLA,
-                addr_mode: AddrMode::Implicit,
-                cycles: 4,
-                size: 1,
-            },
-            0x69 => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0x6a => Instruction {
-                op: OpCode::ROR,
-                addr_mode: AddrMode::Accumulator,
-                cycles: 2,
-                size: 1,
-            },
-            0x6c => Instruction {
-                op: OpCode::JMP,
-                addr_mode: AddrMode::Indirect,
-                cycles: 5,
-                size: 3,
-            },
-            0x6d => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x6e => Instruction {
-                op: OpCode::ROR,
-                addr_mode: AddrMode::Absolute,
-                cycles: 6,
-                size: 3,
-            },
-            0x70 => Instruction {
-                op: OpCode::BVS,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0x71 => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0x75 => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0x76 => Instruction {
-                op: OpCode::ROR,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 6,
-                size: 2,
-            },
-            0x78 => Instruction {
-                op: OpCode::SEI,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x79 => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0x7d => Instruction {
-                op: OpCode::ADC,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0x7e => Instruction {
-                op: OpCode::ROR,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 7,
-                size: 3,
-            },
-            0x81 => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0x84 => Instruction {
-                op: OpCode::STY,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x85 => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x86 => Instruction {
-                op: OpCode::STX,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0x88 => Instruction {
-                op: OpCode::DEY,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x8a => Instruction {
-                op: OpCode::TXA,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x8c => Instruction {
-                op: OpCode::STY,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x8d => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x8e => Instruction {
-                op: OpCode::STX,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0x90 => Instruction {
-                op: OpCode::BCC,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0x91 => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 6,
-                size: 2,
-            },
-            0x94 => Instruction {
-                op: OpCode::STY,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0x95 => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0x96 => Instruction {
-                op: OpCode::STX,
-                addr_mode: AddrMode::ZeroPageY,
-                cycles: 4,
-                size: 2,
-            },
-            0x98 => Instruction {
-                op: OpCode::TYA,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x99 => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 5,
-                size: 3,
-            },
-            0x9a => Instruction {
-                op: OpCode::TXS,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0x9d => Instruction {
-                op: OpCode::STA,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 5,
-                size: 3,
-            },
-            0xa0 => Instruction {
-                op: OpCode::LDY,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xa1 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0xa2 => Instruction {
-                op: OpCode::LDX,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xa4 => Instruction {
-                op: OpCode::LDY,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xa5 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xa6 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xa8 => Instruction {
-                op: OpCode::TAY,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xa9 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xaa => Instruction {
-                op: OpCode::TAX,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xac => Instruction {
-                op: OpCode::LDY,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xad => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xae => Instruction {
-                op: OpCode::LDX,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xb0 => Instruction {
-                op: OpCode::BCS,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0xb1 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0xb4 => Instruction {
-                op: OpCode::LDY,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0xb5 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0xb6 => Instruction {
-                op: OpCode::LDX,
-                addr_mode: AddrMode::ZeroPageY,
-                cycles: 4,
-                size: 2,
-            },
-            0xb8 => Instruction {
-                op: OpCode::CLV,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xb9 => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0xba => Instruction {
-                op: OpCode::TSX,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xbc => Instruction {
-                op: OpCode::LDY,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0xbd => Instruction {
-                op: OpCode::LDA,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0xbe => Instruction {
-                op: OpCode::LDX,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0xc0 => Instruction {
-                op: OpCode::CPY,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xc1 => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0xc4 => Instruction {
-                op: OpCode::CPY,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xc5 => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xc6 => Instruction {
-                op: OpCode::DEC,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 5,
-                size: 2,
-            },
-            0xc8 => Instruction {
-                op: OpCode::INY,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xc9 => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xca => Instruction {
-                op: OpCode::DEX,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xcc => Instruction {
-                op: OpCode::CPY,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xcd => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xce => Instruction {
-                op: OpCode::DEC,
-                addr_mode: AddrMode::Absolute,
-                cycles: 6,
-                size: 3,
-            },
-            0xd0 => Instruction {
-                op: OpCode::BNE,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0xd1 => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0xd5 => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0xd6 => Instruction {
-                op: OpCode::DEC,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 6,
-                size: 2,
-            },
-            0xd8 => Instruction {
-                op: OpCode::CLD,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xd9 => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0xdd => Instruction {
-                op: OpCode::CMP,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0xde => Instruction {
-                op: OpCode::DEC,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 7,
-                size: 3,
-            },
-            0xe0 => Instruction {
-                op: OpCode::CPX,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xe1 => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::IndexedIndirect,
-                cycles: 6,
-                size: 2,
-            },
-            0xe4 => Instruction {
-                op: OpCode::CPX,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xe5 => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 3,
-                size: 2,
-            },
-            0xe6 => Instruction {
-                op: OpCode::INC,
-                addr_mode: AddrMode::ZeroPage,
-                cycles: 5,
-                size: 2,
-            },
-            0xe8 => Instruction {
-                op: OpCode::INX,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xe9 => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::Immediate,
-                cycles: 2,
-                size: 2,
-            },
-            0xea => Instruction {
-                op: OpCode::NOP,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xec => Instruction {
-                op: OpCode::CPX,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xed => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::Absolute,
-                cycles: 4,
-                size: 3,
-            },
-            0xee => Instruction {
-                op: OpCode::INC,
-                addr_mode: AddrMode::Absolute,
-                cycles: 6,
-                size: 3,
-            },
-            0xf0 => Instruction {
-                op: OpCode::BEQ,
-                addr_mode: AddrMode::Relative,
-                cycles: 2,
-                size: 2,
-            },
-            0xf1 => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::IndirectIndexed,
-                cycles: 5,
-                size: 2,
-            },
-            0xf5 => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 4,
-                size: 2,
-            },
-            0xf6 => Instruction {
-                op: OpCode::INC,
-                addr_mode: AddrMode::ZeroPageX,
-                cycles: 6,
-                size: 2,
-            },
-            0xf8 => Instruction {
-                op: OpCode::SED,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
-            0xf9 => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::AbsoluteY,
-                cycles: 4,
-                size: 3,
-            },
-            0xfd => Instruction {
-                op: OpCode::SBC,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 4,
-                size: 3,
-            },
-            0xfe => Instruction {
-                op: OpCode::INC,
-                addr_mode: AddrMode::AbsoluteX,
-                cycles: 7,
-                size: 3,
-            },
-            // TODO maybe change this?
-            _ => Instruction {
-                op: OpCode::NOP,
-                addr_mode: AddrMode::Implicit,
-                cycles: 2,
-                size: 1,
-            },
+    pub fn decode(opcode: u8) -> &'static Instruction {
+        &INSTRUCTIONS[opcode as usize]
+    }
+
+    // Opcode for a mnemonic and addressing mode, for assembling. Official opcodes are preferred
+    // where an unofficial one does the same thing (like SBC immediate at 0xEB), and otherwise the
+    // lowest one is used (for the many unofficial NOPs).
+    pub fn find(op: OpCode, addr_mode: AddrMode) -> Option<u8> {
+        let matching = |official: bool| {
+            INSTRUCTIONS.iter().position(|inst| {
+                inst.op == op && inst.addr_mode == addr_mode && inst.official == official
+            })
+        };
+        matching(true)
+            .or_else(|| matching(false))
+            .map(|opcode| opcode as u8)
+    }
+}
+
+const fn official(op: OpCode, addr_mode: AddrMode, cycles: u8, access: Access) -> Instruction {
+    entry(op, addr_mode, cycles, access, true)
+}
+const fn unofficial(op: OpCode, addr_mode: AddrMode, cycles: u8, access: Access) -> Instruction {
+    entry(op, addr_mode, cycles, access, false)
+}
+const fn entry(
+    op: OpCode,
+    addr_mode: AddrMode,
+    cycles: u8,
+    access: Access,
+    official: bool,
+) -> Instruction {
+    // Only reads can skip the fix up cycle when indexing doesn't cross a page
+    let indexed_read = matches!(access, Access::Read)
+        && matches!(
+            addr_mode,
+            AddrMode::AbsoluteX | AddrMode::AbsoluteY | AddrMode::IndirectIndexed
+        );
+    Instruction {
+        op,
+        addr_mode,
+        cycles,
+        size: addr_mode.size(),
+        page_penalty: indexed_read || matches!(addr_mode, AddrMode::Relative),
+        official,
+        access,
+    }
+}
+
+use Access::*;
+use AddrMode::*;
+use OpCode::*;
+
+#[rustfmt::skip]
+pub static INSTRUCTIONS: [Instruction; 256] = [
+    /* 0x00 */ official(BRK, Implicit, 7, Other),
+    /* 0x01 */ official(ORA, IndexedIndirect, 6, Read),
+    /* 0x02 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x03 */ unofficial(SLO, IndexedIndirect, 8, ReadModifyWrite),
+    /* 0x04 */ unofficial(NOP, ZeroPage, 3, Read),
+    /* 0x05 */ official(ORA, ZeroPage, 3, Read),
+    /* 0x06 */ official(ASL, ZeroPage, 5, ReadModifyWrite),
+    /* 0x07 */ unofficial(SLO, ZeroPage, 5, ReadModifyWrite),
+    /* 0x08 */ official(PHP, Implicit, 3, Other),
+    /* 0x09 */ official(ORA, Immediate, 2, Read),
+    /* 0x0a */ official(ASL, Accumulator, 2, Other),
+    /* 0x0b */ unofficial(ANC, Immediate, 2, Read),
+    /* 0x0c */ unofficial(NOP, Absolute, 4, Read),
+    /* 0x0d */ official(ORA, Absolute, 4, Read),
+    /* 0x0e */ official(ASL, Absolute, 6, ReadModifyWrite),
+    /* 0x0f */ unofficial(SLO, Absolute, 6, ReadModifyWrite),
+    /* 0x10 */ official(BPL, Relative, 2, Other),
+    /* 0x11 */ official(ORA, IndirectIndexed, 5, Read),
+    /* 0x12 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x13 */ unofficial(SLO, IndirectIndexed, 8, ReadModifyWrite),
+    /* 0x14 */ unofficial(NOP, ZeroPageX, 4, Read),
+    /* 0x15 */ official(ORA, ZeroPageX, 4, Read),
+    /* 0x16 */ official(ASL, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x17 */ unofficial(SLO, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x18 */ official(CLC, Implicit, 2, Other),
+    /* 0x19 */ official(ORA, AbsoluteY, 4, Read),
+    /* 0x1a */ unofficial(NOP, Implicit, 2, Other),
+    /* 0x1b */ unofficial(SLO, AbsoluteY, 7, ReadModifyWrite),
+    /* 0x1c */ unofficial(NOP, AbsoluteX, 4, Read),
+    /* 0x1d */ official(ORA, AbsoluteX, 4, Read),
+    /* 0x1e */ official(ASL, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x1f */ unofficial(SLO, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x20 */ official(JSR, Absolute, 6, Other),
+    /* 0x21 */ official(AND, IndexedIndirect, 6, Read),
+    /* 0x22 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x23 */ unofficial(RLA, IndexedIndirect, 8, ReadModifyWrite),
+    /* 0x24 */ official(BIT, ZeroPage, 3, Read),
+    /* 0x25 */ official(AND, ZeroPage, 3, Read),
+    /* 0x26 */ official(ROL, ZeroPage, 5, ReadModifyWrite),
+    /* 0x27 */ unofficial(RLA, ZeroPage, 5, ReadModifyWrite),
+    /* 0x28 */ official(PLP, Implicit, 4, Other),
+    /* 0x29 */ official(AND, Immediate, 2, Read),
+    /* 0x2a */ official(ROL, Accumulator, 2, Other),
+    /* 0x2b */ unofficial(ANC, Immediate, 2, Read),
+    /* 0x2c */ official(BIT, Absolute, 4, Read),
+    /* 0x2d */ official(AND, Absolute, 4, Read),
+    /* 0x2e */ official(ROL, Absolute, 6, ReadModifyWrite),
+    /* 0x2f */ unofficial(RLA, Absolute, 6, ReadModifyWrite),
+    /* 0x30 */ official(BMI, Relative, 2, Other),
+    /* 0x31 */ official(AND, IndirectIndexed, 5, Read),
+    /* 0x32 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x33 */ unofficial(RLA, IndirectIndexed, 8, ReadModifyWrite),
+    /* 0x34 */ unofficial(NOP, ZeroPageX, 4, Read),
+    /* 0x35 */ official(AND, ZeroPageX, 4, Read),
+    /* 0x36 */ official(ROL, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x37 */ unofficial(RLA, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x38 */ official(SEC, Implicit, 2, Other),
+    /* 0x39 */ official(AND, AbsoluteY, 4, Read),
+    /* 0x3a */ unofficial(NOP, Implicit, 2, Other),
+    /* 0x3b */ unofficial(RLA, AbsoluteY, 7, ReadModifyWrite),
+    /* 0x3c */ unofficial(NOP, AbsoluteX, 4, Read),
+    /* 0x3d */ official(AND, AbsoluteX, 4, Read),
+    /* 0x3e */ official(ROL, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x3f */ unofficial(RLA, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x40 */ official(RTI, Implicit, 6, Other),
+    /* 0x41 */ official(EOR, IndexedIndirect, 6, Read),
+    /* 0x42 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x43 */ unofficial(SRE, IndexedIndirect, 8, ReadModifyWrite),
+    /* 0x44 */ unofficial(NOP, ZeroPage, 3, Read),
+    /* 0x45 */ official(EOR, ZeroPage, 3, Read),
+    /* 0x46 */ official(LSR, ZeroPage, 5, ReadModifyWrite),
+    /* 0x47 */ unofficial(SRE, ZeroPage, 5, ReadModifyWrite),
+    /* 0x48 */ official(PHA, Implicit, 3, Other),
+    /* 0x49 */ official(EOR, Immediate, 2, Read),
+    /* 0x4a */ official(LSR, Accumulator, 2, Other),
+    /* 0x4b */ unofficial(ALR, Immediate, 2, Read),
+    /* 0x4c */ official(JMP, Absolute, 3, Other),
+    /* 0x4d */ official(EOR, Absolute, 4, Read),
+    /* 0x4e */ official(LSR, Absolute, 6, ReadModifyWrite),
+    /* 0x4f */ unofficial(SRE, Absolute, 6, ReadModifyWrite),
+    /* 0x50 */ official(BVC, Relative, 2, Other),
+    /* 0x51 */ official(EOR, IndirectIndexed, 5, Read),
+    /* 0x52 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x53 */ unofficial(SRE, IndirectIndexed, 8, ReadModifyWrite),
+    /* 0x54 */ unofficial(NOP, ZeroPageX, 4, Read),
+    /* 0x55 */ official(EOR, ZeroPageX, 4, Read),
+    /* 0x56 */ official(LSR, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x57 */ unofficial(SRE, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x58 */ official(CLI, Implicit, 2, Other),
+    /* 0x59 */ official(EOR, AbsoluteY, 4, Read),
+    /* 0x5a */ unofficial(NOP, Implicit, 2, Other),
+    /* 0x5b */ unofficial(SRE, AbsoluteY, 7, ReadModifyWrite),
+    /* 0x5c */ unofficial(NOP, AbsoluteX, 4, Read),
+    /* 0x5d */ official(EOR, AbsoluteX, 4, Read),
+    /* 0x5e */ official(LSR, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x5f */ unofficial(SRE, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x60 */ official(RTS, Implicit, 6, Other),
+    /* 0x61 */ official(ADC, IndexedIndirect, 6, Read),
+    /* 0x62 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x63 */ unofficial(RRA, IndexedIndirect, 8, ReadModifyWrite),
+    /* 0x64 */ unofficial(NOP, ZeroPage, 3, Read),
+    /* 0x65 */ official(ADC, ZeroPage, 3, Read),
+    /* 0x66 */ official(ROR, ZeroPage, 5, ReadModifyWrite),
+    /* 0x67 */ unofficial(RRA, ZeroPage, 5, ReadModifyWrite),
+    /* 0x68 */ official(PLA, Implicit, 4, Other),
+    /* 0x69 */ official(ADC, Immediate, 2, Read),
+    /* 0x6a */ official(ROR, Accumulator, 2, Other),
+    /* 0x6b */ unofficial(ARR, Immediate, 2, Read),
+    /* 0x6c */ official(JMP, Indirect, 5, Other),
+    /* 0x6d */ official(ADC, Absolute, 4, Read),
+    /* 0x6e */ official(ROR, Absolute, 6, ReadModifyWrite),
+    /* 0x6f */ unofficial(RRA, Absolute, 6, ReadModifyWrite),
+    /* 0x70 */ official(BVS, Relative, 2, Other),
+    /* 0x71 */ official(ADC, IndirectIndexed, 5, Read),
+    /* 0x72 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x73 */ unofficial(RRA, IndirectIndexed, 8, ReadModifyWrite),
+    /* 0x74 */ unofficial(NOP, ZeroPageX, 4, Read),
+    /* 0x75 */ official(ADC, ZeroPageX, 4, Read),
+    /* 0x76 */ official(ROR, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x77 */ unofficial(RRA, ZeroPageX, 6, ReadModifyWrite),
+    /* 0x78 */ official(SEI, Implicit, 2, Other),
+    /* 0x79 */ official(ADC, AbsoluteY, 4, Read),
+    /* 0x7a */ unofficial(NOP, Implicit, 2, Other),
+    /* 0x7b */ unofficial(RRA, AbsoluteY, 7, ReadModifyWrite),
+    /* 0x7c */ unofficial(NOP, AbsoluteX, 4, Read),
+    /* 0x7d */ official(ADC, AbsoluteX, 4, Read),
+    /* 0x7e */ official(ROR, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x7f */ unofficial(RRA, AbsoluteX, 7, ReadModifyWrite),
+    /* 0x80 */ unofficial(NOP, Immediate, 2, Read),
+    /* 0x81 */ official(STA, IndexedIndirect, 6, Write),
+    /* 0x82 */ unofficial(NOP, Immediate, 2, Read),
+    /* 0x83 */ unofficial(SAX, IndexedIndirect, 6, Write),
+    /* 0x84 */ official(STY, ZeroPage, 3, Write),
+    /* 0x85 */ official(STA, ZeroPage, 3, Write),
+    /* 0x86 */ official(STX, ZeroPage, 3, Write),
+    /* 0x87 */ unofficial(SAX, ZeroPage, 3, Write),
+    /* 0x88 */ official(DEY, Implicit, 2, Other),
+    /* 0x89 */ unofficial(NOP, Immediate, 2, Read),
+    /* 0x8a */ official(TXA, Implicit, 2, Other),
+    /* 0x8b */ unofficial(XAA, Immediate, 2, Read),
+    /* 0x8c */ official(STY, Absolute, 4, Write),
+    /* 0x8d */ official(STA, Absolute, 4, Write),
+    /* 0x8e */ official(STX, Absolute, 4, Write),
+    /* 0x8f */ unofficial(SAX, Absolute, 4, Write),
+    /* 0x90 */ official(BCC, Relative, 2, Other),
+    /* 0x91 */ official(STA, IndirectIndexed, 6, Write),
+    /* 0x92 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0x93 */ unofficial(SHA, IndirectIndexed, 6, Write),
+    /* 0x94 */ official(STY, ZeroPageX, 4, Write),
+    /* 0x95 */ official(STA, ZeroPageX, 4, Write),
+    /* 0x96 */ official(STX, ZeroPageY, 4, Write),
+    /* 0x97 */ unofficial(SAX, ZeroPageY, 4, Write),
+    /* 0x98 */ official(TYA, Implicit, 2, Other),
+    /* 0x99 */ official(STA, AbsoluteY, 5, Write),
+    /* 0x9a */ official(TXS, Implicit, 2, Other),
+    /* 0x9b */ unofficial(TAS, AbsoluteY, 5, Write),
+    /* 0x9c */ unofficial(SHY, AbsoluteX, 5, Write),
+    /* 0x9d */ official(STA, AbsoluteX, 5, Write),
+    /* 0x9e */ unofficial(SHX, AbsoluteY, 5, Write),
+    /* 0x9f */ unofficial(SHA, AbsoluteY, 5, Write),
+    /* 0xa0 */ official(LDY, Immediate, 2, Read),
+    /* 0xa1 */ official(LDA, IndexedIndirect, 6, Read),
+    /* 0xa2 */ official(LDX, Immediate, 2, Read),
+    /* 0xa3 */ unofficial(LAX, IndexedIndirect, 6, Read),
+    /* 0xa4 */ official(LDY, ZeroPage, 3, Read),
+    /* 0xa5 */ official(LDA, ZeroPage, 3, Read),
+    /* 0xa6 */ official(LDX, ZeroPage, 3, Read),
+    /* 0xa7 */ unofficial(LAX, ZeroPage, 3, Read),
+    /* 0xa8 */ official(TAY, Implicit, 2, Other),
+    /* 0xa9 */ official(LDA, Immediate, 2, Read),
+    /* 0xaa */ official(TAX, Implicit, 2, Other),
+    /* 0xab */ unofficial(LXA, Immediate, 2, Read),
+    /* 0xac */ official(LDY, Absolute, 4, Read),
+    /* 0xad */ official(LDA, Absolute, 4, Read),
+    /* 0xae */ official(LDX, Absolute, 4, Read),
+    /* 0xaf */ unofficial(LAX, Absolute, 4, Read),
+    /* 0xb0 */ official(BCS, Relative, 2, Other),
+    /* 0xb1 */ official(LDA, IndirectIndexed, 5, Read),
+    /* 0xb2 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0xb3 */ unofficial(LAX, IndirectIndexed, 5, Read),
+    /* 0xb4 */ official(LDY, ZeroPageX, 4, Read),
+    /* 0xb5 */ official(LDA, ZeroPageX, 4, Read),
+    /* 0xb6 */ official(LDX, ZeroPageY, 4, Read),
+    /* 0xb7 */ unofficial(LAX, ZeroPageY, 4, Read),
+    /* 0xb8 */ official(CLV, Implicit, 2, Other),
+    /* 0xb9 */ official(LDA, AbsoluteY, 4, Read),
+    /* 0xba */ official(TSX, Implicit, 2, Other),
+    /* 0xbb */ unofficial(LAS, AbsoluteY, 4, Read),
+    /* 0xbc */ official(LDY, AbsoluteX, 4, Read),
+    /* 0xbd */ official(LDA, AbsoluteX, 4, Read),
+    /* 0xbe */ official(LDX, AbsoluteY, 4, Read),
+    /* 0xbf */ unofficial(LAX, AbsoluteY, 4, Read),
+    /* 0xc0 */ official(CPY, Immediate, 2, Read),
+    /* 0xc1 */ official(CMP, IndexedIndirect, 6, Read),
+    /* 0xc2 */ unofficial(NOP, Immediate, 2, Read),
+    /* 0xc3 */ unofficial(DCP, IndexedIndirect, 8, ReadModifyWrite),
+    /* 0xc4 */ official(CPY, ZeroPage, 3, Read),
+    /* 0xc5 */ official(CMP, ZeroPage, 3, Read),
+    /* 0xc6 */ official(DEC, ZeroPage, 5, ReadModifyWrite),
+    /* 0xc7 */ unofficial(DCP, ZeroPage, 5, ReadModifyWrite),
+    /* 0xc8 */ official(INY, Implicit, 2, Other),
+    /* 0xc9 */ official(CMP, Immediate, 2, Read),
+    /* 0xca */ official(DEX, Implicit, 2, Other),
+    /* 0xcb */ unofficial(AXS, Immediate, 2, Read),
+    /* 0xcc */ official(CPY, Absolute, 4, Read),
+    /* 0xcd */ official(CMP, Absolute, 4, Read),
+    /* 0xce */ official(DEC, Absolute, 6, ReadModifyWrite),
+    /* 0xcf */ unofficial(DCP, Absolute, 6, ReadModifyWrite),
+    /* 0xd0 */ official(BNE, Relative, 2, Other),
+    /* 0xd1 */ official(CMP, IndirectIndexed, 5, Read),
+    /* 0xd2 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0xd3 */ unofficial(DCP, IndirectIndexed, 8, ReadModifyWrite),
+    /* 0xd4 */ unofficial(NOP, ZeroPageX, 4, Read),
+    /* 0xd5 */ official(CMP, ZeroPageX, 4, Read),
+    /* 0xd6 */ official(DEC, ZeroPageX, 6, ReadModifyWrite),
+    /* 0xd7 */ unofficial(DCP, ZeroPageX, 6, ReadModifyWrite),
+    /* 0xd8 */ official(CLD, Implicit, 2, Other),
+    /* 0xd9 */ official(CMP, AbsoluteY, 4, Read),
+    /* 0xda */ unofficial(NOP, Implicit, 2, Other),
+    /* 0xdb */ unofficial(DCP, AbsoluteY, 7, ReadModifyWrite),
+    /* 0xdc */ unofficial(NOP, AbsoluteX, 4, Read),
+    /* 0xdd */ official(CMP, AbsoluteX, 4, Read),
+    /* 0xde */ official(DEC, AbsoluteX, 7, ReadModifyWrite),
+    /* 0xdf */ unofficial(DCP, AbsoluteX, 7, ReadModifyWrite),
+    /* 0xe0 */ official(CPX, Immediate, 2, Read),
+    /* 0xe1 */ official(SBC, IndexedIndirect, 6, Read),
+    /* 0xe2 */ unofficial(NOP, Immediate, 2, Read),
+    /* 0xe3 */ unofficial(ISC, IndexedIndirect, 8, ReadModifyWrite),
+    /* 0xe4 */ official(CPX, ZeroPage, 3, Read),
+    /* 0xe5 */ official(SBC, ZeroPage, 3, Read),
+    /* 0xe6 */ official(INC, ZeroPage, 5, ReadModifyWrite),
+    /* 0xe7 */ unofficial(ISC, ZeroPage, 5, ReadModifyWrite),
+    /* 0xe8 */ official(INX, Implicit, 2, Other),
+    /* 0xe9 */ official(SBC, Immediate, 2, Read),
+    /* 0xea */ official(NOP, Implicit, 2, Other),
+    /* 0xeb */ unofficial(SBC, Immediate, 2, Read),
+    /* 0xec */ official(CPX, Absolute, 4, Read),
+    /* 0xed */ official(SBC, Absolute, 4, Read),
+    /* 0xee */ official(INC, Absolute, 6, ReadModifyWrite),
+    /* 0xef */ unofficial(ISC, Absolute, 6, ReadModifyWrite),
+    /* 0xf0 */ official(BEQ, Relative, 2, Other),
+    /* 0xf1 */ official(SBC, IndirectIndexed, 5, Read),
+    /* 0xf2 */ unofficial(JAM, Implicit, 2, Other),
+    /* 0xf3 */ unofficial(ISC, IndirectIndexed, 8, ReadModifyWrite),
+    /* 0xf4 */ unofficial(NOP, ZeroPageX, 4, Read),
+    /* 0xf5 */ official(SBC, ZeroPageX, 4, Read),
+    /* 0xf6 */ official(INC, ZeroPageX, 6, ReadModifyWrite),
+    /* 0xf7 */ unofficial(ISC, ZeroPageX, 6, ReadModifyWrite),
+    /* 0xf8 */ official(SED, Implicit, 2, Other),
+    /* 0xf9 */ official(SBC, AbsoluteY, 4, Read),
+    /* 0xfa */ unofficial(NOP, Implicit, 2, Other),
+    /* 0xfb */ unofficial(ISC, AbsoluteY, 7, ReadModifyWrite),
+    /* 0xfc */ unofficial(NOP, AbsoluteX, 4, Read),
+    /* 0xfd */ official(SBC, AbsoluteX, 4, Read),
+    /* 0xfe */ official(INC, AbsoluteX, 7, ReadModifyWrite),
+    /* 0xff */ unofficial(ISC, AbsoluteX, 7, ReadModifyWrite),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every official opcode with its mnemonic, addressing mode and base cycle count, from the
+    // MOS datasheet
+    #[rustfmt::skip]
+    const OFFICIAL: [(u8, OpCode, AddrMode, u8); 151] = [
+        (0x69, ADC, Immediate, 2), (0x65, ADC, ZeroPage, 3), (0x75, ADC, ZeroPageX, 4),
+        (0x6D, ADC, Absolute, 4), (0x7D, ADC, AbsoluteX, 4), (0x79, ADC, AbsoluteY, 4),
+        (0x61, ADC, IndexedIndirect, 6), (0x71, ADC, IndirectIndexed, 5),
+        (0x29, AND, Immediate, 2), (0x25, AND, ZeroPage, 3), (0x35, AND, ZeroPageX, 4),
+        (0x2D, AND, Absolute, 4), (0x3D, AND, AbsoluteX, 4), (0x39, AND, AbsoluteY, 4),
+        (0x21, AND, IndexedIndirect, 6), (0x31, AND, IndirectIndexed, 5),
+        (0x0A, ASL, Accumulator, 2), (0x06, ASL, ZeroPage, 5), (0x16, ASL, ZeroPageX, 6),
+        (0x0E, ASL, Absolute, 6), (0x1E, ASL, AbsoluteX, 7),
+        (0x90, BCC, Relative, 2), (0xB0, BCS, Relative, 2), (0xF0, BEQ, Relative, 2),
+        (0x30, BMI, Relative, 2), (0xD0, BNE, Relative, 2), (0x10, BPL, Relative, 2),
+        (0x50, BVC, Relative, 2), (0x70, BVS, Relative, 2),
+        (0x24, BIT, ZeroPage, 3), (0x2C, BIT, Absolute, 4),
+        (0x00, BRK, Implicit, 7),
+        (0x18, CLC, Implicit, 2), (0xD8, CLD, Implicit, 2), (0x58, CLI, Implicit, 2),
+        (0xB8, CLV, Implicit, 2),
+        (0xC9, CMP, Immediate, 2), (0xC5, CMP, ZeroPage, 3), (0xD5, CMP, ZeroPageX, 4),
+        (0xCD, CMP, Absolute, 4), (0xDD, CMP, AbsoluteX, 4), (0xD9, CMP, AbsoluteY, 4),
+        (0xC1, CMP, IndexedIndirect, 6), (0xD1, CMP, IndirectIndexed, 5),
+        (0xE0, CPX, Immediate, 2), (0xE4, CPX, ZeroPage, 3), (0xEC, CPX, Absolute, 4),
+        (0xC0, CPY, Immediate, 2), (0xC4, CPY, ZeroPage, 3), (0xCC, CPY, Absolute, 4),
+        (0xC6, DEC, ZeroPage, 5), (0xD6, DEC, ZeroPageX, 6), (0xCE, DEC, Absolute, 6),
+        (0xDE, DEC, AbsoluteX, 7),
+        (0xCA, DEX, Implicit, 2), (0x88, DEY, Implicit, 2),
+        (0x49, EOR, Immediate, 2), (0x45, EOR, ZeroPage, 3), (0x55, EOR, ZeroPageX, 4),
+        (0x4D, EOR, Absolute, 4), (0x5D, EOR, AbsoluteX, 4), (0x59, EOR, AbsoluteY, 4),
+        (0x41, EOR, IndexedIndirect, 6), (0x51, EOR, IndirectIndexed, 5),
+        (0xE6, INC, ZeroPage, 5), (0xF6, INC, ZeroPageX, 6), (0xEE, INC, Absolute, 6),
+        (0xFE, INC, AbsoluteX, 7),
+        (0xE8, INX, Implicit, 2), (0xC8, INY, Implicit, 2),
+        (0x4C, JMP, Absolute, 3), (0x6C, JMP, Indirect, 5),
+        (0x20, JSR, Absolute, 6),
+        (0xA9, LDA, Immediate, 2), (0xA5, LDA, ZeroPage, 3), (0xB5, LDA, ZeroPageX, 4),
+        (0xAD, LDA, Absolute, 4), (0xBD, LDA, AbsoluteX, 4), (0xB9, LDA, AbsoluteY, 4),
+        (0xA1, LDA, IndexedIndirect, 6), (0xB1, LDA, IndirectIndexed, 5),
+        (0xA2, LDX, Immediate, 2), (0xA6, LDX, ZeroPage, 3), (0xB6, LDX, ZeroPageY, 4),
+        (0xAE, LDX, Absolute, 4), (0xBE, LDX, AbsoluteY, 4),
+        (0xA0, LDY, Immediate, 2), (0xA4, LDY, ZeroPage, 3), (0xB4, LDY, ZeroPageX, 4),
+        (0xAC, LDY, Absolute, 4), (0xBC, LDY, AbsoluteX, 4),
+        (0x4A, LSR, Accumulator, 2), (0x46, LSR, ZeroPage, 5), (0x56, LSR, ZeroPageX, 6),
+        (0x4E, LSR, Absolute, 6), (0x5E, LSR, AbsoluteX, 7),
+        (0xEA, NOP, Implicit, 2),
+        (0x09, ORA, Immediate, 2), (0x05, ORA, ZeroPage, 3), (0x15, ORA, ZeroPageX, 4),
+        (0x0D, ORA, Absolute, 4), (0x1D, ORA, AbsoluteX, 4), (0x19, ORA, AbsoluteY, 4),
+        (0x01, ORA, IndexedIndirect, 6), (0x11, ORA, IndirectIndexed, 5),
+        (0x48, PHA, Implicit, 3), (0x08, PHP, Implicit, 3), (0x68, PLA, Implicit, 4),
+        (0x28, PLP, Implicit, 4),
+        (0x2A, ROL, Accumulator, 2), (0x26, ROL, ZeroPage, 5), (0x36, ROL, ZeroPageX, 6),
+        (0x2E, ROL, Absolute, 6), (0x3E, ROL, AbsoluteX, 7),
+        (0x6A, ROR, Accumulator, 2), (0x66, ROR, ZeroPage, 5), (0x76, ROR, ZeroPageX, 6),
+        (0x6E, ROR, Absolute, 6), (0x7E, ROR, AbsoluteX, 7),
+        (0x40, RTI, Implicit, 6), (0x60, RTS, Implicit, 6),
+        (0xE9, SBC, Immediate, 2), (0xE5, SBC, ZeroPage, 3), (0xF5, SBC, ZeroPageX, 4),
+        (0xED, SBC, Absolute, 4), (0xFD, SBC, AbsoluteX, 4), (0xF9, SBC, AbsoluteY, 4),
+        (0xE1, SBC, IndexedIndirect, 6), (0xF1, SBC, IndirectIndexed, 5),
+        (0x38, SEC, Implicit, 2), (0xF8, SED, Implicit, 2), (0x78, SEI, Implicit, 2),
+        (0x85, STA, ZeroPage, 3), (0x95, STA, ZeroPageX, 4), (0x8D, STA, Absolute, 4),
+        (0x9D, STA, AbsoluteX, 5), (0x99, STA, AbsoluteY, 5),
+        (0x81, STA, IndexedIndirect, 6), (0x91, STA, IndirectIndexed, 6),
+        (0x86, STX, ZeroPage, 3), (0x96, STX, ZeroPageY, 4), (0x8E, STX, Absolute, 4),
+        (0x84, STY, ZeroPage, 3), (0x94, STY, ZeroPageX, 4), (0x8C, STY, Absolute, 4),
+        (0xAA, TAX, Implicit, 2), (0xA8, TAY, Implicit, 2), (0xBA, TSX, Implicit, 2),
+        (0x8A, TXA, Implicit, 2), (0x9A, TXS, Implicit, 2), (0x98, TYA, Implicit, 2),
+    ];
+
+    #[test]
+    fn official_opcodes_match_datasheet() {
+        for &(opcode, op, addr_mode, cycles) in OFFICIAL.iter() {
+            let inst = Instruction::decode(opcode);
+            assert_eq!(
+                (inst.op, inst.addr_mode, inst.cycles, inst.official),
+                (op, addr_mode, cycles, true),
+                "opcode {:02X}",
+                opcode
+            );
+            assert_eq!(inst.size, addr_mode.size(), "opcode {:02X}", opcode);
         }
+        // And nothing else is marked official
+        let official = INSTRUCTIONS.iter().filter(|inst| inst.official).count();
+        assert_eq!(official, OFFICIAL.len());
+    }
+
+    #[test]
+    fn find_prefers_official_opcodes() {
+        assert_eq!(Instruction::find(LDX, ZeroPage), Some(0xA6));
+        assert_eq!(Instruction::find(SBC, Immediate), Some(0xE9));
+        assert_eq!(Instruction::find(NOP, ZeroPage), Some(0x04));
+        assert_eq!(Instruction::find(STA, Immediate), None);
     }
 }
@@ -0,0 +1,20 @@
+// Hardware names like CPU and opcode mnemonics like ADC read better in all caps
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod apu;
+pub mod audio;
+pub mod bus;
+pub mod cartridge;
+pub mod checksum;
+pub mod controller;
+pub mod cpu;
+pub mod image;
+pub mod instruction;
+pub mod mapper;
+pub mod mem;
+pub mod nes;
+pub mod ppu;
+pub mod rewind;
+pub mod state;
+pub mod test_rom;
+pub mod trace;
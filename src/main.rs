@@ -1,10 +1,347 @@
-mod cpu;
-mod instruction;
-mod mem;
-mod nes;
-mod ppu;
+// Headless command line runner, for smoke testing ROMs without a display. Runs a ROM for a number
+// of frames or until a stop condition is met, then writes out the picture, sound and RAM.
+//
+// Input scripts have one line per change in the buttons held, giving the frame the change
+// happens on and the buttons for each controller, joined with + (or - for none):
+//
+// # frame  port 1     port 2
+// 0        -
+// 60       START
+// 62       -
+// 100      A+RIGHT    B
+//
+// Exit codes:
+// 0 - Ran every frame, or the stop condition was met
+// 1 - The ROM or input script couldn't be loaded, or an output couldn't be written
+// 2 - Invalid arguments
+// 3 - The stop condition wasn't met within the frame limit
+// 4 - The CPU jammed (unless that was the stop condition)
+use rust_nes::audio;
+use rust_nes::cartridge::Cartridge;
+use rust_nes::controller::{self, Port};
+use rust_nes::image;
+use rust_nes::mem;
+use rust_nes::nes::NES;
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "usage: rust-nes <rom.nes> [options]
+
+options:
+  --frames N           run for at most N frames (default 60)
+  --until-pc ADDR      stop when the CPU is about to run the instruction at ADDR
+  --until-mem ADDR=N   stop when the byte at ADDR equals N
+  --until-jam          stop when the CPU jams
+  --input FILE         hold buttons as given by an input script
+  --png FILE           write the final picture as a PNG
+  --ppm FILE           write the final picture as a PPM
+  --wav FILE           write the audio as a WAV
+  --sample-rate N      audio sample rate (default 44100)
+  --ram FILE           write the 2KB of internal RAM at the end
+
+Numbers are decimal, or hex with a 0x or $ prefix.";
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_TIMED_OUT: i32 = 3;
+const EXIT_JAMMED: i32 = 4;
+
+const DEFAULT_FRAMES: u64 = 60;
+const AUDIO_BUFFER_LEN: usize = 4096;
+
+#[derive(Default)]
+struct Options {
+    rom: String,
+    frames: u64,
+    until_pc: Option<u16>,
+    until_mem: Option<(u16, u8)>,
+    until_jam: bool,
+    input: Option<String>,
+    png: Option<String>,
+    ppm: Option<String>,
+    wav: Option<String>,
+    sample_rate: u32,
+    ram: Option<String>,
+}
+
+impl Options {
+    fn has_condition(&self) -> bool {
+        self.until_pc.is_some() || self.until_mem.is_some() || self.until_jam
+    }
+}
+
+// How the run ended
+enum Outcome {
+    Finished,
+    ConditionMet(String),
+    TimedOut,
+    Jammed(u16),
+}
+
+// Buttons held from a frame onwards
+struct InputChange {
+    frame: u64,
+    buttons: [u8; 2],
+}
 
 fn main() {
-    // TODO stuff
-    println!("Hello, world!");
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    match run(&options) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        frames: DEFAULT_FRAMES,
+        sample_rate: audio::DEFAULT_SAMPLE_RATE,
+        ..Options::default()
+    };
+    let mut rom = None;
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            if rom.is_some() {
+                return Err(format!("unexpected argument: {}", arg));
+            }
+            rom = Some(arg);
+            continue;
+        }
+        if arg == "--until-jam" {
+            options.until_jam = true;
+            continue;
+        }
+        if arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--frames" => options.frames = parse_number(&value)?,
+            "--until-pc" => options.until_pc = Some(parse_number(&value)?),
+            "--until-mem" => {
+                let (addr, data) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected ADDR=N, found {}", value))?;
+                options.until_mem = Some((parse_number(addr)?, parse_number(data)?));
+            }
+            "--input" => options.input = Some(value),
+            "--png" => options.png = Some(value),
+            "--ppm" => options.ppm = Some(value),
+            "--wav" => options.wav = Some(value),
+            "--sample-rate" => options.sample_rate = parse_number(&value)?,
+            "--ram" => options.ram = Some(value),
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    options.rom = rom.ok_or("no ROM given")?;
+    Ok(options)
+}
+
+fn parse_number<T: TryFrom<u64>>(text: &str) -> Result<T, String> {
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'));
+    let value = match hex {
+        Some(digits) => u64::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    value
+        .ok()
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| format!("invalid number: {}", text))
+}
+
+fn parse_buttons(text: &str) -> Result<u8, String> {
+    if text == "-" {
+        return Ok(0);
+    }
+    text.split('+').try_fold(0, |buttons, name| {
+        let button = match name.to_ascii_uppercase().as_str() {
+            "A" => controller::BUTTON_A,
+            "B" => controller::BUTTON_B,
+            "SELECT" => controller::BUTTON_SELECT,
+            "START" => controller::BUTTON_START,
+            "UP" => controller::BUTTON_UP,
+            "DOWN" => controller::BUTTON_DOWN,
+            "LEFT" => controller::BUTTON_LEFT,
+            "RIGHT" => controller::BUTTON_RIGHT,
+            _ => return Err(format!("unknown button: {}", name)),
+        };
+        Ok(buttons | button)
+    })
+}
+
+fn parse_input_script(text: &str) -> Result<Vec<InputChange>, String> {
+    let mut changes = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |err: String| format!("line {}: {}", number + 1, err);
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(error(
+                "expected a frame and buttons for 1 or 2 ports".to_string(),
+            ));
+        }
+        let frame = parse_number(fields[0]).map_err(error)?;
+        let mut buttons = [0; 2];
+        for (port, field) in fields[1..].iter().enumerate() {
+            buttons[port] = parse_buttons(field).map_err(error)?;
+        }
+        changes.push(InputChange { frame, buttons });
+    }
+    changes.sort_by_key(|change| change.frame);
+    Ok(changes)
+}
+
+// Returns the exit code
+fn run(options: &Options) -> Result<i32, String> {
+    let input = match &options.input {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+            parse_input_script(&text).map_err(|err| format!("{}: {}", path, err))?
+        }
+        None => Vec::new(),
+    };
+    let mut nes = Cartridge::from_file(&options.rom)
+        .and_then(NES::new)
+        .map_err(|err| format!("{}: {}", options.rom, err))?;
+    nes.set_sample_rate(options.sample_rate);
+
+    let mut samples = Vec::new();
+    let mut buffer = [0; AUDIO_BUFFER_LEN];
+    let mut changes = input.iter().peekable();
+    let mut frames = 0;
+    let outcome = 'run: loop {
+        if frames == options.frames {
+            if options.has_condition() {
+                break Outcome::TimedOut;
+            }
+            break Outcome::Finished;
+        }
+        while let Some(change) = changes.next_if(|change| change.frame <= frames) {
+            nes.set_buttons(Port::One, change.buttons[0]);
+            nes.set_buttons(Port::Two, change.buttons[1]);
+        }
+
+        let frame = nes.frame();
+        while nes.frame() == frame {
+            if let Some(outcome) = check_conditions(options, &mut nes) {
+                break 'run outcome;
+            }
+            nes.step_instruction();
+        }
+        frames += 1;
+
+        while nes.samples_available() > 0 {
+            let count = nes.read_samples_i16(&mut buffer);
+            if options.wav.is_some() {
+                samples.extend_from_slice(&buffer[..count]);
+            }
+        }
+    };
+
+    // Outputs are written however the run ended, to help with working out what went wrong
+    write_outputs(options, &mut nes, &samples)?;
+
+    let pc = nes.cpu().registers().pc;
+    let (message, code) = match outcome {
+        Outcome::Finished => (format!("ran {} frames", frames), 0),
+        Outcome::ConditionMet(condition) => (format!("{} after {} frames", condition, frames), 0),
+        Outcome::TimedOut => (
+            format!(
+                "stop condition not met after {} frames, PC={:04X}",
+                frames, pc
+            ),
+            EXIT_TIMED_OUT,
+        ),
+        Outcome::Jammed(addr) => (
+            format!("CPU jammed at {:04X} after {} frames", addr, frames),
+            EXIT_JAMMED,
+        ),
+    };
+    if code == 0 {
+        println!("{}", message);
+    } else {
+        eprintln!("{}", message);
+    }
+    Ok(code)
+}
+
+// Checked before each instruction
+fn check_conditions(options: &Options, nes: &mut NES) -> Option<Outcome> {
+    let pc = nes.cpu().registers().pc;
+    if nes.cpu().halted() {
+        // PC is left just past the JAM opcode
+        let addr = pc.wrapping_sub(1);
+        return Some(if options.until_jam {
+            Outcome::ConditionMet(format!("CPU jammed at {:04X}", addr))
+        } else {
+            Outcome::Jammed(addr)
+        });
+    }
+    if options.until_pc == Some(pc) {
+        return Some(Outcome::ConditionMet(format!("reached PC={:04X}", pc)));
+    }
+    if let Some((addr, data)) = options.until_mem {
+        if nes.peek(addr) == data {
+            return Some(Outcome::ConditionMet(format!(
+                "byte at {:04X} is {:02X}",
+                addr, data
+            )));
+        }
+    }
+    None
+}
+
+fn write_outputs(options: &Options, nes: &mut NES, samples: &[i16]) -> Result<(), String> {
+    if let Some(path) = &options.png {
+        write_file(path, |out| image::write_png(out, nes.framebuffer()))?;
+    }
+    if let Some(path) = &options.ppm {
+        write_file(path, |out| image::write_ppm(out, nes.framebuffer()))?;
+    }
+    if let Some(path) = &options.wav {
+        write_file(path, |out| {
+            audio::write_wav(out, options.sample_rate, samples)
+        })?;
+    }
+    if let Some(path) = &options.ram {
+        let ram = (0..mem::RAM_SIZE as u16)
+            .map(|addr| nes.peek(addr))
+            .collect::<Vec<_>>();
+        write_file(path, |out| out.write_all(&ram))?;
+    }
+    Ok(())
+}
+
+fn write_file<F>(path: &str, write: F) -> Result<(), String>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    File::create(path)
+        .map(BufWriter::new)
+        .and_then(|mut out| {
+            write(&mut out)?;
+            out.flush()
+        })
+        .map_err(|err| format!("{}: {}", path, err))
 }
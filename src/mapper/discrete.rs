@@ -0,0 +1,289 @@
+// Discrete logic boards. These use off-the-shelf latches instead of a custom mapper chip, so
+// a write anywhere in 0x8000-0xFFFF simply latches the data bus into a bank register.
+//
+// Mapper 2: UxROM - 16KB switchable PRG bank at 0x8000, last bank fixed at 0xC000, CHR RAM
+// Mapper 3: CNROM - 32KB PRG ROM, 8KB switchable CHR bank
+// Mapper 7: AxROM - 32KB switchable PRG bank (bits 0-2), single-screen nametable select (bit 4)
+// Mapper 11: Color Dreams - 32KB PRG bank (bits 0-1), 8KB CHR bank (bits 4-7)
+// Mapper 34: BNROM - 32KB switchable PRG bank, CHR RAM
+//            NINA-001 - 32KB PRG bank at 0x7FFD, 4KB CHR banks at 0x7FFE and 0x7FFF
+// Mapper 66: GxROM - 32KB PRG bank (bits 4-5), 8KB CHR bank (bits 0-1)
+// Mapper 71: Codemasters BF909x - like UxROM with the bank register at 0xC000-0xFFFF, and on
+//            BF9097 (Fire Hawk) a single-screen nametable select at 0x9000-0x9FFF
+//
+// Since the ROM is still enabled during writes to 0x8000-0xFFFF, boards without extra logic
+// to prevent it have bus conflicts: the ROM and CPU drive the bus at the same time and the
+// latched value is the AND of both.
+use super::{chr_memory, load_memory, prg_ram, save_memory, Mapper};
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Copy, Clone, PartialEq)]
+enum Board {
+    UxROM,
+    CNROM,
+    AxROM,
+    ColorDreams,
+    BNROM,
+    NINA001,
+    GxROM,
+    BF909x,
+}
+
+pub struct Discrete {
+    board: Board,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+
+    prg_bank: u8,
+    chr_banks: [u8; 2], // only NINA-001 uses the second register, for its upper 4KB bank
+}
+
+impl Discrete {
+    // Returns None for mapper numbers that aren't discrete logic boards
+    pub fn new(cartridge: Cartridge) -> Option<Discrete> {
+        let header = &cartridge.header;
+        let board = match header.mapper {
+            2 => Board::UxROM,
+            3 => Board::CNROM,
+            7 => Board::AxROM,
+            11 => Board::ColorDreams,
+            // NES 2.0 submapper 1 is NINA-001 and 2 is BNROM. Otherwise go by the CHR size:
+            // NINA-001 boards have more than 8KB of CHR ROM to switch, BNROM has CHR RAM, and a
+            // single 8KB bank of CHR ROM is only ever seen on BNROM dumps.
+            34 => match header.submapper {
+                1 => Board::NINA001,
+                2 => Board::BNROM,
+                _ if cartridge.chr_rom.len() > 0x2000 => Board::NINA001,
+                _ => Board::BNROM,
+            },
+            66 => Board::GxROM,
+            71 => Board::BF909x,
+            _ => return None,
+        };
+        // For mappers 2, 3 and 7, NES 2.0 submapper 1 means no bus conflicts and 2 means bus
+        // conflicts. Otherwise assume whatever the common boards for the mapper do.
+        let bus_conflicts = match (board, header.submapper) {
+            (Board::UxROM, 1) | (Board::CNROM, 1) | (Board::AxROM, 1) => false,
+            (Board::UxROM, 2) | (Board::CNROM, 2) | (Board::AxROM, 2) => true,
+            (Board::AxROM, _) | (Board::NINA001, _) | (Board::BF909x, _) => false,
+            _ => true,
+        };
+        let mirroring = match board {
+            Board::AxROM => Mirroring::SingleScreenLower,
+            _ => header.mirroring,
+        };
+        let prg_ram = prg_ram(&cartridge);
+        let (chr, chr_writable) = chr_memory(&cartridge);
+        Some(Discrete {
+            board,
+            prg_rom: cartridge.prg_rom,
+            prg_ram,
+            chr,
+            chr_writable,
+            mirroring,
+            bus_conflicts,
+            prg_bank: 0,
+            chr_banks: [0, 1],
+        })
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        let offset = match self.board {
+            // 16KB switchable bank followed by the last 16KB bank
+            Board::UxROM | Board::BF909x => {
+                let bank = if addr < 0xC000 {
+                    self.prg_bank as usize
+                } else {
+                    (self.prg_rom.len() / 0x4000).saturating_sub(1)
+                };
+                bank * 0x4000 + (addr & 0x3FFF)
+            }
+            // 32KB switchable bank
+            _ => self.prg_bank as usize * 0x8000 + (addr & 0x7FFF),
+        };
+        offset % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize;
+        let offset = match self.board {
+            Board::NINA001 => self.chr_banks[addr >> 12] as usize * 0x1000 + (addr & 0x0FFF),
+            _ => self.chr_banks[0] as usize * 0x2000 + addr,
+        };
+        offset % self.chr.len()
+    }
+
+    fn single_screen(data: u8) -> Mirroring {
+        if data & 0x10 != 0 {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        }
+    }
+}
+
+impl Mapper for Discrete {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_offset(addr)]),
+            _ => None,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x8000 {
+            if let 0x6000..=0x7FFF = addr {
+                if !self.prg_ram.is_empty() {
+                    let len = self.prg_ram.len();
+                    self.prg_ram[(addr as usize - 0x6000) % len] = data;
+                }
+            }
+            // NINA-001 registers sit on top of the end of PRG RAM
+            if self.board == Board::NINA001 {
+                match addr {
+                    0x7FFD => self.prg_bank = data & 0x01,
+                    0x7FFE => self.chr_banks[0] = data & 0x0F,
+                    0x7FFF => self.chr_banks[1] = data & 0x0F,
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        let data = if self.bus_conflicts {
+            data & self.prg_rom[self.prg_rom_offset(addr)]
+        } else {
+            data
+        };
+        match self.board {
+            Board::UxROM | Board::BNROM => self.prg_bank = data,
+            Board::CNROM => self.chr_banks[0] = data,
+            Board::AxROM => {
+                self.prg_bank = data & 0x07;
+                self.mirroring = Discrete::single_screen(data);
+            }
+            Board::ColorDreams => {
+                self.prg_bank = data & 0x03;
+                self.chr_banks[0] = data >> 4;
+            }
+            Board::GxROM => {
+                self.prg_bank = (data >> 4) & 0x03;
+                self.chr_banks[0] = data & 0x03;
+            }
+            Board::BF909x => match addr {
+                0x9000..=0x9FFF => self.mirroring = Discrete::single_screen(data),
+                0xC000..=0xFFFF => self.prg_bank = data & 0x0F,
+                _ => {}
+            },
+            Board::NINA001 => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_writable {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_memory(state, &self.prg_ram, &self.chr, self.chr_writable);
+        state.mirroring(self.mirroring);
+        state.u8(self.prg_bank);
+        state.bytes(&self.chr_banks);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_memory(state, &mut self.prg_ram, &mut self.chr, self.chr_writable)?;
+        self.mirroring = state.mirroring()?;
+        self.prg_bank = state.u8()?;
+        state.bytes_into(&mut self.chr_banks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_cartridge;
+    use super::*;
+
+    fn discrete(mapper: u16, submapper: u8, prg_rom_size: usize, chr_rom_size: usize) -> Discrete {
+        Discrete::new(test_cartridge(
+            mapper,
+            submapper,
+            prg_rom_size,
+            chr_rom_size,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn uxrom_bus_conflicts() {
+        // The bytes at 0xC000 and 0xE000 are 14 and 15, so the written bank gets ANDed with them
+        let mut uxrom = discrete(2, 0, 0x20000, 0);
+        assert_eq!(uxrom.cpu_read(0xE000), Some(15));
+        uxrom.cpu_write(0xE000, 7);
+        assert_eq!(uxrom.cpu_read(0x8000), Some(14));
+        uxrom.cpu_write(0xC000, 7);
+        assert_eq!(uxrom.cpu_read(0x8000), Some(12));
+
+        // Submapper 1 has no bus conflicts
+        let mut uxrom = discrete(2, 1, 0x20000, 0);
+        uxrom.cpu_write(0xC000, 7);
+        assert_eq!(uxrom.cpu_read(0x8000), Some(14));
+    }
+
+    #[test]
+    fn cnrom_bus_conflicts() {
+        // The bytes at 0xC000 and 0xE000 are 2 and 3
+        let mut cnrom = discrete(3, 0, 0x8000, 0x8000);
+        cnrom.cpu_write(0xE000, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 24);
+        cnrom.cpu_write(0xC000, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 16);
+
+        let mut cnrom = discrete(3, 1, 0x8000, 0x8000);
+        cnrom.cpu_write(0xC000, 3);
+        assert_eq!(cnrom.ppu_read(0x1C00), 31);
+    }
+
+    #[test]
+    fn prg_rom_smaller_than_a_bank() {
+        let mut uxrom = discrete(2, 0, 0x2000, 0);
+        assert_eq!(uxrom.cpu_read(0xFFFC), Some(0));
+        let mut bf909x = discrete(71, 0, 0x2000, 0);
+        assert_eq!(bf909x.cpu_read(0xFFFC), Some(0));
+    }
+
+    #[test]
+    fn mapper_34_boards() {
+        // 8KB of CHR ROM is BNROM, switching 32KB PRG banks from 0x8000-0xFFFF
+        let mut bnrom = discrete(34, 0, 0x10000, 0x2000);
+        bnrom.cpu_write(0x7FFD, 1);
+        assert_eq!(bnrom.cpu_read(0x8000), Some(0));
+        bnrom.cpu_write(0xA000, 1);
+        assert_eq!(bnrom.cpu_read(0x8000), Some(4));
+
+        // More is NINA-001, with its registers at 0x7FFD-0x7FFF
+        let mut nina = discrete(34, 0, 0x10000, 0x10000);
+        nina.cpu_write(0x7FFD, 1);
+        nina.cpu_write(0x7FFE, 2);
+        nina.cpu_write(0x7FFF, 5);
+        assert_eq!(nina.cpu_read(0x8000), Some(4));
+        assert_eq!(nina.ppu_read(0x0000), 8);
+        assert_eq!(nina.ppu_read(0x1000), 20);
+    }
+}
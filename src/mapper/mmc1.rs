@@ -0,0 +1,343 @@
+// Mapper 1: MMC1 (SxROM boards).
+// CPU 0x6000-0x7FFF - 8KB PRG RAM bank (banked on SOROM/SXROM)
+// CPU 0x8000-0xBFFF - 16KB PRG ROM bank, switchable or fixed to the first bank
+// CPU 0xC000-0xFFFF - 16KB PRG ROM bank, switchable or fixed to the last bank
+// PPU 0x0000-0x0FFF - 4KB switchable CHR bank
+// PPU 0x1000-0x1FFF - 4KB switchable CHR bank
+//
+// Registers are loaded serially through a 5-bit shift register. Each write to 0x8000-0xFFFF
+// shifts bit 0 of the data into the shift register, and on the fifth write the value is copied
+// into the internal register selected by bits 13-14 of the address:
+// 0x8000-0x9FFF - Control: mirroring (bits 0-1), PRG mode (bits 2-3), CHR mode (bit 4)
+// 0xA000-0xBFFF - CHR bank 0
+// 0xC000-0xDFFF - CHR bank 1
+// 0xE000-0xFFFF - PRG bank (bits 0-3), PRG RAM disable (bit 4)
+// Writing a value with bit 7 set resets the shift register and sets PRG mode 3.
+//
+// Boards with 8KB of CHR RAM reuse the upper CHR bank bits for PRG:
+// SOROM - bit 3 selects the 8KB PRG RAM bank (16KB of PRG RAM)
+// SUROM - bit 4 selects the 256KB PRG ROM half (512KB of PRG ROM)
+// SXROM - bits 2-3 select the PRG RAM bank (32KB of PRG RAM), bit 4 the PRG ROM half
+use super::{chr_memory, load_memory, prg_ram, save_memory, Mapper};
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const SHIFT_RESET: u8 = 0x10; // marker bit that reaches bit 0 after 4 shifts
+
+pub struct MMC1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+
+    shift: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+
+    // The MMC1 ignores a write on the cycle right after another write, which happens when
+    // read-modify-write instructions write twice to the same address
+    cycle: u64,
+    last_write_cycle: Option<u64>,
+    // PPU A12, for picking which CHR bank register supplies the SxROM PRG bits in 4KB mode
+    chr_a12: bool,
+}
+
+impl MMC1 {
+    pub fn new(cartridge: Cartridge) -> MMC1 {
+        let prg_ram = prg_ram(&cartridge);
+        let (chr, chr_writable) = chr_memory(&cartridge);
+        MMC1 {
+            prg_rom: cartridge.prg_rom,
+            prg_ram,
+            chr,
+            chr_writable,
+            shift: SHIFT_RESET,
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_write_cycle: None,
+            chr_a12: false,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = data,
+            0xA000..=0xBFFF => self.chr_bank_0 = data,
+            0xC000..=0xDFFF => self.chr_bank_1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+
+    // The CHR bank register currently driving the SxROM PRG lines
+    fn outer_bank_register(&self) -> u8 {
+        if self.control & 0x10 != 0 && self.chr_a12 {
+            self.chr_bank_1
+        } else {
+            self.chr_bank_0
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let last = 0x0F;
+        let bank = match (self.control >> 2) & 0x03 {
+            // 32KB mode, low bit of the bank number is ignored
+            0 | 1 => (bank & !1) | ((addr as usize >> 14) & 1),
+            // First bank fixed at 0x8000, 0xC000 switchable
+            2 => {
+                if addr < 0xC000 {
+                    0
+                } else {
+                    bank
+                }
+            }
+            // 0x8000 switchable, last bank fixed at 0xC000
+            _ => {
+                if addr < 0xC000 {
+                    bank
+                } else {
+                    last
+                }
+            }
+        };
+        // 512KB boards select the 256KB half with bit 4 of the CHR bank register
+        let outer = if self.prg_rom.len() > 0x40000 {
+            self.outer_bank_register() as usize & 0x10
+        } else {
+            0
+        };
+        ((outer | bank) * 0x4000 + (addr as usize & 0x3FFF)) % self.prg_rom.len()
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> usize {
+        let bank = match self.prg_ram.len() {
+            0x4000 => (self.outer_bank_register() as usize >> 3) & 0x01,
+            0x8000 => (self.outer_bank_register() as usize >> 2) & 0x03,
+            _ => 0,
+        };
+        (bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_ram.len()
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        !self.prg_ram.is_empty() && self.prg_bank & 0x10 == 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = if self.control & 0x10 != 0 {
+            // Two separate 4KB banks
+            if addr < 0x1000 {
+                self.chr_bank_0 as usize
+            } else {
+                self.chr_bank_1 as usize
+            }
+        } else {
+            // One 8KB bank, low bit of the bank number is ignored
+            (self.chr_bank_0 as usize & !1) | ((addr as usize >> 12) & 1)
+        };
+        (bank * 0x1000 + (addr as usize & 0x0FFF)) % self.chr.len()
+    }
+}
+
+impl Mapper for MMC1 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some(self.prg_ram[self.prg_ram_offset(addr)])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_offset(addr)]),
+            _ => None,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(addr);
+                self.prg_ram[offset] = data;
+            }
+            0x8000..=0xFFFF => {
+                let consecutive = self.last_write_cycle == Some(self.cycle.wrapping_sub(1));
+                self.last_write_cycle = Some(self.cycle);
+                if consecutive {
+                    return;
+                }
+                if data & 0x80 != 0 {
+                    self.shift = SHIFT_RESET;
+                    self.control |= 0x0C;
+                    return;
+                }
+                let complete = self.shift & 1 != 0;
+                self.shift = (self.shift >> 1) | ((data & 1) << 4);
+                if complete {
+                    self.write_register(addr, self.shift);
+                    self.shift = SHIFT_RESET;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_writable {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_memory(state, &self.prg_ram, &self.chr, self.chr_writable);
+        state.u8(self.shift);
+        state.u8(self.control);
+        state.u8(self.chr_bank_0);
+        state.u8(self.chr_bank_1);
+        state.u8(self.prg_bank);
+        state.u64(self.cycle);
+        state.bool(self.last_write_cycle.is_some());
+        state.u64(self.last_write_cycle.unwrap_or(0));
+        state.bool(self.chr_a12);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_memory(state, &mut self.prg_ram, &mut self.chr, self.chr_writable)?;
+        self.shift = state.u8()?;
+        self.control = state.u8()?;
+        self.chr_bank_0 = state.u8()?;
+        self.chr_bank_1 = state.u8()?;
+        self.prg_bank = state.u8()?;
+        self.cycle = state.u64()?;
+        let written = state.bool()?;
+        let last_write_cycle = state.u64()?;
+        self.last_write_cycle = if written {
+            Some(last_write_cycle)
+        } else {
+            None
+        };
+        self.chr_a12 = state.bool()?;
+        Ok(())
+    }
+
+    fn cpu_clock(&mut self) {
+        self.cycle = self.cycle.wrapping_add(1);
+    }
+    fn ppu_address(&mut self, addr: u16) {
+        self.chr_a12 = addr & 0x1000 != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_cartridge;
+    use super::*;
+
+    // Loads a register through the shift register, with the writes a few cycles apart like
+    // separate STA instructions
+    fn write_serial(mmc1: &mut MMC1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.cpu_write(addr, (value >> bit) & 1);
+            mmc1.cpu_clock();
+            mmc1.cpu_clock();
+        }
+    }
+
+    #[test]
+    fn serial_load_switches_prg_banks() {
+        let mut mmc1 = MMC1::new(test_cartridge(1, 0, 0x20000, 0x2000));
+        // Powers on with 0x8000 switchable and the last bank fixed at 0xC000
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(14));
+        write_serial(&mut mmc1, 0xE000, 5);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(10));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(14));
+
+        // First bank fixed at 0x8000, vertical mirroring
+        write_serial(&mut mmc1, 0x8000, 0x0A);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(10));
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+
+        // 32KB mode ignores the low bit of the bank
+        write_serial(&mut mmc1, 0x8000, 0x00);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(8));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(10));
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn ignores_consecutive_writes() {
+        let mut mmc1 = MMC1::new(test_cartridge(1, 0, 0x20000, 0x2000));
+        // A read-modify-write instruction writes twice on back to back cycles, and only the first
+        // write counts. If the second one did, this would load 5 instead of 3.
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xE000, 0);
+        for bit in [1, 0, 0, 0] {
+            mmc1.cpu_clock();
+            mmc1.cpu_clock();
+            mmc1.cpu_write(0xE000, bit);
+        }
+        assert_eq!(mmc1.cpu_read(0x8000), Some(6));
+    }
+
+    #[test]
+    fn reset_write_clears_shift_register() {
+        let mut mmc1 = MMC1::new(test_cartridge(1, 0, 0x20000, 0x2000));
+        write_serial(&mut mmc1, 0x8000, 0x00);
+        assert_eq!(mmc1.cpu_read(0xC000), Some(2));
+
+        // Two bits in, then a reset, which also restores the fixed last bank
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0xE000, 1);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        mmc1.cpu_write(0x8000, 0x80);
+        mmc1.cpu_clock();
+        mmc1.cpu_clock();
+        assert_eq!(mmc1.cpu_read(0xC000), Some(14));
+        write_serial(&mut mmc1, 0xE000, 2);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(4));
+    }
+
+    #[test]
+    fn switches_chr_banks() {
+        let mut mmc1 = MMC1::new(test_cartridge(1, 0, 0x20000, 0x8000));
+        // 8KB mode ignores the low bit of CHR bank 0
+        write_serial(&mut mmc1, 0xA000, 3);
+        write_serial(&mut mmc1, 0xC000, 5);
+        assert_eq!(mmc1.ppu_read(0x0000), 8);
+        assert_eq!(mmc1.ppu_read(0x1000), 12);
+
+        // Two 4KB banks
+        write_serial(&mut mmc1, 0x8000, 0x1C);
+        assert_eq!(mmc1.ppu_read(0x0000), 12);
+        assert_eq!(mmc1.ppu_read(0x1000), 20);
+    }
+
+    #[test]
+    fn prg_ram_disable() {
+        let mut mmc1 = MMC1::new(test_cartridge(1, 0, 0x20000, 0x2000));
+        mmc1.cpu_write(0x6000, 0x55);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x55));
+        write_serial(&mut mmc1, 0xE000, 0x10);
+        assert_eq!(mmc1.cpu_read(0x6000), None);
+        mmc1.cpu_write(0x6000, 0xAA);
+        write_serial(&mut mmc1, 0xE000, 0x00);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x55));
+    }
+}
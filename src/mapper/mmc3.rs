@@ -0,0 +1,462 @@
+// Mapper 4: MMC3 (TxROM boards) and MMC6 (HKROM).
+// CPU 0x6000-0x7FFF - 8KB PRG RAM (MMC6: 1KB internal RAM at 0x7000-0x73FF, mirrored)
+// CPU 0x8000-0x9FFF - 8KB switchable PRG ROM bank, or fixed to the second-last bank
+// CPU 0xA000-0xBFFF - 8KB switchable PRG ROM bank
+// CPU 0xC000-0xDFFF - 8KB PRG ROM bank, fixed to the second-last bank, or switchable
+// CPU 0xE000-0xFFFF - 8KB PRG ROM bank, fixed to the last bank
+// PPU 0x0000-0x1FFF - Two 2KB switchable CHR banks and four 1KB switchable CHR banks,
+//                     in either half of the pattern table space
+//
+// Registers are selected by address range and whether the address is even or odd:
+// 0x8000 even - Bank select: register to update (bits 0-2), MMC6 PRG RAM enable (bit 5),
+//               PRG mode (bit 6), CHR A12 inversion (bit 7)
+// 0x8001 odd  - Bank data
+// 0xA000 even - Mirroring: vertical (0) or horizontal (1)
+// 0xA001 odd  - PRG RAM protect
+// 0xC000 even - IRQ latch
+// 0xC001 odd  - IRQ reload
+// 0xE000 even - IRQ disable and acknowledge
+// 0xE001 odd  - IRQ enable
+//
+// The IRQ counter is clocked by rising edges of PPU A12, which normally happens once per
+// scanline when backgrounds use the pattern table at 0x0000 and sprites the one at 0x1000.
+use super::{chr_memory, load_memory, prg_ram, save_memory, Mapper};
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+// A12 has to stay low for this many CPU cycles before a rising edge clocks the IRQ counter.
+// This filters out the toggling during sprite pattern fetches.
+const A12_FILTER_CYCLES: u8 = 3;
+const MMC6_RAM_SIZE: usize = 0x400;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Revision {
+    // MMC3B/MMC3C: IRQ fires on every clock that leaves the counter at 0
+    Mmc3New,
+    // MMC3A: IRQ only fires when the counter is decremented to 0 or reloaded by a 0xC001 write
+    Mmc3Old,
+    Mmc6,
+}
+
+pub struct MMC3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    revision: Revision,
+    four_screen: bool,
+
+    bank_select: u8,
+    banks: [u8; 8], // R0-R7
+    mirroring: Mirroring,
+    prg_ram_protect: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_asserted: bool,
+
+    a12: bool,
+    a12_low_cycles: u8,
+}
+
+impl MMC3 {
+    pub fn new(cartridge: Cartridge) -> MMC3 {
+        // NES 2.0 submappers: 1 = MMC6, 4 = MMC3A
+        let revision = match cartridge.header.submapper {
+            1 => Revision::Mmc6,
+            4 => Revision::Mmc3Old,
+            _ => Revision::Mmc3New,
+        };
+        let prg_ram = if revision == Revision::Mmc6 {
+            vec![0; MMC6_RAM_SIZE]
+        } else {
+            prg_ram(&cartridge)
+        };
+        let (chr, chr_writable) = chr_memory(&cartridge);
+        let mirroring = cartridge.header.mirroring;
+        MMC3 {
+            prg_rom: cartridge.prg_rom,
+            prg_ram,
+            chr,
+            chr_writable,
+            revision,
+            four_screen: mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            prg_ram_protect: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_asserted: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        let bank_count = self.prg_rom.len() / 0x2000;
+        let second_last = bank_count.saturating_sub(2);
+        let r6 = (self.banks[6] & 0x3F) as usize;
+        let r7 = (self.banks[7] & 0x3F) as usize;
+        let prg_mode = self.bank_select & 0x40 != 0;
+        let bank = match (addr >> 13) & 0x03 {
+            0 => {
+                if prg_mode {
+                    second_last
+                } else {
+                    r6
+                }
+            }
+            1 => r7,
+            2 => {
+                if prg_mode {
+                    r6
+                } else {
+                    second_last
+                }
+            }
+            _ => bank_count.saturating_sub(1),
+        };
+        (bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        // With A12 inversion the 2KB banks are at 0x1000 and the 1KB banks at 0x0000
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        } as usize;
+        let bank_1k = match addr >> 10 {
+            0 => self.banks[0] & !1,
+            1 => self.banks[0] | 1,
+            2 => self.banks[1] & !1,
+            3 => self.banks[1] | 1,
+            n => self.banks[n - 2],
+        } as usize;
+        (bank_1k * 0x400 + (addr & 0x3FF)) % self.chr.len()
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr, even) {
+            (0x8000..=0x9FFF, true) => self.bank_select = data,
+            (0x8000..=0x9FFF, false) => self.banks[(self.bank_select & 0x07) as usize] = data,
+            (0xA000..=0xBFFF, true) => {
+                if !self.four_screen {
+                    self.mirroring = if data & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            (0xA000..=0xBFFF, false) => {
+                // MMC6 ignores writes to the protect register while its RAM is disabled
+                if self.revision != Revision::Mmc6 || self.bank_select & 0x20 != 0 {
+                    self.prg_ram_protect = data;
+                }
+            }
+            (0xC000..=0xDFFF, true) => self.irq_latch = data,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_asserted = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let previous = self.irq_counter;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+        } else {
+            self.irq_counter -= 1;
+        }
+        let fire = match self.revision {
+            Revision::Mmc3Old => (previous > 0 || self.irq_reload) && self.irq_counter == 0,
+            _ => self.irq_counter == 0,
+        };
+        if fire && self.irq_enabled {
+            self.irq_asserted = true;
+        }
+        self.irq_reload = false;
+    }
+
+    // MMC3 PRG RAM: chip enable (bit 7), write protect (bit 6)
+    fn mmc3_ram_readable(&self) -> bool {
+        !self.prg_ram.is_empty() && self.prg_ram_protect & 0x80 != 0
+    }
+    fn mmc3_ram_writable(&self) -> bool {
+        self.mmc3_ram_readable() && self.prg_ram_protect & 0x40 == 0
+    }
+
+    // MMC6 PRG RAM is split into two 512 byte halves, each with its own read enable
+    // (bit 5 for 0x7000, bit 7 for 0x7200) and write enable (bit 4 for 0x7000, bit 6 for 0x7200)
+    fn mmc6_ram_read(&self, addr: u16) -> Option<u8> {
+        if addr < 0x7000 || self.bank_select & 0x20 == 0 {
+            return None;
+        }
+        let upper = addr & 0x200 != 0;
+        let lower_readable = self.prg_ram_protect & 0x20 != 0;
+        let upper_readable = self.prg_ram_protect & 0x80 != 0;
+        if !lower_readable && !upper_readable {
+            return None;
+        }
+        // If only one half is readable, the other one reads back as 0
+        if (upper && !upper_readable) || (!upper && !lower_readable) {
+            return Some(0);
+        }
+        Some(self.prg_ram[addr as usize & (MMC6_RAM_SIZE - 1)])
+    }
+    fn mmc6_ram_write(&mut self, addr: u16, data: u8) {
+        if addr < 0x7000 || self.bank_select & 0x20 == 0 {
+            return;
+        }
+        let upper = addr & 0x200 != 0;
+        let writable = if upper {
+            self.prg_ram_protect & 0xC0 == 0xC0
+        } else {
+            self.prg_ram_protect & 0x30 == 0x30
+        };
+        if writable {
+            self.prg_ram[addr as usize & (MMC6_RAM_SIZE - 1)] = data;
+        }
+    }
+}
+
+impl Mapper for MMC3 {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.revision == Revision::Mmc6 => self.mmc6_ram_read(addr),
+            0x6000..=0x7FFF if self.mmc3_ram_readable() => {
+                Some(self.prg_ram[(addr as usize - 0x6000) % self.prg_ram.len()])
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_offset(addr)]),
+            _ => None,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.revision == Revision::Mmc6 => self.mmc6_ram_write(addr, data),
+            0x6000..=0x7FFF if self.mmc3_ram_writable() => {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr as usize - 0x6000) % len] = data;
+            }
+            0x8000..=0xFFFF => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_writable {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        save_memory(state, &self.prg_ram, &self.chr, self.chr_writable);
+        state.u8(self.bank_select);
+        state.bytes(&self.banks);
+        state.mirroring(self.mirroring);
+        state.u8(self.prg_ram_protect);
+        state.u8(self.irq_latch);
+        state.u8(self.irq_counter);
+        state.bool(self.irq_reload);
+        state.bool(self.irq_enabled);
+        state.bool(self.irq_asserted);
+        state.bool(self.a12);
+        state.u8(self.a12_low_cycles);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_memory(state, &mut self.prg_ram, &mut self.chr, self.chr_writable)?;
+        self.bank_select = state.u8()?;
+        state.bytes_into(&mut self.banks)?;
+        self.mirroring = state.mirroring()?;
+        self.prg_ram_protect = state.u8()?;
+        self.irq_latch = state.u8()?;
+        self.irq_counter = state.u8()?;
+        self.irq_reload = state.bool()?;
+        self.irq_enabled = state.bool()?;
+        self.irq_asserted = state.bool()?;
+        self.a12 = state.bool()?;
+        self.a12_low_cycles = state.u8()?;
+        Ok(())
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_asserted
+    }
+    fn cpu_clock(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+    fn ppu_address(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 {
+            if self.a12_low_cycles >= A12_FILTER_CYCLES {
+                self.clock_irq_counter();
+            }
+        } else if !a12 && self.a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_cartridge;
+    use super::*;
+
+    // A rising edge on A12 after it's been low long enough to get through the filter, like
+    // the switch from background to sprite fetches on each scanline
+    fn scanline(mmc3: &mut MMC3) {
+        mmc3.ppu_address(0x0000);
+        for _ in 0..A12_FILTER_CYCLES {
+            mmc3.cpu_clock();
+        }
+        mmc3.ppu_address(0x1000);
+    }
+
+    // Latch, reload and enable the IRQ
+    fn start_irq(mmc3: &mut MMC3, latch: u8) {
+        mmc3.cpu_write(0xC000, latch);
+        mmc3.cpu_write(0xC001, 0);
+        mmc3.cpu_write(0xE001, 0);
+    }
+
+    #[test]
+    fn switches_prg_banks() {
+        let mut mmc3 = MMC3::new(test_cartridge(4, 0, 0x10000, 0x2000));
+        mmc3.cpu_write(0x8000, 6);
+        mmc3.cpu_write(0x8001, 3);
+        mmc3.cpu_write(0x8000, 7);
+        mmc3.cpu_write(0x8001, 5);
+        let banks = |mmc3: &mut MMC3| -> Vec<u8> {
+            [0x8000, 0xA000, 0xC000, 0xE000]
+                .iter()
+                .map(|&addr| mmc3.cpu_read(addr).unwrap())
+                .collect()
+        };
+        assert_eq!(banks(&mut mmc3), [3, 5, 6, 7]);
+        // PRG mode 1 swaps 0x8000 and 0xC000
+        mmc3.cpu_write(0x8000, 0x40);
+        assert_eq!(banks(&mut mmc3), [6, 5, 3, 7]);
+    }
+
+    #[test]
+    fn prg_rom_smaller_than_a_bank() {
+        let mut mmc3 = MMC3::new(test_cartridge(4, 0, 0x1000, 0x2000));
+        assert_eq!(mmc3.cpu_read(0xFFFC), Some(0));
+    }
+
+    #[test]
+    fn irq_counter_reloads_and_counts_down() {
+        let mut mmc3 = MMC3::new(test_cartridge(4, 0, 0x10000, 0x2000));
+        start_irq(&mut mmc3, 3);
+        // The first clock reloads the counter, then 3 more take it to 0
+        for _ in 0..3 {
+            scanline(&mut mmc3);
+            assert!(!mmc3.irq());
+        }
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+
+        // Acknowledging clears the IRQ and disables it until the next 0xE001 write
+        mmc3.cpu_write(0xE000, 0);
+        assert!(!mmc3.irq());
+        for _ in 0..4 {
+            scanline(&mut mmc3);
+        }
+        assert!(!mmc3.irq());
+        mmc3.cpu_write(0xE001, 0);
+        for _ in 0..3 {
+            scanline(&mut mmc3);
+        }
+        assert!(!mmc3.irq());
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+    }
+
+    #[test]
+    fn a12_filter_ignores_quick_toggles() {
+        let mut mmc3 = MMC3::new(test_cartridge(4, 0, 0x10000, 0x2000));
+        start_irq(&mut mmc3, 1);
+        scanline(&mut mmc3);
+        // Toggling A12 without enough CPU cycles in between, like 8x16 sprite fetches
+        for _ in 0..8 {
+            mmc3.ppu_address(0x0000);
+            mmc3.cpu_clock();
+            mmc3.ppu_address(0x1000);
+        }
+        assert!(!mmc3.irq());
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+    }
+
+    #[test]
+    fn old_and_new_irq_behavior() {
+        // With a latch of 0, MMC3B fires on every clock, while MMC3A only fires after the
+        // reload from the 0xC001 write
+        for (submapper, fires_again) in [(0, true), (4, false)] {
+            let mut mmc3 = MMC3::new(test_cartridge(4, submapper, 0x10000, 0x2000));
+            start_irq(&mut mmc3, 0);
+            scanline(&mut mmc3);
+            assert!(mmc3.irq());
+            mmc3.cpu_write(0xE000, 0);
+            mmc3.cpu_write(0xE001, 0);
+            scanline(&mut mmc3);
+            assert_eq!(mmc3.irq(), fires_again);
+        }
+    }
+
+    #[test]
+    fn mmc6_ram_halves() {
+        let mut mmc6 = MMC3::new(test_cartridge(4, 1, 0x10000, 0x2000));
+        // Disabled RAM is open bus, and the protect register can't be written
+        mmc6.cpu_write(0xA001, 0xF0);
+        assert_eq!(mmc6.cpu_read(0x7000), None);
+        mmc6.cpu_write(0x8000, 0x20);
+        assert_eq!(mmc6.cpu_read(0x7000), None);
+
+        // Both halves readable and writable, mirrored through 0x7000-0x7FFF
+        mmc6.cpu_write(0xA001, 0xF0);
+        mmc6.cpu_write(0x7000, 0x11);
+        mmc6.cpu_write(0x7200, 0x22);
+        assert_eq!(mmc6.cpu_read(0x7400), Some(0x11));
+        assert_eq!(mmc6.cpu_read(0x7E00), Some(0x22));
+        assert_eq!(mmc6.cpu_read(0x6000), None);
+
+        // Only the lower half readable and writable: the upper half reads as 0 and ignores
+        // writes
+        mmc6.cpu_write(0xA001, 0x30);
+        mmc6.cpu_write(0x7200, 0x33);
+        mmc6.cpu_write(0x7001, 0x44);
+        assert_eq!(mmc6.cpu_read(0x7200), Some(0));
+        assert_eq!(mmc6.cpu_read(0x7001), Some(0x44));
+
+        // Readable but not writable
+        mmc6.cpu_write(0xA001, 0xA0);
+        mmc6.cpu_write(0x7000, 0x55);
+        assert_eq!(mmc6.cpu_read(0x7000), Some(0x11));
+        assert_eq!(mmc6.cpu_read(0x7200), Some(0x22));
+    }
+}
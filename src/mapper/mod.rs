@@ -0,0 +1,165 @@
+// Cartridge boards. Everything the CPU sees at 0x4020-0xFFFF and everything the PPU sees at
+// 0x0000-0x1FFF is decided by the board's mapping hardware, which also controls how the PPU's
+// nametables are mirrored and may drive the CPU's IRQ line.
+use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+mod discrete;
+mod mmc1;
+mod mmc3;
+mod nrom;
+
+pub use discrete::Discrete;
+pub use mmc1::MMC1;
+pub use mmc3::MMC3;
+pub use nrom::NROM;
+
+pub trait Mapper {
+    // CPU side, 0x4020-0xFFFF. Returns None when nothing on the cartridge drives the data bus.
+    fn cpu_read(&mut self, addr: u16) -> Option<u8>;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+    // Same as cpu_read, but for debuggers and trace logs, so it must not change any state. None
+    // of the supported boards have read side effects, so this defaults to cpu_read.
+    fn cpu_peek(&mut self, addr: u16) -> Option<u8> {
+        self.cpu_read(addr)
+    }
+
+    // PPU side, 0x0000-0x1FFF pattern tables
+    fn ppu_read(&mut self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    // Current nametable arrangement
+    fn mirroring(&self) -> Mirroring;
+
+    // State of the cartridge's IRQ output, true while asserted
+    fn irq(&self) -> bool {
+        false
+    }
+    // Called once per CPU cycle (on M2)
+    fn cpu_clock(&mut self) {}
+    // Called whenever the PPU drives a new address onto its bus, for boards that watch PPU
+    // address lines to count scanlines
+    fn ppu_address(&mut self, _addr: u16) {}
+    // Called once per scanline while rendering is enabled
+    fn scanline(&mut self) {}
+
+    // Registers and cartridge RAM, for save states. ROM isn't included, the state can only be
+    // loaded into a mapper built from the same cartridge.
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+// Builds the mapper for the board described by the cartridge header
+pub fn new(cartridge: Cartridge) -> Result<Box<dyn Mapper>, CartridgeError> {
+    if cartridge.prg_rom.is_empty() {
+        return Err(CartridgeError::MissingPrgRom);
+    }
+    match cartridge.header.mapper {
+        0 => Ok(Box::new(NROM::new(cartridge))),
+        1 => Ok(Box::new(MMC1::new(cartridge))),
+        4 => Ok(Box::new(MMC3::new(cartridge))),
+        n => match Discrete::new(cartridge) {
+            Some(mapper) => Ok(Box::new(mapper)),
+            None => Err(CartridgeError::UnsupportedMapper(n)),
+        },
+    }
+}
+
+// PRG RAM mapped at 0x6000-0x7FFF. The trainer, if present, is loaded at 0x7000.
+fn prg_ram(cartridge: &Cartridge) -> Vec<u8> {
+    let header = &cartridge.header;
+    let size = header.prg_ram_size + header.prg_nvram_size;
+    let mut ram = vec![0; size];
+    if let Some(trainer) = &cartridge.trainer {
+        if ram.len() < 0x2000 {
+            ram.resize(0x2000, 0);
+        }
+        ram[0x1000..0x1000 + trainer.len()].copy_from_slice(trainer);
+    }
+    ram
+}
+
+// Pattern table memory: CHR ROM if the cartridge has any, CHR RAM otherwise.
+// Returns (memory, writable).
+fn chr_memory(cartridge: &Cartridge) -> (Vec<u8>, bool) {
+    if cartridge.chr_rom.is_empty() {
+        let header = &cartridge.header;
+        let size = (header.chr_ram_size + header.chr_nvram_size).max(0x2000);
+        (vec![0; size], true)
+    } else {
+        (cartridge.chr_rom.clone(), false)
+    }
+}
+
+// The PRG RAM and CHR RAM part of a mapper's save state
+fn save_memory(state: &mut StateWriter, prg_ram: &[u8], chr: &[u8], chr_writable: bool) {
+    state.section(b"CART");
+    state.bytes(prg_ram);
+    if chr_writable {
+        state.bytes(chr);
+    }
+}
+fn load_memory(
+    state: &mut StateReader,
+    prg_ram: &mut [u8],
+    chr: &mut [u8],
+    chr_writable: bool,
+) -> Result<(), StateError> {
+    state.section(b"CART")?;
+    state.bytes_into(prg_ram)?;
+    if chr_writable {
+        state.bytes_into(chr)?;
+    }
+    Ok(())
+}
+
+// A cartridge for mapper tests with 8KB of PRG RAM. Every byte of PRG ROM holds the number of the
+// 8KB bank it's in and every byte of CHR ROM the number of its 1KB bank, so reads show which banks
+// are mapped. With no CHR ROM the board gets 8KB of CHR RAM.
+#[cfg(test)]
+pub(crate) fn test_cartridge(
+    mapper: u16,
+    submapper: u8,
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+) -> Cartridge {
+    use crate::cartridge::{ConsoleType, Format, Header, Timing};
+    Cartridge {
+        header: Header {
+            format: Format::Nes2,
+            prg_rom_size,
+            chr_rom_size,
+            mapper,
+            submapper,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            prg_ram_size: 0x2000,
+            prg_nvram_size: 0,
+            chr_ram_size: if chr_rom_size == 0 { 0x2000 } else { 0 },
+            chr_nvram_size: 0,
+            console_type: ConsoleType::Nes,
+            timing: Timing::Ntsc,
+        },
+        trainer: None,
+        prg_rom: (0..prg_rom_size).map(|i| (i / 0x2000) as u8).collect(),
+        chr_rom: (0..chr_rom_size).map(|i| (i / 0x400) as u8).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unusable_cartridges() {
+        assert!(matches!(
+            new(test_cartridge(0, 0, 0, 0x2000)),
+            Err(CartridgeError::MissingPrgRom)
+        ));
+        assert!(matches!(
+            new(test_cartridge(5, 0, 0x8000, 0x2000)),
+            Err(CartridgeError::UnsupportedMapper(5))
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+// Mapper 0: NROM. No bank switching.
+// CPU 0x6000-0x7FFF - PRG RAM (only on some boards, e.g. Family Basic)
+// CPU 0x8000-0xBFFF - First 16KB of PRG ROM
+// CPU 0xC000-0xFFFF - Last 16KB of PRG ROM, or a mirror of 0x8000-0xBFFF for 16KB boards
+// PPU 0x0000-0x1FFF - 8KB of CHR ROM or CHR RAM
+// Mirroring is fixed by solder pads on the board.
+use super::{chr_memory, load_memory, prg_ram, save_memory, Mapper};
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub struct NROM {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    mirroring: Mirroring,
+}
+
+impl NROM {
+    pub fn new(cartridge: Cartridge) -> NROM {
+        let prg_ram = prg_ram(&cartridge);
+        let (chr, chr_writable) = chr_memory(&cartridge);
+        NROM {
+            prg_rom: cartridge.prg_rom,
+            prg_ram,
+            chr,
+            chr_writable,
+            mirroring: cartridge.header.mirroring,
+        }
+    }
+}
+
+impl Mapper for NROM {
+    fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()])
+            }
+            // 16KB boards mirror their PRG ROM into both halves
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => {
+                Some(self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr - 0x6000) as usize % len] = data;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_writable {
+            let len = self.chr.len();
+            self.chr[addr as usize % len] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    // The mirroring is fixed, so there's only memory to save
+    fn save_state(&self, state: &mut StateWriter) {
+        save_memory(state, &self.prg_ram, &self.chr, self.chr_writable);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        load_memory(state, &mut self.prg_ram, &mut self.chr, self.chr_writable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_cartridge;
+    use super::*;
+
+    #[test]
+    fn mirrors_16kb_prg_rom() {
+        let mut nrom = NROM::new(test_cartridge(0, 0, 0x4000, 0x2000));
+        assert_eq!(nrom.cpu_read(0x8000), Some(0));
+        assert_eq!(nrom.cpu_read(0xA000), Some(1));
+        assert_eq!(nrom.cpu_read(0xC000), Some(0));
+        assert_eq!(nrom.cpu_read(0xFFFF), Some(1));
+
+        let mut nrom = NROM::new(test_cartridge(0, 0, 0x8000, 0x2000));
+        assert_eq!(nrom.cpu_read(0xC000), Some(2));
+        assert_eq!(nrom.cpu_read(0xFFFF), Some(3));
+    }
+
+    #[test]
+    fn chr_rom_is_read_only() {
+        let mut nrom = NROM::new(test_cartridge(0, 0, 0x4000, 0x2000));
+        nrom.ppu_write(0x1C00, 0xAA);
+        assert_eq!(nrom.ppu_read(0x1C00), 7);
+
+        let mut nrom = NROM::new(test_cartridge(0, 0, 0x4000, 0));
+        nrom.ppu_write(0x1C00, 0xAA);
+        assert_eq!(nrom.ppu_read(0x1C00), 0xAA);
+    }
+
+    #[test]
+    fn prg_ram() {
+        let mut nrom = NROM::new(test_cartridge(0, 0, 0x4000, 0x2000));
+        nrom.cpu_write(0x6123, 0x55);
+        assert_eq!(nrom.cpu_read(0x6123), Some(0x55));
+
+        let mut cartridge = test_cartridge(0, 0, 0x4000, 0x2000);
+        cartridge.header.prg_ram_size = 0;
+        let mut nrom = NROM::new(cartridge);
+        nrom.cpu_write(0x6123, 0x55);
+        assert_eq!(nrom.cpu_read(0x6123), None);
+    }
+}
@@ -7,25 +7,43 @@
 // 0x4000-0x4017 - NES APU and I/O registers
 // 0x4018-0x401F - APU and I/O functionality that's normally disabled
 // 0x4020-0xFFFF - Cartridge space: PRG ROM, PRG RAM, and mapper registers
+// See bus.rs for the dispatch of these ranges.
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub const ZERO_PAGE_START: u16 = 0x00;
 pub const STACK_TOP: u16 = 0x100;
+pub const RAM_SIZE: usize = 2048;
 
 pub struct Memory {
-    ram: Box<[u8; 2048]>,
+    ram: Box<[u8; RAM_SIZE]>,
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Memory {
         Memory {
-            ram: Box::new([0xFFu8; 2048]),
+            ram: Box::new([0xFFu8; RAM_SIZE]),
         }
     }
-    // 2kb on-board memory
+    // 2kb on-board memory, mirrored every 2kb up to 0x1FFF
     pub fn ram_read(&self, addr: u16) -> u8 {
-        self.ram[(addr % 2048) as usize]
+        self.ram[addr as usize % RAM_SIZE]
     }
     pub fn ram_write(&mut self, addr: u16, data: u8) {
-        self.ram[(addr & 2048) as usize] = data;
+        self.ram[addr as usize % RAM_SIZE] = data;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"RAM ");
+        state.bytes(&self.ram[..]);
+    }
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"RAM ")?;
+        state.bytes_into(&mut self.ram[..])
     }
 }
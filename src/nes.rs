@@ -1,20 +1,254 @@
-use crate::cpu::CPU;
-use crate::mem::Memory;
-use crate::ppu::PPU;
-use std::cell::RefCell;
-use std::rc::Rc;
-
-struct NES {
-    cpu: CPU,
-    ppu: PPU,
+use crate::apu;
+use crate::audio::AudioOutput;
+use crate::bus::{Bus, NesBus};
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::checksum;
+use crate::controller::Port;
+use crate::cpu::{Variant, CPU};
+use crate::mapper;
+use crate::rewind::Rewind;
+use crate::state::{self, StateError, StateReader, StateWriter};
+use crate::trace;
+use std::io::{self, Read, Write};
+use std::mem;
+
+// The whole console. Runs an instruction at a time, with the PPU and APU kept in step with the
+// CPU, and gives access to the picture, sound and controllers.
+pub struct NES {
+    cartridge: Cartridge,              // kept as loaded, for power cycling
+    rom_crc: u32,     // CRC-32 of the PRG and CHR ROM, identifies the ROM in save states
+    cpu: CPU<NesBus>, // the bus owns the PPU, APU, RAM and cartridge mapper
+    trace: Option<Box<dyn io::Write>>, // receives a nestest.log style line before each instruction
+    rewind: Option<Rewind>, // snapshots taken by run_frame
 }
 
 impl NES {
-    pub fn new() -> NES {
-        let mem = Rc::new(RefCell::new(Memory::new()));
-        NES {
-            cpu: CPU::new(mem.clone()),
-            ppu: PPU::new(mem.clone()),
+    pub fn new(cartridge: Cartridge) -> Result<NES, CartridgeError> {
+        let cpu = NES::power_on(cartridge.clone())?;
+        let rom_crc =
+            checksum::crc32_update(checksum::crc32(&cartridge.prg_rom), &cartridge.chr_rom);
+        Ok(NES {
+            cartridge,
+            rom_crc,
+            cpu,
+            trace: None,
+            rewind: None,
+        })
+    }
+
+    fn power_on(cartridge: Cartridge) -> Result<CPU<NesBus>, CartridgeError> {
+        let mapper = mapper::new(cartridge)?;
+        let mut cpu = CPU::new(NesBus::new(mapper), Variant::Ricoh2A03);
+        cpu.reset();
+        Ok(cpu)
+    }
+
+    // Runs a single CPU instruction, along with any interrupt it triggers
+    pub fn step_instruction(&mut self) {
+        if self.trace.is_some() {
+            self.write_trace();
+        }
+        self.cpu.advance_cpu();
+    }
+
+    // Runs until the PPU starts the next frame
+    pub fn run_frame(&mut self) {
+        let frame = self.cpu.bus().ppu().frame();
+        while self.cpu.bus().ppu().frame() == frame {
+            self.step_instruction();
+        }
+        self.take_snapshot();
+    }
+
+    // Runs whole instructions until at least n CPU cycles have passed, and returns the number of
+    // cycles that actually ran
+    pub fn run_cycles(&mut self, n: u64) -> u64 {
+        let start = self.cycles();
+        while self.cycles() - start < n {
+            self.step_instruction();
+        }
+        self.cycles() - start
+    }
+
+    // Presses the reset button
+    pub fn reset(&mut self) {
+        self.cpu.bus_mut().reset();
+        self.cpu.reset();
+    }
+
+    // Switches the console off and on again. Everything is reinitialized, including cartridge
+    // RAM, and the audio sample rate is kept.
+    pub fn power_cycle(&mut self) {
+        let sample_rate = self.sample_rate();
+        // The mapper was already built from this cartridge, so it can't fail
+        self.cpu = NES::power_on(self.cartridge.clone()).expect("cartridge was already loaded");
+        self.set_sample_rate(sample_rate);
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+    }
+
+    // Writes the state of the whole machine, see state.rs for the format
+    pub fn save_state<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut state = StateWriter::new();
+        state.section(&state::MAGIC);
+        state.u32(state::VERSION);
+        state.u32(self.rom_crc);
+        self.cpu.save_state(&mut state);
+        self.cpu.bus().save_state(&mut state);
+        out.write_all(&state.into_bytes())
+    }
+
+    // Restores a state written by save_state for the same ROM. If it fails, the machine is left
+    // as it was. The audio output carries on from where it was, since it isn't part of the state.
+    pub fn load_state<R: Read>(&mut self, input: &mut R) -> Result<(), StateError> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut state = StateReader::new(&data);
+        if state.section(&state::MAGIC).is_err() {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = state.u32()?;
+        if version != state::VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let rom_crc = state.u32()?;
+        if rom_crc != self.rom_crc {
+            return Err(StateError::RomMismatch {
+                expected: self.rom_crc,
+                actual: rom_crc,
+            });
+        }
+
+        // Loaded into a fresh machine first, so a corrupt state can't leave this one half loaded
+        let mut cpu = NES::power_on(self.cartridge.clone()).expect("cartridge was already loaded");
+        cpu.load_state(&mut state)?;
+        cpu.bus_mut().load_state(&mut state)?;
+        if !state.at_end() {
+            return Err(StateError::Corrupt);
+        }
+        mem::swap(cpu.bus_mut().audio_mut(), self.cpu.bus_mut().audio_mut());
+        self.cpu = cpu;
+        Ok(())
+    }
+
+    // Starts keeping snapshots to rewind to, one every interval frames, using up to budget bytes
+    // of memory. Older snapshots are dropped to stay within the budget. Snapshots are only taken
+    // by run_frame.
+    pub fn enable_rewind(&mut self, interval: u64, budget: usize) {
+        self.rewind = Some(Rewind::new(interval, budget));
+        self.take_snapshot();
+    }
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+    pub fn rewind_history(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    // Goes back to the latest snapshot at least the given number of frames ago, or the oldest
+    // one if there's not enough history, and returns how many frames it went back. Snapshots
+    // after that point are dropped. Running on with the same input from there repeats exactly
+    // what happened before, apart from audio that has already been queued.
+    pub fn rewind(&mut self, frames: u64) -> Result<u64, StateError> {
+        let current = self.frame();
+        let target = current.saturating_sub(frames);
+        let state = match self
+            .rewind
+            .as_mut()
+            .and_then(|rewind| rewind.restore(target))
+        {
+            Some((_, state)) => state,
+            None => return Ok(0),
+        };
+        self.load_state(&mut &state[..])?;
+        Ok(current.saturating_sub(self.frame()))
+    }
+
+    fn take_snapshot(&mut self) {
+        let frame = self.frame();
+        if !self.rewind.as_ref().is_some_and(|rewind| rewind.due(frame)) {
+            return;
+        }
+        let mut state = Vec::new();
+        self.save_state(&mut state)
+            .expect("writing to a Vec can't fail");
+        if let Some(rewind) = &mut self.rewind {
+            rewind.push(frame, state);
+        }
+    }
+
+    // CPU cycles since power on
+    pub fn cycles(&self) -> u64 {
+        self.cpu.bus().cycles()
+    }
+    // Frames the PPU has started since power on
+    pub fn frame(&self) -> u64 {
+        self.cpu.bus().ppu().frame()
+    }
+
+    // The last complete picture the PPU drew, ppu::WIDTH x ppu::HEIGHT palette indices (0-63) in
+    // row-major order. While a frame is being drawn this is still the previous one.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.cpu.bus().ppu().framebuffer()
+    }
+
+    // Sets the buttons held on the controller in a port, using the BUTTON_ bits in
+    // controller.rs. Games normally read the controllers once per frame.
+    pub fn set_buttons(&mut self, port: Port, buttons: u8) {
+        self.cpu.bus_mut().controller_mut(port).set_buttons(buttons);
+    }
+    pub fn buttons(&self, port: Port) -> u8 {
+        self.cpu.bus().controller(port).buttons()
+    }
+
+    // Changes the rate audio samples are produced at, e.g. 44100 or 48000. Samples that haven't
+    // been read yet are dropped.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        *self.cpu.bus_mut().audio_mut() = AudioOutput::new(apu::CPU_CLOCK_RATE, sample_rate);
+    }
+    pub fn sample_rate(&self) -> u32 {
+        self.cpu.bus().audio().sample_rate()
+    }
+
+    // Audio samples are queued as the APU runs, about one frame's worth per frame, and should be
+    // read regularly. Only the most recent second is kept.
+    pub fn samples_available(&self) -> usize {
+        self.cpu.bus().audio().available()
+    }
+    // Moves queued samples into out, returning how many were read
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.cpu.bus_mut().audio_mut().read(out)
+    }
+    pub fn read_samples_i16(&mut self, out: &mut [i16]) -> usize {
+        self.cpu.bus_mut().audio_mut().read_i16(out)
+    }
+
+    // Reads the CPU address space without side effects
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        self.cpu.bus_mut().peek(addr)
+    }
+
+    // Starts or stops writing a trace line for every instruction, see trace.rs. Tracing stops if
+    // a write fails.
+    pub fn set_trace(&mut self, trace: Option<Box<dyn io::Write>>) {
+        self.trace = trace;
+    }
+
+    fn write_trace(&mut self) {
+        let registers = self.cpu.registers();
+        let line = trace::trace_line(&registers, self.cpu.bus_mut());
+        if let Some(out) = &mut self.trace {
+            if writeln!(out, "{}", line).is_err() {
+                self.trace = None;
+            }
         }
     }
+
+    pub fn cpu(&self) -> &CPU<NesBus> {
+        &self.cpu
+    }
+    pub fn cpu_mut(&mut self) -> &mut CPU<NesBus> {
+        &mut self.cpu
+    }
 }
@@ -1,12 +1,1069 @@
-use crate::mem::Memory;
-use std::cell::RefCell;
-use std::rc::Rc;
+// 2C02 PPU. The CPU talks to it through 8 memory mapped registers at 0x2000-0x2007:
+// 0x2000 - PPUCTRL (write): nametable select, VRAM increment, pattern tables, sprite size, NMI enable
+// 0x2001 - PPUMASK (write): greyscale, left column clipping, background/sprite enable, emphasis
+// 0x2002 - PPUSTATUS (read): sprite overflow, sprite 0 hit, vblank
+// 0x2003 - OAMADDR (write)
+// 0x2004 - OAMDATA (read/write)
+// 0x2005 - PPUSCROLL (write x2)
+// 0x2006 - PPUADDR (write x2)
+// 0x2007 - PPUDATA (read/write)
+//
+// The PPU has its own 14 bit address space:
+// 0x0000-0x1FFF - Pattern tables, provided by the cartridge
+// 0x2000-0x2FFF - 4 nametables, backed by 2KB of internal VRAM mirrored as the cartridge says
+// 0x3000-0x3EFF - Mirror of 0x2000-0x2EFF
+// 0x3F00-0x3F1F - Palette RAM
+// 0x3F20-0x3FFF - Mirrors of 0x3F00-0x3F1F
+//
+// A frame is 262 scanlines of 341 dots each, with the PPU running 3 dots per CPU cycle:
+// 0-239   - Visible scanlines
+// 240     - Post-render scanline
+// 241-260 - Vertical blank. The vblank flag is set and NMI raised at dot 1 of scanline 241.
+// 261     - Pre-render scanline. Flags are cleared at dot 1. On odd frames with rendering
+//           enabled, the last dot of this scanline is skipped.
+//
+// On visible and pre-render scanlines with rendering enabled, the PPU fetches a tile every 8 dots:
+// 1-256   - Background tiles for this scanline (nametable, attribute, pattern low, pattern high)
+// 257-320 - Pattern data for the sprites found by sprite evaluation, for the next scanline
+// 321-336 - The first two background tiles of the next scanline
+// 337-340 - Two unused nametable fetches
+// Background pixels come out of 16 bit shift registers, which are reloaded every 8 dots.
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::state::{StateError, StateReader, StateWriter};
+use std::mem;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+
+// PPUCTRL
+const CTRL_NAMETABLE: u8 = 0x03;
+const CTRL_INCREMENT_32: u8 = 1 << 2;
+const CTRL_SPRITE_TABLE: u8 = 1 << 3;
+const CTRL_BACKGROUND_TABLE: u8 = 1 << 4;
+const CTRL_SPRITE_SIZE_16: u8 = 1 << 5;
+const CTRL_NMI_ENABLE: u8 = 1 << 7;
+// PPUMASK
+const MASK_GREYSCALE: u8 = 1 << 0;
+const MASK_BACKGROUND_LEFT: u8 = 1 << 1;
+const MASK_SPRITES_LEFT: u8 = 1 << 2;
+const MASK_SHOW_BACKGROUND: u8 = 1 << 3;
+const MASK_SHOW_SPRITES: u8 = 1 << 4;
+// PPUSTATUS
+const STATUS_SPRITE_OVERFLOW: u8 = 1 << 5;
+const STATUS_SPRITE_ZERO_HIT: u8 = 1 << 6;
+const STATUS_VBLANK: u8 = 1 << 7;
+// Sprite attributes
+const SPRITE_PALETTE: u8 = 0x03;
+const SPRITE_BEHIND_BACKGROUND: u8 = 1 << 5;
+const SPRITE_FLIP_HORIZONTAL: u8 = 1 << 6;
+const SPRITE_FLIP_VERTICAL: u8 = 1 << 7;
+
 pub struct PPU {
-    mem: Rc<RefCell<Memory>>,
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+
+    // Internal registers shared by PPUSCROLL, PPUADDR and rendering.
+    // v and t are laid out as yyy NN YYYYY XXXXX: fine Y, nametable, coarse Y, coarse X
+    v: u16,          // current VRAM address
+    t: u16,          // temporary VRAM address, the top left onscreen tile
+    x: u8,           // fine X scroll, 3 bits
+    w: bool,         // first/second write toggle for PPUSCROLL and PPUADDR
+    read_buffer: u8, // PPUDATA reads below the palette return the result of the previous read
+    io_latch: u8, // The PPU's internal data bus. Reading a write-only register returns its contents.
+
+    vram: Box<[u8; 0x1000]>, // 2KB nametable RAM, plus 2KB for cartridges with four-screen VRAM
+    palette: [u8; 32],
+    oam: [u8; 256], // 64 sprites, 4 bytes each
+
+    // Background fetch pipeline. The latches hold the tile being fetched, the shift registers
+    // the two tiles being drawn, with the next pixel in bit 15.
+    nametable_latch: u8,
+    attribute_latch: u8, // 2 bit palette number for the tile being fetched
+    pattern_lo_latch: u8,
+    pattern_hi_latch: u8,
+    pattern_lo_shift: u16,
+    pattern_hi_shift: u16,
+    attribute_lo_shift: u16,
+    attribute_hi_shift: u16,
+
+    // Sprites. Evaluation fills secondary OAM with up to 8 sprites for the next scanline, and
+    // their pattern data is fetched into the per-slot registers at the end of the scanline.
+    secondary_oam: [u8; 32],
+    sprite_count: usize,
+    sprite_zero_in_line: bool, // slot 0 holds sprite 0
+    sprite_x: [u8; 8],
+    sprite_attributes: [u8; 8],
+    sprite_pattern_lo: [u8; 8], // already flipped horizontally if needed
+    sprite_pattern_hi: [u8; 8],
+
+    // Palette indices, one per pixel. Pixels are drawn into the back buffer, which is swapped
+    // with framebuffer at the end of the last visible scanline, so framebuffer always holds a
+    // whole picture.
+    framebuffer: Box<[u8; WIDTH * HEIGHT]>,
+    back_buffer: Box<[u8; WIDTH * HEIGHT]>,
+
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    odd_frame: bool,
+    // Reading PPUSTATUS just before vblank starts prevents the flag from being set that frame
+    suppress_vblank: bool,
+}
+
+impl Default for PPU {
+    fn default() -> PPU {
+        PPU::new()
+    }
 }
 
 impl PPU {
-    pub fn new(mem: Rc<RefCell<Memory>>) -> PPU {
-        PPU { mem }
+    pub fn new() -> PPU {
+        PPU {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            vram: Box::new([0; 0x1000]),
+            palette: [0; 32],
+            oam: [0; 256],
+            nametable_latch: 0,
+            attribute_latch: 0,
+            pattern_lo_latch: 0,
+            pattern_hi_latch: 0,
+            pattern_lo_shift: 0,
+            pattern_hi_shift: 0,
+            attribute_lo_shift: 0,
+            attribute_hi_shift: 0,
+            secondary_oam: [0xFF; 32],
+            sprite_count: 0,
+            sprite_zero_in_line: false,
+            sprite_x: [0; 8],
+            sprite_attributes: [0; 8],
+            sprite_pattern_lo: [0; 8],
+            sprite_pattern_hi: [0; 8],
+            framebuffer: Box::new([0; WIDTH * HEIGHT]),
+            back_buffer: Box::new([0; WIDTH * HEIGHT]),
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            odd_frame: false,
+            suppress_vblank: false,
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.section(b"PPU ");
+        state.u8(self.ctrl);
+        state.u8(self.mask);
+        state.u8(self.status);
+        state.u8(self.oam_addr);
+        state.u16(self.v);
+        state.u16(self.t);
+        state.u8(self.x);
+        state.bool(self.w);
+        state.u8(self.read_buffer);
+        state.u8(self.io_latch);
+        state.bytes(&self.vram[..]);
+        state.bytes(&self.palette);
+        state.bytes(&self.oam);
+
+        state.u8(self.nametable_latch);
+        state.u8(self.attribute_latch);
+        state.u8(self.pattern_lo_latch);
+        state.u8(self.pattern_hi_latch);
+        state.u16(self.pattern_lo_shift);
+        state.u16(self.pattern_hi_shift);
+        state.u16(self.attribute_lo_shift);
+        state.u16(self.attribute_hi_shift);
+
+        state.bytes(&self.secondary_oam);
+        state.u8(self.sprite_count as u8);
+        state.bool(self.sprite_zero_in_line);
+        state.bytes(&self.sprite_x);
+        state.bytes(&self.sprite_attributes);
+        state.bytes(&self.sprite_pattern_lo);
+        state.bytes(&self.sprite_pattern_hi);
+
+        state.bytes(&self.framebuffer[..]);
+        state.bytes(&self.back_buffer[..]);
+        state.u16(self.scanline);
+        state.u16(self.dot);
+        state.u64(self.frame);
+        state.bool(self.odd_frame);
+        state.bool(self.suppress_vblank);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.section(b"PPU ")?;
+        self.ctrl = state.u8()?;
+        self.mask = state.u8()?;
+        self.status = state.u8()?;
+        self.oam_addr = state.u8()?;
+        self.v = state.u16()?;
+        self.t = state.u16()?;
+        self.x = state.u8_below(8)?;
+        self.w = state.bool()?;
+        self.read_buffer = state.u8()?;
+        self.io_latch = state.u8()?;
+        state.bytes_into(&mut self.vram[..])?;
+        state.bytes_into(&mut self.palette)?;
+        state.bytes_into(&mut self.oam)?;
+
+        self.nametable_latch = state.u8()?;
+        self.attribute_latch = state.u8()?;
+        self.pattern_lo_latch = state.u8()?;
+        self.pattern_hi_latch = state.u8()?;
+        self.pattern_lo_shift = state.u16()?;
+        self.pattern_hi_shift = state.u16()?;
+        self.attribute_lo_shift = state.u16()?;
+        self.attribute_hi_shift = state.u16()?;
+
+        state.bytes_into(&mut self.secondary_oam)?;
+        self.sprite_count = state.u8_below(9)? as usize;
+        self.sprite_zero_in_line = state.bool()?;
+        state.bytes_into(&mut self.sprite_x)?;
+        state.bytes_into(&mut self.sprite_attributes)?;
+        state.bytes_into(&mut self.sprite_pattern_lo)?;
+        state.bytes_into(&mut self.sprite_pattern_hi)?;
+
+        state.bytes_into(&mut self.framebuffer[..])?;
+        state.bytes_into(&mut self.back_buffer[..])?;
+        self.scanline = state.u16()?;
+        self.dot = state.u16()?;
+        if self.scanline >= SCANLINES_PER_FRAME || self.dot >= DOTS_PER_SCANLINE {
+            return Err(StateError::Corrupt);
+        }
+        self.frame = state.u64()?;
+        self.odd_frame = state.bool()?;
+        self.suppress_vblank = state.bool()?;
+        Ok(())
+    }
+
+    // reg is the register index, 0-7
+    pub fn read_register(&mut self, reg: u16, mapper: &mut dyn Mapper) -> u8 {
+        let data = match reg {
+            2 => {
+                // Low 5 bits aren't driven and read back whatever was last on the bus
+                let data = (self.status & 0xE0) | (self.io_latch & 0x1F);
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                if self.scanline == VBLANK_SCANLINE && self.dot == 0 {
+                    self.suppress_vblank = true;
+                }
+                data
+            }
+            4 => {
+                let data = self.oam[self.oam_addr as usize];
+                // Bits 2-4 of the sprite attribute byte don't exist
+                if self.oam_addr & 0x03 == 2 {
+                    data & 0xE3
+                } else {
+                    data
+                }
+            }
+            7 => {
+                let addr = self.v & 0x3FFF;
+                let data = if addr >= 0x3F00 {
+                    // Palette reads come back immediately with the open bus in the top 2 bits,
+                    // while the buffer is filled from the nametable "underneath" the palette
+                    self.read_buffer = self.vram_read(addr - 0x1000, mapper);
+                    (self.read_palette(addr) & 0x3F) | (self.io_latch & 0xC0)
+                } else {
+                    let data = self.read_buffer;
+                    self.read_buffer = self.vram_read(addr, mapper);
+                    data
+                };
+                self.increment_v(mapper);
+                data
+            }
+            // Write-only registers
+            _ => self.io_latch,
+        };
+        self.io_latch = data;
+        data
+    }
+
+    pub fn write_register(&mut self, reg: u16, data: u8, mapper: &mut dyn Mapper) {
+        self.io_latch = data;
+        match reg {
+            0 => {
+                self.ctrl = data;
+                self.t = (self.t & !0x0C00) | (((data & CTRL_NAMETABLE) as u16) << 10);
+            }
+            1 => self.mask = data,
+            2 => {}
+            3 => self.oam_addr = data,
+            4 => {
+                // Writes during rendering don't reach OAM, they just bump the address
+                if self.rendering() {
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                } else {
+                    self.oam[self.oam_addr as usize] = data;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                }
+            }
+            5 => {
+                if !self.w {
+                    self.t = (self.t & !0x001F) | (data >> 3) as u16;
+                    self.x = data & 0x07;
+                } else {
+                    self.t = (self.t & !0x73E0)
+                        | (((data & 0x07) as u16) << 12)
+                        | (((data & 0xF8) as u16) << 2);
+                }
+                self.w = !self.w;
+            }
+            6 => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                    mapper.ppu_address(self.v & 0x3FFF);
+                }
+                self.w = !self.w;
+            }
+            _ => {
+                self.vram_write(self.v & 0x3FFF, data, mapper);
+                self.increment_v(mapper);
+            }
+        }
+    }
+
+    // OAM DMA writes go through OAMDATA
+    pub fn write_oam_data(&mut self, data: u8, mapper: &mut dyn Mapper) {
+        self.write_register(4, data, mapper);
+    }
+
+    // After PPUDATA accesses v moves across (by 1) or down (by 32). During rendering the
+    // increment collides with the rendering increments and bumps both coarse X and Y instead.
+    fn increment_v(&mut self, mapper: &mut dyn Mapper) {
+        if self.rendering() {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            let step = if self.ctrl & CTRL_INCREMENT_32 != 0 {
+                32
+            } else {
+                1
+            };
+            self.v = self.v.wrapping_add(step) & 0x7FFF;
+        }
+        mapper.ppu_address(self.v & 0x3FFF);
+    }
+
+    // Moves v to the next tile, wrapping into the horizontally adjacent nametable
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Moves v to the next pixel row, wrapping into the vertically adjacent nametable after
+    // row 29. Coarse Y values of 30 and 31 (the attribute table) wrap without switching.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn copy_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+    fn copy_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn vram_read(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        mapper.ppu_address(addr);
+        match addr {
+            0x0000..=0x1FFF => mapper.ppu_read(addr),
+            0x2000..=0x3EFF => self.vram[nametable_index(addr, mapper.mirroring())],
+            _ => self.read_palette(addr),
+        }
+    }
+
+    fn vram_write(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+        mapper.ppu_address(addr);
+        match addr {
+            0x0000..=0x1FFF => mapper.ppu_write(addr, data),
+            0x2000..=0x3EFF => self.vram[nametable_index(addr, mapper.mirroring())] = data,
+            _ => self.palette[palette_index(addr)] = data & 0x3F,
+        }
+    }
+
+    fn read_palette(&self, addr: u16) -> u8 {
+        let color = self.palette[palette_index(addr)];
+        if self.mask & MASK_GREYSCALE != 0 {
+            color & 0x30
+        } else {
+            color
+        }
+    }
+
+    fn rendering(&self) -> bool {
+        self.rendering_enabled() && (self.scanline < 240 || self.scanline == PRE_RENDER_SCANLINE)
+    }
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES) != 0
+    }
+
+    // Advances the PPU by one dot
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        let visible = self.scanline < 240;
+        if self.rendering_enabled() && (visible || self.scanline == PRE_RENDER_SCANLINE) {
+            self.render_tick(visible, mapper);
+        }
+        if visible && (1..=256).contains(&self.dot) {
+            self.output_pixel();
+        }
+
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.status |= STATUS_VBLANK;
+            }
+            self.suppress_vblank = false;
+        } else if self.scanline == PRE_RENDER_SCANLINE && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+
+        self.dot += 1;
+        // The pre-render scanline is one dot shorter on odd frames when rendering is enabled
+        if self.scanline == PRE_RENDER_SCANLINE
+            && self.dot == DOTS_PER_SCANLINE - 1
+            && self.odd_frame
+            && self.rendering_enabled()
+        {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == HEIGHT as u16 {
+                mem::swap(&mut self.framebuffer, &mut self.back_buffer);
+            }
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    // Memory fetches and scroll updates for one dot of a visible or pre-render scanline
+    fn render_tick(&mut self, visible: bool, mapper: &mut dyn Mapper) {
+        let dot = self.dot;
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.nametable_latch = self.vram_read(0x2000 | (self.v & 0x0FFF), mapper);
+                }
+                2 => {
+                    let v = self.v;
+                    let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    let attribute = self.vram_read(addr, mapper);
+                    // Each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+                    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                    self.attribute_latch = (attribute >> shift) & 0x03;
+                }
+                4 => {
+                    let addr = self.background_pattern_addr();
+                    self.pattern_lo_latch = self.vram_read(addr, mapper);
+                }
+                6 => {
+                    let addr = self.background_pattern_addr() + 8;
+                    self.pattern_hi_latch = self.vram_read(addr, mapper);
+                }
+                7 => self.increment_x(),
+                _ => {}
+            }
+        }
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.copy_x();
+                // There are never any sprites on the first scanline
+                if visible {
+                    self.evaluate_sprites();
+                } else {
+                    self.sprite_count = 0;
+                    self.sprite_zero_in_line = false;
+                }
+            }
+            // Unused nametable fetches
+            338 | 340 => {
+                self.vram_read(0x2000 | (self.v & 0x0FFF), mapper);
+            }
+            _ => {}
+        }
+        if (257..=320).contains(&dot) {
+            self.oam_addr = 0;
+            self.fetch_sprite(((dot - 257) / 8) as usize, (dot - 257) % 8, mapper);
+        }
+        if !visible && (280..=304).contains(&dot) {
+            self.copy_y();
+        }
+        if dot == 260 {
+            mapper.scanline();
+        }
+    }
+
+    fn background_pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BACKGROUND_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        let fine_y = (self.v >> 12) & 0x07;
+        table | ((self.nametable_latch as u16) << 4) | fine_y
+    }
+
+    fn shift_background(&mut self) {
+        self.pattern_lo_shift <<= 1;
+        self.pattern_hi_shift <<= 1;
+        self.attribute_lo_shift <<= 1;
+        self.attribute_hi_shift <<= 1;
+    }
+
+    // Loads the fetched tile into the low byte of the shift registers
+    fn load_background_shifters(&mut self) {
+        self.pattern_lo_shift = (self.pattern_lo_shift & 0xFF00) | self.pattern_lo_latch as u16;
+        self.pattern_hi_shift = (self.pattern_hi_shift & 0xFF00) | self.pattern_hi_latch as u16;
+        let attribute_lo = if self.attribute_latch & 1 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let attribute_hi = if self.attribute_latch & 2 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        self.attribute_lo_shift = (self.attribute_lo_shift & 0xFF00) | attribute_lo;
+        self.attribute_hi_shift = (self.attribute_hi_shift & 0xFF00) | attribute_hi;
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE_16 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    // Finds the first 8 sprites in OAM that are on the next scanline and copies them to
+    // secondary OAM. Sprite Y positions are one less than the scanline they start on.
+    fn evaluate_sprites(&mut self) {
+        let height = self.sprite_height();
+        let scanline = self.scanline;
+        let in_range = |y: u8| scanline >= y as u16 && scanline < y as u16 + height;
+
+        self.secondary_oam = [0xFF; 32];
+        self.sprite_count = 0;
+        self.sprite_zero_in_line = false;
+        let mut n = 0;
+        while n < 64 && self.sprite_count < 8 {
+            if in_range(self.oam[n * 4]) {
+                let slot = self.sprite_count * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                if n == 0 {
+                    self.sprite_zero_in_line = true;
+                }
+                self.sprite_count += 1;
+            }
+            n += 1;
+        }
+
+        // With 8 sprites found, the PPU keeps looking for a ninth to set the overflow flag. Due
+        // to a hardware bug it increments the byte offset within each sprite along with the
+        // sprite index, so it checks tile numbers, attributes and X positions as if they were Y
+        // coordinates, causing both false positives and false negatives.
+        if self.sprite_count == 8 {
+            let mut m = 0;
+            while n < 64 {
+                if in_range(self.oam[n * 4 + m]) {
+                    self.status |= STATUS_SPRITE_OVERFLOW;
+                    break;
+                }
+                n += 1;
+                m = (m + 1) & 0x03;
+            }
+        }
+    }
+
+    // One dot of the 8 dot fetch for a secondary OAM slot. Empty slots still fetch pattern
+    // data (for tile 0xFF), which mappers watching the PPU address bus depend on.
+    fn fetch_sprite(&mut self, slot: usize, step: u16, mapper: &mut dyn Mapper) {
+        match step {
+            // Unused nametable fetches
+            0 | 2 => {
+                self.vram_read(0x2000 | (self.v & 0x0FFF), mapper);
+            }
+            4 => {
+                let addr = self.sprite_pattern_addr(slot);
+                self.sprite_pattern_lo[slot] = self.vram_read(addr, mapper);
+            }
+            6 => {
+                let addr = self.sprite_pattern_addr(slot) + 8;
+                let mut lo = self.sprite_pattern_lo[slot];
+                let mut hi = self.vram_read(addr, mapper);
+                let attributes = self.secondary_oam[slot * 4 + 2];
+                if attributes & SPRITE_FLIP_HORIZONTAL != 0 {
+                    lo = lo.reverse_bits();
+                    hi = hi.reverse_bits();
+                }
+                self.sprite_pattern_lo[slot] = lo;
+                self.sprite_pattern_hi[slot] = hi;
+                self.sprite_attributes[slot] = attributes;
+                self.sprite_x[slot] = self.secondary_oam[slot * 4 + 3];
+            }
+            _ => {}
+        }
+    }
+
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        let height = self.sprite_height();
+        let y = self.secondary_oam[slot * 4] as u16;
+        let tile = self.secondary_oam[slot * 4 + 1] as u16;
+        let attributes = self.secondary_oam[slot * 4 + 2];
+        let mut row = self.scanline.wrapping_sub(y) & (height - 1);
+        if attributes & SPRITE_FLIP_VERTICAL != 0 {
+            row = height - 1 - row;
+        }
+        if height == 8 {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                0x1000
+            } else {
+                0
+            };
+            table | (tile << 4) | row
+        } else {
+            // 8x16 sprites take the pattern table from bit 0 of the tile number
+            let table = (tile & 1) * 0x1000;
+            let tile = (tile & 0xFE) + row / 8;
+            table | (tile << 4) | (row & 0x07)
+        }
+    }
+
+    // Picks the color for the pixel at the current dot and writes it to the back buffer
+    fn output_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+        let y = self.scanline as usize;
+
+        if !self.rendering_enabled() {
+            // With rendering disabled the backdrop color is shown, unless v points into
+            // palette RAM, in which case that color is shown instead
+            let addr = if self.v & 0x3F00 == 0x3F00 {
+                self.v
+            } else {
+                0x3F00
+            };
+            self.back_buffer[y * WIDTH + x] = self.read_palette(addr);
+            return;
+        }
+
+        let mut bg_pixel = 0;
+        let mut bg_palette = 0;
+        if self.mask & MASK_SHOW_BACKGROUND != 0
+            && (x >= 8 || self.mask & MASK_BACKGROUND_LEFT != 0)
+        {
+            let bit = 0x8000 >> self.x;
+            bg_pixel = ((self.pattern_hi_shift & bit != 0) as u8) << 1
+                | (self.pattern_lo_shift & bit != 0) as u8;
+            bg_palette = ((self.attribute_hi_shift & bit != 0) as u8) << 1
+                | (self.attribute_lo_shift & bit != 0) as u8;
+        }
+
+        let mut sprite_pixel = 0;
+        let mut sprite_attributes = 0;
+        let mut sprite_zero = false;
+        if self.mask & MASK_SHOW_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITES_LEFT != 0) {
+            // Lower slots have priority, so the first opaque pixel wins
+            for slot in 0..self.sprite_count {
+                let offset = x.wrapping_sub(self.sprite_x[slot] as usize);
+                if offset >= 8 {
+                    continue;
+                }
+                let bit = 7 - offset;
+                let pixel = ((self.sprite_pattern_hi[slot] >> bit) & 1) << 1
+                    | ((self.sprite_pattern_lo[slot] >> bit) & 1);
+                if pixel != 0 {
+                    sprite_pixel = pixel;
+                    sprite_attributes = self.sprite_attributes[slot];
+                    sprite_zero = slot == 0 && self.sprite_zero_in_line;
+                    break;
+                }
+            }
+        }
+
+        if sprite_zero && bg_pixel != 0 && sprite_pixel != 0 && x != 255 {
+            self.status |= STATUS_SPRITE_ZERO_HIT;
+        }
+
+        let sprite_addr =
+            0x3F10 | ((sprite_attributes & SPRITE_PALETTE) << 2) as u16 | sprite_pixel as u16;
+        let bg_addr = 0x3F00 | (bg_palette << 2) as u16 | bg_pixel as u16;
+        let addr = match (bg_pixel, sprite_pixel) {
+            (0, 0) => 0x3F00,
+            (0, _) => sprite_addr,
+            (_, 0) => bg_addr,
+            _ => {
+                if sprite_attributes & SPRITE_BEHIND_BACKGROUND != 0 {
+                    bg_addr
+                } else {
+                    sprite_addr
+                }
+            }
+        };
+        self.back_buffer[y * WIDTH + x] = self.read_palette(addr);
+    }
+
+    // The last completed picture, WIDTH x HEIGHT palette indices (0-63) in row-major order. It's
+    // replaced at the end of the last visible scanline, never partly drawn.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer[..]
+    }
+
+    // State of the PPU's NMI output, true while asserted. The CPU triggers on its rising edge.
+    pub fn nmi(&self) -> bool {
+        self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI_ENABLE != 0
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+}
+
+// Maps a nametable address (0x2000-0x3EFF) to an offset into VRAM
+fn nametable_index(addr: u16, mirroring: Mirroring) -> usize {
+    let addr = (addr as usize - 0x2000) & 0x0FFF;
+    let table = addr / 0x400;
+    let physical = match mirroring {
+        Mirroring::Horizontal => table / 2,
+        Mirroring::Vertical => table % 2,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        Mirroring::FourScreen => table,
+    };
+    physical * 0x400 + (addr & 0x3FF)
+}
+
+// Maps a palette address (0x3F00-0x3FFF) to an offset into palette RAM. The sprite palettes'
+// transparent entries (0x3F10, 0x3F14, 0x3F18, 0x3F1C) mirror the background ones.
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1F;
+    if index & 0x13 == 0x10 {
+        index & !0x10
+    } else {
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::{test_cartridge, NROM};
+
+    // A PPU on an NROM board with CHR RAM, driven through its registers like the CPU would
+    struct TestPpu {
+        ppu: PPU,
+        mapper: NROM,
+    }
+
+    impl TestPpu {
+        fn new(mirroring: Mirroring) -> TestPpu {
+            let mut cartridge = test_cartridge(0, 0, 0x4000, 0);
+            cartridge.header.mirroring = mirroring;
+            TestPpu {
+                ppu: PPU::new(),
+                mapper: NROM::new(cartridge),
+            }
+        }
+
+        fn write(&mut self, reg: u16, data: u8) {
+            self.ppu.write_register(reg, data, &mut self.mapper);
+        }
+
+        fn read(&mut self, reg: u16) -> u8 {
+            self.ppu.read_register(reg, &mut self.mapper)
+        }
+
+        fn set_address(&mut self, addr: u16) {
+            self.write(6, (addr >> 8) as u8);
+            self.write(6, addr as u8);
+        }
+        fn write_vram(&mut self, addr: u16, data: &[u8]) {
+            self.set_address(addr);
+            for &byte in data {
+                self.write(7, byte);
+            }
+        }
+
+        // Below the palette the first PPUDATA read only fills the buffer
+        fn read_vram(&mut self, addr: u16) -> u8 {
+            self.set_address(addr);
+            if addr < 0x3F00 {
+                self.read(7);
+            }
+            self.read(7)
+        }
+
+        // Runs until the PPU is about to draw the given dot
+        fn run_to(&mut self, scanline: u16, dot: u16) {
+            loop {
+                self.ppu.tick(&mut self.mapper);
+                if self.ppu.scanline == scanline && self.ppu.dot == dot {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn framebuffer_only_changes_between_frames() {
+        // With rendering disabled every pixel is the backdrop color. v is moved out of the
+        // palette afterwards, or the color it points at would be shown instead.
+        let mut test = TestPpu::new(Mirroring::Horizontal);
+        test.write_vram(0x3F00, &[0x21]);
+        test.set_address(0x2000);
+        test.run_to(VBLANK_SCANLINE, 0);
+        assert!(test.ppu.framebuffer().iter().all(|&pixel| pixel == 0x21));
+
+        // Halfway through drawing the next frame, the last one is still there
+        test.write_vram(0x3F00, &[0x16]);
+        test.set_address(0x2000);
+        test.run_to(120, 0);
+        assert!(test.ppu.framebuffer().iter().all(|&pixel| pixel == 0x21));
+        test.run_to(VBLANK_SCANLINE, 0);
+        assert!(test.ppu.framebuffer().iter().all(|&pixel| pixel == 0x16));
+    }
+
+    #[test]
+    fn status_read_clears_vblank_and_write_toggle() {
+        let mut test = TestPpu::new(Mirroring::Horizontal);
+        test.run_to(VBLANK_SCANLINE, 2);
+        // The low 5 bits are whatever was last on the PPU's data bus
+        test.write(2, 0x1F);
+        assert_eq!(test.read(2), STATUS_VBLANK | 0x1F);
+        assert_eq!(test.read(2) & STATUS_VBLANK, 0);
+
+        // A read between the two PPUADDR writes starts the address over
+        test.write(6, 0x3F);
+        test.read(2);
+        test.set_address(0x2400);
+        assert_eq!(test.ppu.v, 0x2400);
+
+        // And the same for PPUSCROLL, the next write is the X scroll again
+        test.write(5, 0x08);
+        test.read(2);
+        test.write(5, 0x10);
+        assert_eq!(test.ppu.t & 0x001F, 2);
+    }
+
+    #[test]
+    fn ppudata_reads_are_buffered_below_the_palette() {
+        let mut test = TestPpu::new(Mirroring::Horizontal);
+        test.write_vram(0x2400, &[0x11, 0x22, 0x33]);
+        test.set_address(0x2400);
+        test.read(7);
+        assert_eq!(test.read(7), 0x11);
+        assert_eq!(test.read(7), 0x22);
+
+        // Incrementing by 32 moves down a row of tiles
+        test.write(0, CTRL_INCREMENT_32);
+        test.write_vram(0x2000, &[0x01, 0x02]);
+        test.write(0, 0);
+        assert_eq!(test.read_vram(0x2020), 0x02);
+
+        // Palette reads come back straight away, and fill the buffer from the nametable
+        // underneath the palette
+        test.write_vram(0x2F05, &[0x44]);
+        test.write_vram(0x3F05, &[0x2A]);
+        test.set_address(0x3F05);
+        assert_eq!(test.read(7), 0x2A);
+        test.set_address(0x2000);
+        assert_eq!(test.read(7), 0x44);
+    }
+
+    #[test]
+    fn palette_mirrors() {
+        let mut test = TestPpu::new(Mirroring::Horizontal);
+        // The sprite palettes' transparent entries are the background ones
+        for &addr in [0x3F10, 0x3F14, 0x3F18, 0x3F1C].iter() {
+            test.write_vram(addr, &[addr as u8 & 0x1F]);
+            assert_eq!(test.read_vram(addr - 0x10), addr as u8 & 0x1F);
+        }
+        // But not the other sprite colors
+        test.write_vram(0x3F11, &[0x30]);
+        assert_eq!(test.read_vram(0x3F01), 0x00);
+        // Palette RAM repeats up to 0x3FFF, and is 6 bits wide with the top bits read from the
+        // data bus, which still has the low address byte on it
+        assert_eq!(test.read_vram(0x3FE4), 0xC0 | 0x14);
+        test.write_vram(0x3F02, &[0xFF]);
+        assert_eq!(test.read_vram(0x3F02), 0x3F);
+    }
+
+    #[test]
+    fn nametable_mirroring() {
+        // Writes 1-4 to the start of each nametable, then reads them back
+        #[rustfmt::skip]
+        let cases = [
+            (Mirroring::Horizontal, [2, 2, 4, 4]),
+            (Mirroring::Vertical, [3, 4, 3, 4]),
+            (Mirroring::SingleScreenLower, [4, 4, 4, 4]),
+            (Mirroring::SingleScreenUpper, [4, 4, 4, 4]),
+            (Mirroring::FourScreen, [1, 2, 3, 4]),
+        ];
+        for &(mirroring, expected) in cases.iter() {
+            let mut test = TestPpu::new(mirroring);
+            for table in 0..4 {
+                test.write_vram(0x2000 + table * 0x400, &[table as u8 + 1]);
+            }
+            let actual = [0x2000, 0x2400, 0x2800, 0x2C00].map(|addr| test.read_vram(addr));
+            assert_eq!(actual, expected, "{:?}", mirroring);
+            // 0x3000-0x3EFF mirrors 0x2000-0x2EFF
+            assert_eq!(test.read_vram(0x3000), expected[0], "{:?}", mirroring);
+        }
+
+        // Single screen lower and upper use different halves of VRAM
+        let mut test = TestPpu::new(Mirroring::SingleScreenLower);
+        test.write_vram(0x2000, &[0x55]);
+        test.mapper = TestPpu::new(Mirroring::SingleScreenUpper).mapper;
+        assert_eq!(test.read_vram(0x2000), 0x00);
+    }
+
+    #[test]
+    fn vblank_and_nmi_timing() {
+        let mut test = TestPpu::new(Mirroring::Horizontal);
+        test.write(0, CTRL_NMI_ENABLE);
+        // The flag is set and NMI raised at dot 1 of scanline 241
+        test.run_to(VBLANK_SCANLINE, 1);
+        assert!(!test.ppu.nmi());
+        test.ppu.tick(&mut test.mapper);
+        assert!(test.ppu.nmi());
+        assert_ne!(test.read(2) & STATUS_VBLANK, 0);
+        assert!(!test.ppu.nmi());
+
+        // NMI follows the enable bit while the flag is set, so toggling it gives another edge
+        test.run_to(VBLANK_SCANLINE, 2);
+        assert!(test.ppu.nmi());
+        test.write(0, 0);
+        assert!(!test.ppu.nmi());
+        test.write(0, CTRL_NMI_ENABLE);
+        assert!(test.ppu.nmi());
+
+        // Cleared at dot 1 of the pre-render scanline
+        test.run_to(PRE_RENDER_SCANLINE, 1);
+        assert!(test.ppu.nmi());
+        test.ppu.tick(&mut test.mapper);
+        assert!(!test.ppu.nmi());
+        assert_eq!(test.ppu.status & STATUS_VBLANK, 0);
+
+        // Reading the status just before the flag would be set keeps it clear all frame
+        test.run_to(VBLANK_SCANLINE, 0);
+        assert_eq!(test.read(2) & STATUS_VBLANK, 0);
+        test.run_to(VBLANK_SCANLINE + 1, 0);
+        assert!(!test.ppu.nmi());
+        assert_eq!(test.ppu.status & STATUS_VBLANK, 0);
+
+        // Only for that frame
+        test.run_to(VBLANK_SCANLINE, 2);
+        assert!(test.ppu.nmi());
+    }
+
+    // Rendering a screen of one background tile (1 is solid, 0 is transparent) with the given
+    // sprites, stopped just after the flags are cleared at the start of the second frame
+    fn render(background_tile: u8, sprites: &[[u8; 4]]) -> TestPpu {
+        let mut test = TestPpu::new(Mirroring::Horizontal);
+        test.write_vram(0x0010, &[0xFF; 8]);
+        test.write_vram(0x2000, &[background_tile; 960]);
+        test.write(3, 0);
+        for i in 0..64 {
+            let sprite = sprites.get(i).copied().unwrap_or([0xFF; 4]);
+            for &byte in sprite.iter() {
+                test.write(4, byte);
+            }
+        }
+        test.write(0, 0);
+        test.write(5, 0);
+        test.write(5, 0);
+        test.write(1, MASK_SHOW_BACKGROUND | MASK_SHOW_SPRITES | 0x06);
+        test.run_to(PRE_RENDER_SCANLINE, 2);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+        test
+    }
+
+    #[test]
+    fn sprite_zero_hit() {
+        // Sprite 0 at Y 30 is drawn from scanline 31
+        let mut test = render(1, &[[30, 1, 0, 50]]);
+        test.run_to(31, 0);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+        test.run_to(32, 0);
+        assert_ne!(test.ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+
+        // Not over a transparent background
+        let mut test = render(0, &[[30, 1, 0, 50]]);
+        test.run_to(100, 0);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+
+        // Nor for other sprites
+        let mut test = render(1, &[[0xFF; 4], [30, 1, 0, 50]]);
+        test.run_to(100, 0);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+    }
+
+    #[test]
+    fn sprite_overflow() {
+        let sprites = (0..9).map(|i| [100, 1, 0, i * 10]).collect::<Vec<_>>();
+        // Eight sprites on a scanline fit
+        let mut test = render(1, &sprites[..8]);
+        test.run_to(120, 0);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+
+        // A ninth sets the flag when that scanline is evaluated
+        let mut test = render(1, &sprites);
+        test.run_to(100, 0);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+        test.run_to(101, 0);
+        assert_ne!(test.ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+        test.run_to(PRE_RENDER_SCANLINE, 2);
+        assert_eq!(test.ppu.status & STATUS_SPRITE_OVERFLOW, 0);
     }
 }
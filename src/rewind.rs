@@ -0,0 +1,225 @@
+// Rewind history: save states taken every few frames, kept in memory within a size budget.
+//
+// Consecutive states are mostly the same, so only every KEYFRAME_INTERVAL-th snapshot is kept
+// whole. The ones in between are XORed against that keyframe, which leaves long runs of zeros
+// wherever nothing changed. Both kinds are run-length encoded. When the budget is exceeded the
+// oldest keyframe is dropped along with the snapshots that depend on it.
+use std::collections::VecDeque;
+
+// Snapshots per keyframe, including the keyframe
+const KEYFRAME_INTERVAL: usize = 30;
+// Shorter runs are cheaper to store as literals
+const MIN_RUN: usize = 3;
+
+struct Snapshot {
+    frame: u64,
+    data: Vec<u8>, // compressed, and XORed with the keyframe unless it is one
+}
+
+// A keyframe and the snapshots that depend on it
+struct Group {
+    keyframe: Snapshot,
+    deltas: Vec<Snapshot>,
+}
+
+pub struct Rewind {
+    interval: u64, // frames between snapshots
+    budget: usize, // bytes
+    groups: VecDeque<Group>,
+    keyframe: Vec<u8>, // the newest keyframe uncompressed, to XOR new snapshots with
+    size: usize,       // bytes used by the snapshots and the uncompressed keyframe
+    last_frame: Option<u64>,
+    force_keyframe: bool, // set when the only keyframe has to go to stay within the budget
+}
+
+impl Rewind {
+    pub fn new(interval: u64, budget: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            groups: VecDeque::new(),
+            keyframe: Vec::new(),
+            size: 0,
+            last_frame: None,
+            force_keyframe: false,
+        }
+    }
+
+    // True if a snapshot should be taken at this frame
+    pub fn due(&self, frame: u64) -> bool {
+        match self.last_frame {
+            Some(last) => frame >= last + self.interval || frame < last,
+            None => true,
+        }
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        self.last_frame = Some(frame);
+        let new_keyframe = match self.groups.back() {
+            Some(group) => {
+                self.force_keyframe
+                    || group.deltas.len() + 1 >= KEYFRAME_INTERVAL
+                    || state.len() != self.keyframe.len()
+            }
+            None => true,
+        };
+        if new_keyframe {
+            let snapshot = Snapshot {
+                frame,
+                data: compress(&state),
+            };
+            self.size += snapshot.data.len() + state.len();
+            self.size -= self.keyframe.len();
+            self.keyframe = state;
+            self.force_keyframe = false;
+            self.groups.push_back(Group {
+                keyframe: snapshot,
+                deltas: Vec::new(),
+            });
+        } else {
+            let delta = xor(&state, &self.keyframe);
+            let snapshot = Snapshot {
+                frame,
+                data: compress(&delta),
+            };
+            self.size += snapshot.data.len();
+            if let Some(group) = self.groups.back_mut() {
+                group.deltas.push(snapshot);
+            }
+        }
+
+        while self.size > self.budget && self.groups.len() > 1 {
+            if let Some(group) = self.groups.pop_front() {
+                self.size -= group_size(&group);
+            }
+        }
+        if self.size > self.budget {
+            self.force_keyframe = true;
+        }
+    }
+
+    // Takes out the newest snapshot at or before the frame, or the oldest one if they're all
+    // later, and returns its frame and state. Snapshots after it are discarded, since the
+    // machine is about to go back to it.
+    pub fn restore(&mut self, frame: u64) -> Option<(u64, Vec<u8>)> {
+        // Drop whole groups that start too late, keeping at least one
+        while self.groups.len() > 1 && self.groups.back()?.keyframe.frame > frame {
+            let group = self.groups.pop_back()?;
+            self.size -= group_size(&group);
+        }
+        let group = self.groups.back_mut()?;
+        let keep = group
+            .deltas
+            .iter()
+            .take_while(|delta| delta.frame <= frame)
+            .count();
+        for delta in group.deltas.drain(keep..) {
+            self.size -= delta.data.len();
+        }
+
+        let keyframe = decompress(&group.keyframe.data)?;
+        self.size += keyframe.len();
+        self.size -= self.keyframe.len();
+        self.keyframe = keyframe;
+        let (frame, state) = match group.deltas.last() {
+            Some(delta) => (delta.frame, xor(&decompress(&delta.data)?, &self.keyframe)),
+            None => (group.keyframe.frame, self.keyframe.clone()),
+        };
+        self.last_frame = Some(frame);
+        Some((frame, state))
+    }
+
+    pub fn clear(&mut self) {
+        *self = Rewind::new(self.interval, self.budget);
+    }
+
+    // Bytes currently used, which stays within the budget except while there's only one
+    // keyframe to keep
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    // Frames of history available
+    pub fn frames(&self) -> u64 {
+        match (self.groups.front(), self.last_frame) {
+            (Some(group), Some(last)) => last.saturating_sub(group.keyframe.frame),
+            _ => 0,
+        }
+    }
+}
+
+fn group_size(group: &Group) -> usize {
+    group.keyframe.data.len()
+        + group
+            .deltas
+            .iter()
+            .map(|delta| delta.data.len())
+            .sum::<usize>()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+// Run-length encoding. The data is a sequence of a LEB128 length followed by either one byte
+// repeated length >> 1 times if bit 0 is clear, or length >> 1 literal bytes if it's set.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..].iter().take_while(|&&b| b == data[i]).count();
+        if run >= MIN_RUN {
+            write_literal(&mut out, &data[literal_start..i]);
+            write_length(&mut out, run << 1);
+            out.push(data[i]);
+            literal_start = i + run;
+        }
+        i += run;
+    }
+    write_literal(&mut out, &data[literal_start..]);
+    out
+}
+
+fn write_literal(out: &mut Vec<u8>, literal: &[u8]) {
+    if !literal.is_empty() {
+        write_length(out, literal.len() << 1 | 1);
+        out.extend_from_slice(literal);
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        out.push(length as u8 | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+}
+
+// None if the data wasn't produced by compress
+fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let mut length = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = *data.get(i)?;
+            i += 1;
+            length |= ((byte & 0x7F) as usize).checked_shl(shift)?;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let count = length >> 1;
+        if length & 1 == 0 {
+            let byte = *data.get(i)?;
+            i += 1;
+            out.resize(out.len() + count, byte);
+        } else {
+            out.extend_from_slice(data.get(i..i.checked_add(count)?)?);
+            i += count;
+        }
+    }
+    Some(out)
+}
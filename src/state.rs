@@ -0,0 +1,199 @@
+// Save state encoding. A save state is a header followed by the state of each part of the
+// machine, in a fixed order:
+// 0-3   - Constant "NESS"
+// 4-7   - Format version
+// 8-11  - CRC-32 of the PRG and CHR ROM the state was saved from
+// 12-   - Sections, each starting with a 4 byte tag: CPU, bus, RAM, PPU, APU, mapper
+//
+// All values are little endian. Byte arrays are prefixed with their length as a u32. Each part
+// of the machine saves and loads its own fields, so the order they're written in is the format.
+// Anything that changes it needs a new VERSION.
+use crate::cartridge::Mirroring;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub const MAGIC: [u8; 4] = *b"NESS";
+pub const VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    // The state was saved from a different ROM. Both are CRC-32s of the PRG and CHR ROM.
+    RomMismatch { expected: u32, actual: u32 },
+    // The data ends early, a section is out of place, or a value is out of range
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "failed to read save state: {}", e),
+            StateError::InvalidMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported", version)
+            }
+            StateError::RomMismatch { expected, actual } => write!(
+                f,
+                "save state is for a different ROM: expected CRC {:08X}, found {:08X}",
+                expected, actual
+            ),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StateError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> StateError {
+        StateError::Io(e)
+    }
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn section(&mut self, tag: &[u8; 4]) {
+        self.data.extend_from_slice(tag);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.data.extend_from_slice(data);
+    }
+
+    pub fn mirroring(&mut self, mirroring: Mirroring) {
+        self.u8(match mirroring {
+            Mirroring::Horizontal => 0,
+            Mirroring::Vertical => 1,
+            Mirroring::FourScreen => 2,
+            Mirroring::SingleScreenLower => 3,
+            Mirroring::SingleScreenUpper => 4,
+        });
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    // True once everything has been read
+    pub fn at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::Corrupt)?;
+        let bytes = self.data.get(self.pos..end).ok_or(StateError::Corrupt)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn section(&mut self, tag: &[u8; 4]) -> Result<(), StateError> {
+        if self.take(4)? == tag {
+            Ok(())
+        } else {
+            Err(StateError::Corrupt)
+        }
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    // Reads a byte array that has to be exactly the size of out
+    pub fn bytes_into(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.bytes()?;
+        if bytes.len() != out.len() {
+            return Err(StateError::Corrupt);
+        }
+        out.copy_from_slice(bytes);
+        Ok(())
+    }
+    // Reads a value that has to be below limit, e.g. an index into a table
+    pub fn u8_below(&mut self, limit: usize) -> Result<u8, StateError> {
+        let value = self.u8()?;
+        if (value as usize) < limit {
+            Ok(value)
+        } else {
+            Err(StateError::Corrupt)
+        }
+    }
+
+    pub fn mirroring(&mut self) -> Result<Mirroring, StateError> {
+        match self.u8()? {
+            0 => Ok(Mirroring::Horizontal),
+            1 => Ok(Mirroring::Vertical),
+            2 => Ok(Mirroring::FourScreen),
+            3 => Ok(Mirroring::SingleScreenLower),
+            4 => Ok(Mirroring::SingleScreenUpper),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+}
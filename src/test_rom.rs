@@ -0,0 +1,139 @@
+// Headless runners for accuracy test ROMs.
+//
+// Most of blargg's suites (cpu_instrs, instr_timing, ppu_vbl_nmi, apu_test, ...) report through
+// PRG RAM at 0x6000:
+// 0x6000      - Status: 0x80 while running, 0x81 when the ROM wants the reset button pressed,
+//               otherwise the final result code (0 is a pass)
+// 0x6001-6003 - 0xDE 0xB0 0x61 signature, written once the status byte is valid
+// 0x6004      - Null terminated text output, the same text the ROM prints on screen
+//
+// nestest has an automation mode which starts at 0xC000 without needing a PPU, and stores
+// the result codes of its official and unofficial opcode tests at 0x02 and 0x03.
+//
+// Audio can be recorded headlessly too, for dumping to a WAV file with audio::write_wav and
+// comparing against earlier runs.
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::nes::NES;
+use std::io;
+
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+const SIGNATURE_BYTES: [u8; 3] = [0xDE, 0xB0, 0x61];
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET: u8 = 0x81;
+// The ROMs ask for reset to be held for at least 100ms
+const RESET_DELAY_FRAMES: u32 = 6;
+const MAX_TEXT_LEN: u16 = 0x1FFC;
+
+const NESTEST_START: u16 = 0xC000;
+// Address of the final RTS of the automated tests, the last line of nestest.log
+const NESTEST_END: u16 = 0xC66E;
+const NESTEST_MAX_INSTRUCTIONS: usize = 10_000;
+
+// Read out after every frame, so well under the queue's limit
+const AUDIO_BUFFER_LEN: usize = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(u8), // result code, its meaning is specific to the ROM
+    TimedOut,   // the ROM hadn't finished when the frame limit was reached
+}
+
+#[derive(Debug)]
+pub struct TestResult {
+    pub outcome: Outcome,
+    pub text: String,
+}
+
+// Runs a ROM using the 0x6000 protocol for up to max_frames frames
+pub fn run_blargg(cartridge: Cartridge, max_frames: u32) -> Result<TestResult, CartridgeError> {
+    let mut nes = NES::new(cartridge)?;
+    let mut reset_frame = None;
+    for frame in 0..max_frames {
+        nes.run_frame();
+        let signature = [
+            nes.peek(SIGNATURE),
+            nes.peek(SIGNATURE + 1),
+            nes.peek(SIGNATURE + 2),
+        ];
+        if signature != SIGNATURE_BYTES {
+            continue;
+        }
+        match nes.peek(STATUS) {
+            STATUS_RUNNING => {}
+            STATUS_RESET => match reset_frame {
+                None => reset_frame = Some(frame + RESET_DELAY_FRAMES),
+                Some(reset) if frame >= reset => {
+                    nes.reset();
+                    reset_frame = None;
+                }
+                Some(_) => {}
+            },
+            code => {
+                let outcome = if code == 0 {
+                    Outcome::Passed
+                } else {
+                    Outcome::Failed(code)
+                };
+                return Ok(TestResult {
+                    outcome,
+                    text: read_text(&mut nes),
+                });
+            }
+        }
+    }
+    Ok(TestResult {
+        outcome: Outcome::TimedOut,
+        text: read_text(&mut nes),
+    })
+}
+
+fn read_text(nes: &mut NES) -> String {
+    let bytes = (0..MAX_TEXT_LEN)
+        .map(|i| nes.peek(TEXT + i))
+        .take_while(|&b| b != 0)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// Runs nestest's automation mode, optionally writing a trace in the nestest.log format.
+// Returns the result codes of the official and unofficial opcode tests, which are 0 on a pass.
+pub fn run_nestest(
+    cartridge: Cartridge,
+    trace: Option<Box<dyn io::Write>>,
+) -> Result<(u8, u8), CartridgeError> {
+    let mut nes = NES::new(cartridge)?;
+    nes.cpu_mut().set_pc(NESTEST_START);
+    nes.set_trace(trace);
+    for _ in 0..NESTEST_MAX_INSTRUCTIONS {
+        let pc = nes.cpu().registers().pc;
+        nes.step_instruction();
+        if pc == NESTEST_END || nes.cpu().halted() {
+            break;
+        }
+    }
+    nes.set_trace(None);
+    Ok((nes.peek(0x02), nes.peek(0x03)))
+}
+
+// Runs a ROM for a number of frames with no input, returning the audio it produced
+pub fn record_audio(
+    cartridge: Cartridge,
+    frames: u32,
+    sample_rate: u32,
+) -> Result<Vec<i16>, CartridgeError> {
+    let mut nes = NES::new(cartridge)?;
+    nes.set_sample_rate(sample_rate);
+    let mut samples = Vec::new();
+    let mut buffer = [0; AUDIO_BUFFER_LEN];
+    for _ in 0..frames {
+        nes.run_frame();
+        while nes.samples_available() > 0 {
+            let count = nes.read_samples_i16(&mut buffer);
+            samples.extend_from_slice(&buffer[..count]);
+        }
+    }
+    Ok(samples)
+}
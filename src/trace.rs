@@ -0,0 +1,142 @@
+// Per-instruction CPU trace in the format of nestest.log, so runs can be diffed line by line
+// against the reference log:
+//
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+//
+// Each line shows the state before the instruction executes: its address and raw bytes, the
+// disassembly with effective addresses and the values currently stored at them, the registers,
+// the PPU's scanline and dot, and the CPU cycle count. Unofficial opcodes are marked with a *.
+use crate::bus::{Bus, NesBus};
+use crate::cpu::Registers;
+use crate::instruction::{Access, AddrMode, Instruction, OpCode};
+use crate::mem;
+
+pub fn trace_line(regs: &Registers, bus: &mut NesBus) -> String {
+    let inst = Instruction::decode(bus.peek(regs.pc));
+    let bytes = (0..inst.size as u16)
+        .map(|i| format!("{:02X}", bus.peek(regs.pc.wrapping_add(i))))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let marker = if inst.official { ' ' } else { '*' };
+    let disassembly = disassemble(regs, bus);
+    // The status is shown the way PHP would push it, minus the B flag
+    let status = (regs.status | (1 << 5)) & !(1 << 4);
+    format!(
+        "{:04X}  {:<9}{}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        regs.pc,
+        bytes,
+        marker,
+        disassembly,
+        regs.accum,
+        regs.x,
+        regs.y,
+        status,
+        regs.sp,
+        bus.ppu().scanline(),
+        bus.ppu().dot(),
+        regs.cycle
+    )
+}
+
+// Disassembles the instruction at PC. Operands that address memory are resolved using the
+// current register values.
+pub fn disassemble<B: Bus>(regs: &Registers, bus: &mut B) -> String {
+    let pc = regs.pc;
+    let inst = Instruction::decode(bus.peek(pc));
+    let byte = bus.peek(pc.wrapping_add(1));
+    let word = byte as u16 | (bus.peek(pc.wrapping_add(2)) as u16) << 8;
+    let mnemonic = mnemonic(inst.op);
+    // Jumps don't show the value at their target
+    let show_value = inst.access != Access::Other;
+    let operand = match inst.addr_mode {
+        AddrMode::Implicit => String::new(),
+        AddrMode::Accumulator => "A".to_string(),
+        AddrMode::Immediate => format!("#${:02X}", byte),
+        AddrMode::ZeroPage => {
+            format!("${:02X} = {:02X}", byte, bus.peek(byte as u16))
+        }
+        AddrMode::ZeroPageX | AddrMode::ZeroPageY => {
+            let (index, name) = if inst.addr_mode == AddrMode::ZeroPageX {
+                (regs.x, 'X')
+            } else {
+                (regs.y, 'Y')
+            };
+            let addr = byte.wrapping_add(index);
+            format!(
+                "${:02X},{} @ {:02X} = {:02X}",
+                byte,
+                name,
+                addr,
+                bus.peek(addr as u16)
+            )
+        }
+        AddrMode::Relative => {
+            let target = pc.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${:04X}", target)
+        }
+        AddrMode::Absolute if show_value => format!("${:04X} = {:02X}", word, bus.peek(word)),
+        AddrMode::Absolute => format!("${:04X}", word),
+        AddrMode::AbsoluteX | AddrMode::AbsoluteY => {
+            let (index, name) = if inst.addr_mode == AddrMode::AbsoluteX {
+                (regs.x, 'X')
+            } else {
+                (regs.y, 'Y')
+            };
+            let addr = word.wrapping_add(index as u16);
+            format!(
+                "${:04X},{} @ {:04X} = {:02X}",
+                word,
+                name,
+                addr,
+                bus.peek(addr)
+            )
+        }
+        AddrMode::Indirect => {
+            // Same page wrapping bug as the CPU
+            let msb_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = bus.peek(word) as u16 | (bus.peek(msb_addr) as u16) << 8;
+            format!("(${:04X}) = {:04X}", word, target)
+        }
+        AddrMode::IndexedIndirect => {
+            let ptr = byte.wrapping_add(regs.x);
+            let addr = peek_zero_page_u16(bus, ptr);
+            format!(
+                "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                byte,
+                ptr,
+                addr,
+                bus.peek(addr)
+            )
+        }
+        AddrMode::IndirectIndexed => {
+            let base = peek_zero_page_u16(bus, byte);
+            let addr = base.wrapping_add(regs.y as u16);
+            format!(
+                "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                byte,
+                base,
+                addr,
+                bus.peek(addr)
+            )
+        }
+    };
+    if operand.is_empty() {
+        mnemonic
+    } else {
+        format!("{} {}", mnemonic, operand)
+    }
+}
+
+// nestest.log calls ISC by its other common name
+fn mnemonic(op: OpCode) -> String {
+    match op {
+        OpCode::ISC => "ISB".to_string(),
+        op => format!("{:?}", op),
+    }
+}
+
+fn peek_zero_page_u16<B: Bus>(bus: &mut B, addr: u8) -> u16 {
+    let lsb = bus.peek(mem::ZERO_PAGE_START + addr as u16) as u16;
+    let msb = bus.peek(mem::ZERO_PAGE_START + addr.wrapping_add(1) as u16) as u16;
+    lsb | (msb << 8)
+}
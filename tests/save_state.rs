@@ -0,0 +1,187 @@
+// Save states and rewinding, using a small generated ROM that keeps the CPU, PPU and APU busy:
+// the main loop counts in RAM and feeds the count to the DMC, and the NMI handler changes the
+// backdrop color and scrolls the screen.
+use rust_nes::cartridge::Cartridge;
+use rust_nes::nes::NES;
+use rust_nes::state::StateError;
+
+#[rustfmt::skip]
+const PROGRAM: [u8; 0x40] = [
+    0xA9, 0x80, 0x8D, 0x00, 0x20, // LDA #$80, STA $2000  ; NMI on
+    0xA9, 0x1E, 0x8D, 0x01, 0x20, // LDA #$1E, STA $2001  ; rendering on
+    0xA9, 0x0F, 0x8D, 0x15, 0x40, // LDA #$0F, STA $4015
+    0xA9, 0xBF, 0x8D, 0x00, 0x40, // LDA #$BF, STA $4000
+    0xA9, 0xFD, 0x8D, 0x02, 0x40, // LDA #$FD, STA $4002
+    0xA9, 0x08, 0x8D, 0x03, 0x40, // LDA #$08, STA $4003
+    0xE6, 0x10,                   // loop: INC $10
+    0xA6, 0x10,                   // LDX $10
+    0x8E, 0x11, 0x40,             // STX $4011
+    0x4C, 0x1E, 0x80,             // JMP loop
+    0xE6, 0x11,                   // nmi: INC $11
+    0xA9, 0x3F, 0x8D, 0x06, 0x20, // LDA #$3F, STA $2006
+    0xA9, 0x00, 0x8D, 0x06, 0x20, // LDA #$00, STA $2006
+    0xA5, 0x11,                   // LDA $11
+    0x8D, 0x07, 0x20,             // STA $2007  ; backdrop color
+    0x8D, 0x05, 0x20,             // STA $2005
+    0x8D, 0x05, 0x20,             // STA $2005
+    0x40,                         // RTI
+];
+const NMI_HANDLER: u16 = 0x8028;
+const RESET_HANDLER: u16 = 0x8000;
+const IRQ_HANDLER: u16 = 0x803F;
+
+// NROM with 16KB PRG ROM and 8KB CHR ROM. seed changes the CHR ROM, giving a different ROM.
+fn cartridge(seed: u8) -> Cartridge {
+    let mut rom = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0; 0x4000];
+    prg[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+    for (i, vector) in [NMI_HANDLER, RESET_HANDLER, IRQ_HANDLER].iter().enumerate() {
+        prg[0x3FFA + i * 2..0x3FFC + i * 2].copy_from_slice(&vector.to_le_bytes());
+    }
+    rom.extend_from_slice(&prg);
+    rom.extend((0..0x2000).map(|i| (i as u8).wrapping_mul(seed)));
+    Cartridge::from_bytes(&rom).unwrap()
+}
+
+// The RAM, picture and cycle count after running some frames. Audio isn't compared, the
+// resampler isn't part of the state so its output depends on where it was before a load.
+fn run(nes: &mut NES, frames: u32) -> (Vec<u8>, Vec<u8>, u64) {
+    for _ in 0..frames {
+        nes.run_frame();
+    }
+    let ram = (0..0x800).map(|addr| nes.peek(addr)).collect();
+    (ram, nes.framebuffer().to_vec(), nes.cycles())
+}
+
+#[test]
+fn resumes_identically() {
+    let mut nes = NES::new(cartridge(3)).unwrap();
+    run(&mut nes, 10);
+    let mut state = Vec::new();
+    nes.save_state(&mut state).unwrap();
+    let expected = run(&mut nes, 30);
+
+    nes.load_state(&mut &state[..]).unwrap();
+    assert_eq!(run(&mut nes, 30), expected);
+
+    // And into a different machine running the same ROM
+    let mut other = NES::new(cartridge(3)).unwrap();
+    other.load_state(&mut &state[..]).unwrap();
+    assert_eq!(run(&mut other, 30), expected);
+}
+
+#[test]
+fn rejects_bad_states() {
+    let mut nes = NES::new(cartridge(3)).unwrap();
+    run(&mut nes, 5);
+    let mut state = Vec::new();
+    nes.save_state(&mut state).unwrap();
+
+    let mut other = NES::new(cartridge(5)).unwrap();
+    assert!(matches!(
+        other.load_state(&mut &state[..]),
+        Err(StateError::RomMismatch { .. })
+    ));
+
+    let mut bad_magic = state.clone();
+    bad_magic[0] = b'X';
+    assert!(matches!(
+        nes.load_state(&mut &bad_magic[..]),
+        Err(StateError::InvalidMagic)
+    ));
+
+    let mut bad_version = state.clone();
+    bad_version[4] = 0xFF;
+    assert!(matches!(
+        nes.load_state(&mut &bad_version[..]),
+        Err(StateError::UnsupportedVersion(_))
+    ));
+
+    // A failed load leaves the machine alone
+    let expected = {
+        let mut copy = NES::new(cartridge(3)).unwrap();
+        copy.load_state(&mut &state[..]).unwrap();
+        run(&mut copy, 5)
+    };
+    let truncated = &state[..state.len() - 1];
+    assert!(matches!(
+        nes.load_state(&mut &truncated[..]),
+        Err(StateError::Corrupt)
+    ));
+    let mut trailing = state.clone();
+    trailing.push(0);
+    assert!(matches!(
+        nes.load_state(&mut &trailing[..]),
+        Err(StateError::Corrupt)
+    ));
+    assert_eq!(run(&mut nes, 5), expected);
+}
+
+#[test]
+fn rewinds_and_replays() {
+    let mut nes = NES::new(cartridge(3)).unwrap();
+    nes.enable_rewind(4, 1 << 24);
+    let mut history = vec![run(&mut nes, 0)];
+    for _ in 0..100 {
+        history.push(run(&mut nes, 1));
+    }
+    // Snapshots are 4 frames apart, so going back 10 frames lands 12 back
+    let start = nes.frame();
+    assert_eq!(nes.rewind(10).unwrap(), 12);
+    let frame = nes.frame();
+    assert_eq!(frame, start - 12);
+    let index = history.len() - 13;
+    assert_eq!(run(&mut nes, 0), history[index]);
+    assert_eq!(run(&mut nes, 12), history[index + 12]);
+
+    // Rewinding again after replaying, back past the ones dropped by the first rewind
+    assert_eq!(nes.rewind(20).unwrap(), 20);
+    assert_eq!(run(&mut nes, 0), history[index - 8]);
+
+    // More than there is goes back to the start
+    nes.rewind(1000).unwrap();
+    assert_eq!(run(&mut nes, 0), history[0]);
+    assert_eq!(nes.rewind(1).unwrap(), 0);
+}
+
+#[test]
+fn rewind_stays_within_budget() {
+    // Room for the uncompressed keyframe and half of the snapshots taken without a limit
+    let mut nes = NES::new(cartridge(3)).unwrap();
+    let mut state = Vec::new();
+    nes.save_state(&mut state).unwrap();
+    nes.enable_rewind(1, usize::MAX);
+    run(&mut nes, 300);
+    let budget = state.len() + (nes.rewind_history().unwrap().size() - state.len()) / 2;
+
+    let mut nes = NES::new(cartridge(3)).unwrap();
+    nes.enable_rewind(1, budget);
+    for _ in 0..300 {
+        nes.run_frame();
+        let history = nes.rewind_history().unwrap();
+        assert!(history.size() <= budget);
+    }
+    let history = nes.rewind_history().unwrap();
+    assert!(history.frames() > 0 && history.frames() < 300);
+
+    let frames = history.frames();
+    let expected = {
+        let mut copy = NES::new(cartridge(3)).unwrap();
+        run(&mut copy, (nes.frame() - frames) as u32)
+    };
+    assert_eq!(nes.rewind(1000).unwrap(), frames);
+    assert_eq!(run(&mut nes, 0), expected);
+}
+
+#[test]
+fn program_drives_the_dmc() {
+    // The main loop is 4 instructions, so a few thousand run it through every 7 bit DMC level
+    // well before the first NMI
+    let mut nes = NES::new(cartridge(3)).unwrap();
+    let mut levels = [false; 0x80];
+    for _ in 0..2000 {
+        nes.step_instruction();
+        levels[nes.cpu().bus().apu().dmc_level() as usize] = true;
+    }
+    assert!(levels.iter().all(|&seen| seen));
+}
@@ -0,0 +1,356 @@
+// Runs the CPU against the SingleStepTests (formerly ProcessorTests) 6502 JSON tests. Each test
+// sets up the registers and some RAM, runs one instruction, and lists the final state along
+// with every bus access the instruction makes, cycle by cycle:
+//
+// { "name": "b1 28 b5",
+//   "initial": { "pc": 59082, "s": 39, "a": 57, "x": 33, "y": 174, "p": 96, "ram": [[59082, 177], ...] },
+//   "final": { ... },
+//   "cycles": [[59082, 177, "read"], ...] }
+//
+// The tests aren't distributed with the emulator. They're read from the directory in
+// SINGLE_STEP_TESTS, or tests/single_step by default, laid out like the published repository
+// (6502/v1/00.json ... ff.json for the NMOS 6502, nes6502/v1 for the 2A03). Those sets are
+// ignored by default, run them with `cargo test --test single_step -- --ignored`. A missing set
+// fails rather than passing without running anything. A few hand-written tests in the same
+// format live in tests/fixtures and always run.
+use rust_nes::bus::{BusAccess, RamBus};
+use rust_nes::cpu::{Registers, Variant, CPU};
+use rust_nes::instruction::{Instruction, OpCode};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn test_dir() -> PathBuf {
+    match env::var_os("SINGLE_STEP_TESTS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("single_step"),
+    }
+}
+
+// The B flag and bit 5 don't exist in the CPU, so only the pushed copies of them are compared
+const STATUS_MASK: u8 = 0xCF;
+
+fn registers(state: &json::Value) -> Registers {
+    Registers {
+        pc: state["pc"].as_u64() as u16,
+        accum: state["a"].as_u64() as u8,
+        x: state["x"].as_u64() as u8,
+        y: state["y"].as_u64() as u8,
+        status: state["p"].as_u64() as u8,
+        sp: state["s"].as_u64() as u8,
+        cycle: 0,
+    }
+}
+
+// Runs one test, returning a description of the first difference
+fn run_test(test: &json::Value, variant: Variant) -> Result<(), String> {
+    let mut bus = RamBus::new();
+    bus.set_logging(true);
+    for entry in test["initial"]["ram"].as_array() {
+        bus.ram[entry[0].as_u64() as usize] = entry[1].as_u64() as u8;
+    }
+    let mut cpu = CPU::new(bus, variant);
+    cpu.set_registers(&registers(&test["initial"]));
+    cpu.advance_cpu();
+
+    let expected = registers(&test["final"]);
+    let mut actual = cpu.registers();
+    actual.cycle = 0;
+    actual.status &= STATUS_MASK;
+    let expected = Registers {
+        status: expected.status & STATUS_MASK,
+        ..expected
+    };
+    if actual != expected {
+        return Err(format!(
+            "registers\n  expected {:?}\n  actual   {:?}",
+            expected, actual
+        ));
+    }
+    for entry in test["final"]["ram"].as_array() {
+        let addr = entry[0].as_u64() as u16;
+        let data = entry[1].as_u64() as u8;
+        let actual = cpu.bus().ram[addr as usize];
+        if actual != data {
+            return Err(format!(
+                "ram[{:04X}]: expected {:02X}, actual {:02X}",
+                addr, data, actual
+            ));
+        }
+    }
+    let expected_cycles = test["cycles"]
+        .as_array()
+        .iter()
+        .map(|cycle| {
+            let addr = cycle[0].as_u64() as u16;
+            let data = cycle[1].as_u64() as u8;
+            match cycle[2].as_str() {
+                "read" => BusAccess::Read(addr, data),
+                _ => BusAccess::Write(addr, data),
+            }
+        })
+        .collect::<Vec<_>>();
+    let actual_cycles = &cpu.bus().log;
+    if *actual_cycles != expected_cycles {
+        return Err(format!(
+            "cycles\n  expected {:X?}\n  actual   {:X?}",
+            expected_cycles, actual_cycles
+        ));
+    }
+    Ok(())
+}
+
+// Runs every test in a file, returning a description of the first one that fails
+fn run_file(path: &Path, variant: Variant) -> Result<(), String> {
+    let text = fs::read_to_string(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    let tests = json::parse(&text).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    assert!(!tests.as_array().is_empty(), "{}: no tests", path.display());
+    for test in tests.as_array() {
+        run_test(test, variant)
+            .map_err(|difference| format!("\"{}\": {}", test["name"].as_str(), difference))?;
+    }
+    Ok(())
+}
+
+fn run_set(set: &str, variant: Variant) {
+    let dir = test_dir().join(set);
+    let mut failures = Vec::new();
+    for opcode in 0..=0xFFu8 {
+        // JAM locks up the bus in ways that vary between chips
+        if Instruction::decode(opcode).op == OpCode::JAM {
+            continue;
+        }
+        // One failure per opcode is enough to go on
+        let path = dir.join(format!("{:02x}.json", opcode));
+        if let Err(failure) = run_file(&path, variant) {
+            failures.push(format!("{:02x} {}", opcode, failure));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// Covers immediate, page crossing absolute indexed, zero page indexed wrapping, ADC overflow,
+// BRK and LDX zero page
+#[test]
+fn fixture() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join("single_step.json");
+    if let Err(failure) = run_file(&path, Variant::Ricoh2A03) {
+        panic!("{}", failure);
+    }
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests files, see the top of this file"]
+fn nmos_6502() {
+    run_set("6502/v1", Variant::Nmos6502);
+}
+
+#[test]
+#[ignore = "needs the SingleStepTests files, see the top of this file"]
+fn ricoh_2a03() {
+    run_set("nes6502/v1", Variant::Ricoh2A03);
+}
+
+// Just enough JSON for the test files, which only use objects, arrays, strings and numbers
+mod json {
+    use std::ops::Index;
+
+    #[derive(Debug)]
+    pub enum Value {
+        Null,
+        Number(f64),
+        String(String),
+        Array(Vec<Value>),
+        Object(Vec<(String, Value)>),
+    }
+
+    static NULL: Value = Value::Null;
+
+    impl Value {
+        pub fn as_u64(&self) -> u64 {
+            match self {
+                Value::Number(n) => *n as u64,
+                _ => panic!("expected a number, found {:?}", self),
+            }
+        }
+        pub fn as_str(&self) -> &str {
+            match self {
+                Value::String(s) => s,
+                _ => panic!("expected a string, found {:?}", self),
+            }
+        }
+        pub fn as_array(&self) -> &[Value] {
+            match self {
+                Value::Array(values) => values,
+                _ => panic!("expected an array, found {:?}", self),
+            }
+        }
+    }
+
+    impl Index<&str> for Value {
+        type Output = Value;
+        fn index(&self, key: &str) -> &Value {
+            match self {
+                Value::Object(fields) => fields
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map_or(&NULL, |(_, value)| value),
+                _ => &NULL,
+            }
+        }
+    }
+
+    impl Index<usize> for Value {
+        type Output = Value;
+        fn index(&self, index: usize) -> &Value {
+            self.as_array().get(index).unwrap_or(&NULL)
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    struct Parser<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn error(&self, message: &str) -> String {
+            format!("{} at byte {}", message, self.pos)
+        }
+
+        fn skip_whitespace(&mut self) {
+            while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+                self.pos += 1;
+            }
+        }
+
+        fn peek(&mut self) -> Option<u8> {
+            self.skip_whitespace();
+            self.bytes.get(self.pos).copied()
+        }
+
+        fn expect(&mut self, byte: u8) -> Result<(), String> {
+            if self.peek() == Some(byte) {
+                self.pos += 1;
+                Ok(())
+            } else {
+                Err(self.error(&format!("expected '{}'", byte as char)))
+            }
+        }
+
+        fn literal(&mut self, literal: &str, value: Value) -> Result<Value, String> {
+            if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+                self.pos += literal.len();
+                Ok(value)
+            } else {
+                Err(self.error("invalid literal"))
+            }
+        }
+
+        fn value(&mut self) -> Result<Value, String> {
+            match self.peek() {
+                Some(b'{') => self.object(),
+                Some(b'[') => self.array(),
+                Some(b'"') => Ok(Value::String(self.string()?)),
+                Some(b'n') => self.literal("null", Value::Null),
+                Some(_) => self.number(),
+                None => Err(self.error("unexpected end of input")),
+            }
+        }
+
+        fn object(&mut self) -> Result<Value, String> {
+            self.expect(b'{')?;
+            let mut fields = Vec::new();
+            if self.peek() == Some(b'}') {
+                self.pos += 1;
+                return Ok(Value::Object(fields));
+            }
+            loop {
+                self.skip_whitespace();
+                let name = self.string()?;
+                self.expect(b':')?;
+                fields.push((name, self.value()?));
+                match self.peek() {
+                    Some(b',') => self.pos += 1,
+                    Some(b'}') => {
+                        self.pos += 1;
+                        return Ok(Value::Object(fields));
+                    }
+                    _ => return Err(self.error("expected ',' or '}'")),
+                }
+            }
+        }
+
+        fn array(&mut self) -> Result<Value, String> {
+            self.expect(b'[')?;
+            let mut values = Vec::new();
+            if self.peek() == Some(b']') {
+                self.pos += 1;
+                return Ok(Value::Array(values));
+            }
+            loop {
+                values.push(self.value()?);
+                match self.peek() {
+                    Some(b',') => self.pos += 1,
+                    Some(b']') => {
+                        self.pos += 1;
+                        return Ok(Value::Array(values));
+                    }
+                    _ => return Err(self.error("expected ',' or ']'")),
+                }
+            }
+        }
+
+        // The test files only use plain ASCII strings, so escapes are passed through as is
+        fn string(&mut self) -> Result<String, String> {
+            self.expect(b'"')?;
+            let start = self.pos;
+            while self.pos < self.bytes.len() && self.bytes[self.pos] != b'"' {
+                if self.bytes[self.pos] == b'\\' {
+                    self.pos += 1;
+                }
+                self.pos += 1;
+            }
+            if self.pos >= self.bytes.len() {
+                return Err(self.error("unterminated string"));
+            }
+            let string = String::from_utf8_lossy(&self.bytes[start..self.pos]).into_owned();
+            self.pos += 1;
+            Ok(string)
+        }
+
+        fn number(&mut self) -> Result<Value, String> {
+            let start = self.pos;
+            while self.pos < self.bytes.len()
+                && matches!(
+                    self.bytes[self.pos],
+                    b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9'
+                )
+            {
+                self.pos += 1;
+            }
+            std::str::from_utf8(&self.bytes[start..self.pos])
+                .ok()
+                .and_then(|number| number.parse().ok())
+                .map(Value::Number)
+                .ok_or_else(|| self.error("invalid number"))
+        }
+    }
+}
@@ -0,0 +1,199 @@
+// Accuracy test ROMs. The ROMs aren't distributed with the emulator, so they're read from the
+// directory in NES_TEST_ROMS, or tests/roms by default, laid out the way the suites are
+// published (e.g. cpu_instrs/rom_singles/01-basics.nes). The tests are ignored by default, run
+// them with `cargo test --test test_roms -- --ignored` once the ROMs are in place. A suite that
+// isn't there fails rather than passing without running anything.
+use rust_nes::audio;
+use rust_nes::cartridge::Cartridge;
+use rust_nes::test_rom::{self, Outcome};
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Generous limit: the slowest single ROMs take around 30 seconds of emulated time
+const MAX_FRAMES: u32 = 60 * 60;
+
+fn rom_dir() -> PathBuf {
+    match env::var_os("NES_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("roms"),
+    }
+}
+
+// Sorted paths of the files in dir with the given extension. Panics if there aren't any.
+fn list_files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("{}: {}", dir.display(), err))
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect::<Vec<_>>();
+    assert!(
+        !files.is_empty(),
+        "no .{} files in {}",
+        extension,
+        dir.display()
+    );
+    files.sort();
+    files
+}
+
+fn load(path: &Path) -> Cartridge {
+    Cartridge::from_file(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err))
+}
+
+// Runs every .nes file in the suite's directory, and fails listing every ROM that didn't pass
+fn run_suite(suite: &str) {
+    let roms = list_files(&rom_dir().join(suite), "nes");
+    let mut failures = Vec::new();
+    for rom in &roms {
+        let cartridge = load(rom);
+        let result = test_rom::run_blargg(cartridge, MAX_FRAMES).unwrap();
+        if result.outcome != Outcome::Passed {
+            failures.push(format!(
+                "{}: {:?}\n{}",
+                rom.display(),
+                result.outcome,
+                result.text.trim_end()
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn cpu_instrs() {
+    run_suite("cpu_instrs/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn instr_timing() {
+    run_suite("instr_timing/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn instr_misc() {
+    run_suite("instr_misc/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn cpu_interrupts() {
+    run_suite("cpu_interrupts_v2/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn ppu_vbl_nmi() {
+    run_suite("ppu_vbl_nmi/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn ppu_open_bus() {
+    run_suite("ppu_open_bus");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn sprite_hit() {
+    run_suite("ppu_sprite_hit/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn oam_read() {
+    run_suite("oam_read");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn mmc3_test() {
+    run_suite("mmc3_test_2/rom_singles");
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn apu_test() {
+    run_suite("apu_test/rom_singles");
+}
+
+// Each .wav file in the audio directory is a reference recording of the .nes file with the same
+// name, made by record_audio and write_wav with these settings
+const AUDIO_FRAMES: u32 = 10 * 60;
+const AUDIO_SAMPLE_RATE: u32 = 44_100;
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn audio_regression() {
+    let references = list_files(&rom_dir().join("audio"), "wav");
+    let mut failures = Vec::new();
+    for reference in &references {
+        let rom = reference.with_extension("nes");
+        let cartridge = load(&rom);
+        let samples = test_rom::record_audio(cartridge, AUDIO_FRAMES, AUDIO_SAMPLE_RATE).unwrap();
+        let mut actual = Vec::new();
+        audio::write_wav(&mut actual, AUDIO_SAMPLE_RATE, &samples).unwrap();
+        if actual != fs::read(reference).unwrap() {
+            failures.push(format!("{}: audio differs", rom.display()));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+// Collects the trace in memory so it can be compared against nestest.log
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the top of this file"]
+fn nestest() {
+    let dir = rom_dir();
+    let rom = dir.join("nestest.nes");
+    let trace = SharedBuffer::default();
+    let cartridge = load(&rom);
+    let (official, unofficial) =
+        test_rom::run_nestest(cartridge, Some(Box::new(trace.clone()))).unwrap();
+
+    // Compare against the reference log line by line, if it's there
+    if let Ok(expected) = fs::read_to_string(dir.join("nestest.log")) {
+        let actual = String::from_utf8(trace.0.borrow().clone()).unwrap();
+        let expected = expected.lines().map(str::trim_end).collect::<Vec<_>>();
+        let actual = actual.lines().collect::<Vec<_>>();
+        for (number, (expected, actual)) in expected.iter().zip(&actual).enumerate() {
+            assert_eq!(
+                expected,
+                actual,
+                "trace differs from nestest.log at line {}",
+                number + 1
+            );
+        }
+        // A trace that stops early, e.g. because the CPU jammed, matches as far as it goes
+        assert_eq!(
+            actual.len(),
+            expected.len(),
+            "trace has {} lines, nestest.log has {}",
+            actual.len(),
+            expected.len()
+        );
+    }
+    assert_eq!(official, 0, "official opcode test failed");
+    assert_eq!(unofficial, 0, "unofficial opcode test failed");
+}